@@ -0,0 +1,61 @@
+// benches/server_list.rs
+//
+// Compares rebuilding the `ServerList` response on every request against serving
+// the cached bytes. Run with `cargo bench --features bench --bench server_list`.
+use actix_web::web::{ Bytes, Query };
+use criterion::{ black_box, criterion_group, criterion_main, BenchmarkId, Criterion };
+use r1ms::config::Config;
+use r1ms::handlers::list_cache::ListCache;
+use r1ms::handlers::list_query::ServerListQuery;
+use r1ms::handlers::servers::serialize_server_list;
+use r1ms::models::server::{ Player, ServerInfo };
+use r1ms::storage::memory::ServerStorage;
+use r1ms::storage::ServerStore;
+use std::time::Duration;
+
+const SIZES: [usize; 3] = [100, 1_000, 10_000];
+
+fn server(i: usize) -> ServerInfo {
+    ServerInfo {
+        players: (0..8)
+            .map(|p| Player { name: format!("player-{}-{}", i, p), gen: 1, lvl: 50, team: p % 2 })
+            .collect(),
+        ..ServerInfo::for_test(i)
+    }
+}
+
+fn populated_storage(size: usize) -> ServerStorage {
+    let storage = ServerStorage::new(Config { max_servers_per_ip: 8, ..Config::default() });
+    for i in 0..size {
+        storage.add_server(server(i)).unwrap();
+    }
+    storage
+}
+
+/// What `get_servers` did before the cache: clone the registry, then sort and serialize it.
+fn rebuild(storage: &ServerStorage, query: &ServerListQuery) -> Bytes {
+    let page = query.apply(storage.get_servers()).unwrap();
+    Bytes::from(serialize_server_list(&page, storage.version(), false))
+}
+
+fn bench_server_list(c: &mut Criterion) {
+    let mut group = c.benchmark_group("server_list");
+    let query = Query::<ServerListQuery>::from_query("").unwrap().into_inner();
+
+    for size in SIZES {
+        let storage = populated_storage(size);
+        group.bench_with_input(BenchmarkId::new("rebuild", size), &size, |b, _| {
+            b.iter(|| rebuild(&storage, black_box(&query)))
+        });
+
+        let cache = ListCache::new(Duration::from_secs(1), 256);
+        cache.insert("", storage.version(), rebuild(&storage, &query));
+        group.bench_with_input(BenchmarkId::new("cached", size), &size, |b, _| {
+            b.iter(|| cache.get(black_box(""), storage.version()).unwrap().body)
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_server_list);
+criterion_main!(benches);
@@ -0,0 +1,94 @@
+// benches/storage.rs
+//
+// Compares the indexed `ServerStorage` against the linear scans it replaced.
+// Run with `cargo bench --features bench --bench storage`.
+use criterion::{ black_box, criterion_group, criterion_main, BenchmarkId, Criterion };
+use dashmap::DashMap;
+use r1ms::config::Config;
+use r1ms::models::server::ServerInfo;
+use r1ms::storage::memory::ServerStorage;
+use r1ms::storage::ServerStore;
+
+const SIZES: [usize; 3] = [100, 1_000, 10_000];
+
+fn populated_storage(size: usize) -> ServerStorage {
+    let storage = ServerStorage::new(Config { max_servers_per_ip: 8, ..Config::default() });
+    for i in 0..size {
+        storage.add_server(ServerInfo::for_test(i)).unwrap();
+    }
+    storage
+}
+
+fn populated_map(size: usize) -> DashMap<String, ServerInfo> {
+    let servers = DashMap::new();
+    for i in 0..size {
+        let s = ServerInfo::for_test(i);
+        servers.insert(s.id.clone(), s);
+    }
+    servers
+}
+
+/// The pre-index `add_server`: one scan for the ip:port duplicate and one for the per-IP count.
+fn scan_add_server(servers: &DashMap<String, ServerInfo>, server_info: ServerInfo, max_per_ip: usize) -> Result<(), String> {
+    let existing_server_id = servers
+        .iter()
+        .find(|r| r.value().ip == server_info.ip && r.value().port == server_info.port)
+        .map(|r| r.key().clone());
+
+    if let Some(id) = existing_server_id {
+        servers.remove(&id);
+    } else {
+        let server_count = servers.iter().filter(|r| r.value().ip == server_info.ip).count();
+        if server_count >= max_per_ip {
+            return Err("limit".to_string());
+        }
+    }
+
+    servers.insert(server_info.id.clone(), server_info);
+    Ok(())
+}
+
+fn bench_add_server(c: &mut Criterion) {
+    let mut group = c.benchmark_group("add_server_refresh");
+    for size in SIZES {
+        let target = ServerInfo::for_test(size / 2);
+
+        let storage = populated_storage(size);
+        group.bench_with_input(BenchmarkId::new("indexed", size), &size, |b, _| {
+            b.iter(|| storage.add_server(black_box(target.clone())).unwrap())
+        });
+
+        let servers = populated_map(size);
+        group.bench_with_input(BenchmarkId::new("linear_scan", size), &size, |b, _| {
+            b.iter(|| scan_add_server(&servers, black_box(target.clone()), 8).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_find_server(c: &mut Criterion) {
+    let mut group = c.benchmark_group("find_server");
+    for size in SIZES {
+        let target = ServerInfo::for_test(size / 2);
+
+        let storage = populated_storage(size);
+        group.bench_with_input(BenchmarkId::new("indexed", size), &size, |b, _| {
+            b.iter(|| storage.find_server(black_box(&target.ip), black_box(target.port)).unwrap())
+        });
+
+        // What `delete_server` used to do: clone the whole list and search it.
+        group.bench_with_input(BenchmarkId::new("clone_and_scan", size), &size, |b, _| {
+            b.iter(|| {
+                storage
+                    .get_servers()
+                    .into_iter()
+                    .find(|s| s.ip == black_box(&target.ip)[..] && s.port == black_box(target.port))
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_add_server, bench_find_server);
+criterion_main!(benches);
@@ -0,0 +1,16 @@
+// Mutates the body of an otherwise well-formed challenge reply, which reaches
+// the nonce and connect-string checks far more often than random bytes do.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use r1ms::protocol::{ Packet, CONNECTIONLESS_HEADER, S2C_CHALLENGE };
+
+fuzz_target!(|body: &[u8]| {
+    let mut reply = CONNECTIONLESS_HEADER.to_vec();
+    reply.push(S2C_CHALLENGE);
+    reply.extend_from_slice(body);
+
+    if let Ok(Packet::ChallengeReply { nonce, .. }) = Packet::decode(&reply) {
+        // Whatever nonce was accepted must be the one that was on the wire.
+        assert!(reply[16..26].eq_ignore_ascii_case(nonce.to_string().as_bytes()));
+    }
+});
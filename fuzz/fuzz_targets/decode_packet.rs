@@ -0,0 +1,11 @@
+// Arbitrary datagrams must never panic the decoder, and anything it accepts
+// must survive an encode/decode round trip.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use r1ms::protocol::Packet;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(packet) = Packet::decode(data) {
+        assert_eq!(Packet::decode(&packet.encode()), Ok(packet));
+    }
+});
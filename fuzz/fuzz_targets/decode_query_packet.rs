@@ -0,0 +1,14 @@
+// Arbitrary query replies must never panic the decoder, and anything it accepts
+// must encode back to the same packet. Durations may decode to NaN, so the
+// round trip is compared on the encoded bytes.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use r1ms::protocol::QueryPacket;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(packet) = QueryPacket::decode(data) {
+        let encoded = packet.encode();
+        let decoded = QueryPacket::decode(&encoded).expect("re-encoded packet failed to decode");
+        assert_eq!(decoded.encode(), encoded);
+    }
+});
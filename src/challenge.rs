@@ -0,0 +1,190 @@
+// src/challenge.rs
+use dashmap::DashMap;
+use log::debug;
+use rand::Rng;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::{ oneshot, Semaphore };
+use crate::protocol::{ Nonce, Packet };
+use crate::utils::{ bind_udp_sockets, socket_for };
+
+// Challenges waiting for a reply, keyed by the server address and the nonce we sent it
+type PendingChallenges = DashMap<(SocketAddr, Nonce), oneshot::Sender<Result<(), ChallengeError>>>;
+
+#[derive(Debug)]
+pub enum ChallengeError {
+    /// Too many challenges are already waiting for a reply.
+    Busy,
+    /// No challenge socket of the server's address family is bound.
+    Unreachable,
+    Send(std::io::Error),
+    Timeout,
+}
+
+impl fmt::Display for ChallengeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Busy => write!(f, "Too many challenges in flight"),
+            Self::Unreachable => write!(f, "No challenge socket for this address family"),
+            Self::Send(e) => write!(f, "Failed to send challenge: {}", e),
+            Self::Timeout => write!(f, "Timed out waiting for challenge response"),
+        }
+    }
+}
+
+/// Sends connect challenges to game servers from long-lived UDP sockets, one
+/// per address family.
+///
+/// A background task per socket reads every reply and hands it to the
+/// heartbeat waiting on the same source address and nonce.
+pub struct ChallengeDispatcher {
+    sockets: Vec<Arc<UdpSocket>>,
+    pending: Arc<PendingChallenges>,
+    in_flight: Semaphore,
+    timeout: Duration,
+}
+
+impl ChallengeDispatcher {
+    pub async fn bind(bind_addresses: &[&str], max_in_flight: usize, timeout: Duration) -> std::io::Result<Self> {
+        let sockets = bind_udp_sockets(bind_addresses).await?;
+        let pending = Arc::new(PendingChallenges::new());
+
+        for socket in &sockets {
+            tokio::spawn(receive_loop(socket.clone(), pending.clone()));
+        }
+
+        Ok(Self {
+            sockets,
+            pending,
+            in_flight: Semaphore::new(max_in_flight),
+            timeout,
+        })
+    }
+
+    pub fn local_addrs(&self) -> std::io::Result<Vec<SocketAddr>> {
+        self.sockets.iter().map(|socket| socket.local_addr()).collect()
+    }
+
+    /// Challenges the game server at `server_addr` and waits for a matching reply.
+    pub async fn verify(&self, server_addr: &SocketAddr) -> Result<(), ChallengeError> {
+        let socket = socket_for(&self.sockets, server_addr).ok_or(ChallengeError::Unreachable)?;
+        let _permit = self.in_flight.try_acquire().map_err(|_| ChallengeError::Busy)?;
+
+        let nonce = Nonce(rand::thread_rng().gen());
+        let challenge_packet = Packet::ConnectRequest { nonce }.encode();
+
+        let key = (*server_addr, nonce);
+        let (sender, receiver) = oneshot::channel();
+        self.pending.insert(key, sender);
+        let _pending = PendingGuard { pending: &self.pending, key };
+
+        if let Err(e) = socket.send_to(&challenge_packet, server_addr).await {
+            return Err(ChallengeError::Send(e));
+        }
+        debug!("Challenge sent to {} with nonce {}", server_addr, nonce);
+
+        match tokio::time::timeout(self.timeout, receiver).await {
+            Ok(Ok(result)) => {
+                if result.is_ok() {
+                    debug!("Received valid challenge response from {} with nonce: {}", server_addr, nonce);
+                }
+                result
+            }
+            _ => Err(ChallengeError::Timeout),
+        }
+    }
+}
+
+/// Takes a challenge out of `pending` however `verify` ends, including when the
+/// heartbeat awaiting it is dropped because its client hung up.
+struct PendingGuard<'a> {
+    pending: &'a PendingChallenges,
+    key: (SocketAddr, Nonce),
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.remove(&self.key);
+    }
+}
+
+async fn receive_loop(socket: Arc<UdpSocket>, pending: Arc<PendingChallenges>) {
+    let mut buffer = [0u8; 1024];
+    loop {
+        let (len, addr) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(e) => {
+                // ICMP errors from earlier sends surface here on some platforms
+                debug!("Error receiving on challenge socket: {}", e);
+                continue;
+            }
+        };
+
+        match Packet::decode(&buffer[..len]) {
+            Ok(Packet::ChallengeReply { nonce, .. }) => match pending.remove(&(addr, nonce)) {
+                Some((_, waiter)) => {
+                    let _ = waiter.send(Ok(()));
+                }
+                None => debug!("Dropping unexpected challenge response from {} with nonce {}", addr, nonce),
+            },
+            // Rejections don't echo the nonce, so one can't be tied to a challenge and anyone
+            // could spoof them. The challenge is left to time out instead.
+            Ok(Packet::ConnectReject { reason }) => debug!("Ignoring connect reject from {}: {}", addr, reason),
+            Ok(other) => debug!("Ignoring unexpected {:?} from {}", other, addr),
+            Err(e) => debug!("Received invalid challenge response from {} with len {}: {}", addr, len, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn dropped_verify_leaves_nothing_pending() {
+        let dispatcher = ChallengeDispatcher::bind(&["127.0.0.1:0"], 16, Duration::from_secs(60)).await.unwrap();
+        // Nobody answers on this socket, so the challenge stays in flight
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = silent.local_addr().unwrap();
+
+        let mut verify = Box::pin(dispatcher.verify(&server_addr));
+        assert!(futures_util::poll!(&mut verify).is_pending());
+        assert_eq!(dispatcher.pending.len(), 1);
+
+        drop(verify);
+        assert!(dispatcher.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn timed_out_verify_leaves_nothing_pending() {
+        let dispatcher = ChallengeDispatcher::bind(&["127.0.0.1:0"], 16, Duration::from_millis(20)).await.unwrap();
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let result = dispatcher.verify(&silent.local_addr().unwrap()).await;
+        assert!(matches!(result, Err(ChallengeError::Timeout)));
+        assert!(dispatcher.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn connect_rejects_dont_end_challenges() {
+        let dispatcher = ChallengeDispatcher::bind(&["127.0.0.1:0"], 16, Duration::from_secs(5)).await.unwrap();
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 1500];
+            let (len, from) = server.recv_from(&mut buffer).await.unwrap();
+            let Ok(Packet::ConnectRequest { nonce }) = Packet::decode(&buffer[..len]) else { return };
+            // A reject carries no nonce, so it could have come from anyone
+            let reject = Packet::ConnectReject { reason: "Server is full.".to_string() }.encode();
+            server.send_to(&reject, from).await.unwrap();
+            let reply = Packet::ChallengeReply { challenge: 42, nonce }.encode();
+            server.send_to(&reply, from).await.unwrap();
+        });
+
+        assert!(dispatcher.verify(&server_addr).await.is_ok());
+        assert!(dispatcher.pending.is_empty());
+    }
+}
@@ -3,6 +3,95 @@ use std::time::Duration;
 use std::num::NonZeroU32;
 use governor::Quota;
 
+/// Which `ServerStore` implementation holds the server registry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageBackend {
+    Memory,
+    Sqlite,
+}
+
+/// When a heartbeat's UDP challenge runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerificationMode {
+    /// The heartbeat request waits for the challenge.
+    Inline,
+    /// The heartbeat is answered with 202 and the challenge runs in the background.
+    Deferred,
+}
+
+/// What the prober does with a server whose query answers keep disagreeing with its heartbeats.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProbeMismatchAction {
+    /// Keep listing the server, but mark it as flagged.
+    Flag,
+    /// Remove the server from the registry. Player list mismatches alone only flag it.
+    Delist,
+}
+
+impl ProbeMismatchAction {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "flag" => Some(Self::Flag),
+            "delist" => Some(Self::Delist),
+            _ => None,
+        }
+    }
+}
+
+/// Who the master trusts to report the address of the client behind them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyMode {
+    /// Clients connect directly, forwarding headers are ignored.
+    Direct,
+    /// Requests must come through Cloudflare.
+    Cloudflare,
+    /// Requests must come through one of `trusted_proxies`.
+    Trusted,
+}
+
+fn parse_storage_backend(value: &str) -> Result<StorageBackend, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "memory" => Ok(StorageBackend::Memory),
+        "sqlite" => Ok(StorageBackend::Sqlite),
+        _ => Err(format!("Invalid STORAGE_BACKEND {:?}, expected memory or sqlite", value)),
+    }
+}
+
+fn parse_verification_mode(value: &str) -> Result<VerificationMode, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "inline" => Ok(VerificationMode::Inline),
+        "deferred" => Ok(VerificationMode::Deferred),
+        _ => Err(format!("Invalid VERIFICATION_MODE {:?}, expected inline or deferred", value)),
+    }
+}
+
+fn parse_proxy_mode(value: &str) -> Result<ProxyMode, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "direct" => Ok(ProxyMode::Direct),
+        "cloudflare" => Ok(ProxyMode::Cloudflare),
+        "trusted" => Ok(ProxyMode::Trusted),
+        _ => Err(format!("Invalid PROXY_MODE {:?}, expected direct, cloudflare or trusted", value)),
+    }
+}
+
+// Unset falls back to `default`, anything set has to parse
+fn mode_from_env<T>(name: &str, default: T, parse: fn(&str) -> Result<T, String>) -> Result<T, String> {
+    match env::var(name) {
+        Ok(value) => parse(&value),
+        Err(_) => Ok(default),
+    }
+}
+
+// Comma-separated, blanks dropped
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
 #[derive(Clone)]
 pub struct Config {
     // Rate limiting configs
@@ -18,6 +107,61 @@ pub struct Config {
     
     // Other configs
     pub server_timeout_secs: u64,
+    pub reaper_interval_secs: u64,
+
+    // Client address configs
+    pub proxy_mode: ProxyMode,
+    /// CIDRs of the reverse proxies in front of the master, for `ProxyMode::Trusted`.
+    pub trusted_proxies: Vec<String>,
+    /// Headers Cloudflare's client address is read from, in order. Empty for the
+    /// defaults, `CF-Connecting-IP` then `X-Forwarded-For`.
+    ///
+    /// Earlier versions also took a client-sent `X-Real-IP` over `CF-Connecting-IP`.
+    /// It isn't read by default anymore, since any client can set it. Deployments
+    /// that relied on it can list it first in `CLOUDFLARE_FORWARDED_HEADERS`.
+    pub cloudflare_forwarded_headers: Vec<String>,
+    /// Headers `trusted_proxies` report the client address in, in order. Empty for the defaults.
+    pub trusted_forwarded_headers: Vec<String>,
+
+    // Storage configs
+    pub storage_backend: StorageBackend,
+    pub sqlite_path: String,
+    pub snapshot_path: Option<String>,
+    pub snapshot_interval_secs: u64,
+    pub change_log_capacity: usize,
+    pub history_minute_buckets: usize,
+    pub history_hour_buckets: usize,
+    pub history_day_buckets: usize,
+    /// Map series kept in the player history. Past this the least recently sampled is dropped.
+    pub history_max_maps: usize,
+    pub reliability_half_life_secs: u64,
+
+    // Challenge session configs
+    pub session_secret: Option<String>,
+    pub session_token_ttl_secs: u64,
+
+    // UDP challenge configs
+    pub challenge_bind_address: String,
+    /// IPv6 challenge socket, empty to challenge over IPv4 only.
+    pub challenge_bind_address_v6: String,
+    pub challenge_max_in_flight: usize,
+    pub challenge_timeout_ms: u64,
+    pub verification_mode: VerificationMode,
+    pub verification_result_ttl_secs: u64,
+
+    // Server list configs
+    pub list_cache_debounce_ms: u64,
+    pub list_cache_max_entries: usize,
+    pub event_stream_max_clients: usize,
+
+    // Server query prober configs
+    pub probe_interval_secs: u64,
+    pub probe_bind_address: String,
+    pub probe_bind_address_v6: String,
+    pub probe_timeout_ms: u64,
+    pub probe_concurrency: usize,
+    pub probe_mismatch_threshold: u32,
+    pub probe_mismatch_action: ProbeMismatchAction,
 }
 
 impl Default for Config {
@@ -32,13 +176,55 @@ impl Default for Config {
             server_delete_burst_limit: 1,
             max_servers_per_ip: 3,
             server_timeout_secs: 300, // 5 minutes
+            reaper_interval_secs: 5,
+            proxy_mode: ProxyMode::Cloudflare,
+            trusted_proxies: Vec::new(),
+            cloudflare_forwarded_headers: Vec::new(),
+            trusted_forwarded_headers: Vec::new(),
+            storage_backend: StorageBackend::Memory,
+            sqlite_path: "r1ms.db".to_string(),
+            snapshot_path: None,
+            snapshot_interval_secs: 60,
+            change_log_capacity: 4096,
+            history_minute_buckets: 360,
+            history_hour_buckets: 168,
+            history_day_buckets: 90,
+            history_max_maps: 256,
+            reliability_half_life_secs: 86400, // 1 day
+            session_secret: None,
+            session_token_ttl_secs: 300,
+            challenge_bind_address: "0.0.0.0:0".to_string(),
+            challenge_bind_address_v6: "[::]:0".to_string(),
+            challenge_max_in_flight: 512,
+            challenge_timeout_ms: 2000,
+            verification_mode: VerificationMode::Inline,
+            verification_result_ttl_secs: 60,
+            list_cache_debounce_ms: 1000,
+            list_cache_max_entries: 256,
+            event_stream_max_clients: 1024,
+            probe_interval_secs: 0, // disabled
+            probe_bind_address: "0.0.0.0:0".to_string(),
+            probe_bind_address_v6: "[::]:0".to_string(),
+            probe_timeout_ms: 1000,
+            probe_concurrency: 64,
+            probe_mismatch_threshold: 3,
+            probe_mismatch_action: ProbeMismatchAction::Flag,
         }
     }
 }
 
 impl Config {
-    pub fn from_env() -> Self {
-        Self {
+    /// Reads the configuration from the environment, falling back to the defaults
+    /// for unset or unparsable values. The modes are the exception: a typo in
+    /// `PROXY_MODE`, `STORAGE_BACKEND` or `VERIFICATION_MODE` is an error, since
+    /// quietly running with another trust policy, backend or verification would
+    /// go unnoticed.
+    pub fn from_env() -> Result<Self, String> {
+        let proxy_mode = mode_from_env("PROXY_MODE", ProxyMode::Cloudflare, parse_proxy_mode)?;
+        let storage_backend = mode_from_env("STORAGE_BACKEND", StorageBackend::Memory, parse_storage_backend)?;
+        let verification_mode = mode_from_env("VERIFICATION_MODE", VerificationMode::Inline, parse_verification_mode)?;
+
+        Ok(Self {
             heartbeat_period_secs: env::var("HEARTBEAT_PERIOD_SECS")
                 .ok()
                 .and_then(|v| v.parse().ok())
@@ -78,7 +264,152 @@ impl Config {
                 .ok()
                 .and_then(|v| v.parse().ok())
                 .unwrap_or(30),
-        }
+
+            reaper_interval_secs: env::var("REAPER_INTERVAL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|v| *v > 0)
+                .unwrap_or(5),
+
+            proxy_mode,
+
+            trusted_proxies: env::var("TRUSTED_PROXIES")
+                .map(|v| parse_list(&v))
+                .unwrap_or_default(),
+
+            cloudflare_forwarded_headers: env::var("CLOUDFLARE_FORWARDED_HEADERS")
+                .map(|v| parse_list(&v))
+                .unwrap_or_default(),
+
+            trusted_forwarded_headers: env::var("TRUSTED_FORWARDED_HEADERS")
+                .map(|v| parse_list(&v))
+                .unwrap_or_default(),
+
+            storage_backend,
+
+            sqlite_path: env::var("SQLITE_PATH")
+                .unwrap_or_else(|_| "r1ms.db".to_string()),
+
+            snapshot_path: env::var("SNAPSHOT_PATH")
+                .ok()
+                .filter(|v| !v.is_empty()),
+
+            snapshot_interval_secs: env::var("SNAPSHOT_INTERVAL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|v| *v > 0)
+                .unwrap_or(60),
+
+            change_log_capacity: env::var("CHANGE_LOG_CAPACITY")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(4096),
+
+            history_minute_buckets: env::var("HISTORY_MINUTE_BUCKETS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(360),
+
+            history_hour_buckets: env::var("HISTORY_HOUR_BUCKETS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(168),
+
+            history_day_buckets: env::var("HISTORY_DAY_BUCKETS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(90),
+
+            history_max_maps: env::var("HISTORY_MAX_MAPS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|v| *v > 0)
+                .unwrap_or(256),
+
+            reliability_half_life_secs: env::var("RELIABILITY_HALF_LIFE_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(86400),
+
+            session_secret: env::var("SESSION_SECRET")
+                .ok()
+                .filter(|v| !v.is_empty()),
+
+            session_token_ttl_secs: env::var("SESSION_TOKEN_TTL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(300),
+
+            challenge_bind_address: env::var("CHALLENGE_BIND_ADDRESS")
+                .unwrap_or_else(|_| "0.0.0.0:0".to_string()),
+
+            challenge_bind_address_v6: env::var("CHALLENGE_BIND_ADDRESS_V6")
+                .unwrap_or_else(|_| "[::]:0".to_string()),
+
+            challenge_max_in_flight: env::var("CHALLENGE_MAX_IN_FLIGHT")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(512),
+
+            challenge_timeout_ms: env::var("CHALLENGE_TIMEOUT_MS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(2000),
+
+            verification_mode,
+
+            verification_result_ttl_secs: env::var("VERIFICATION_RESULT_TTL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(60),
+
+            list_cache_debounce_ms: env::var("LIST_CACHE_DEBOUNCE_MS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(1000),
+
+            list_cache_max_entries: env::var("LIST_CACHE_MAX_ENTRIES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(256),
+
+            event_stream_max_clients: env::var("EVENT_STREAM_MAX_CLIENTS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(1024),
+
+            probe_interval_secs: env::var("PROBE_INTERVAL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+
+            probe_bind_address: env::var("PROBE_BIND_ADDRESS")
+                .unwrap_or_else(|_| "0.0.0.0:0".to_string()),
+
+            probe_bind_address_v6: env::var("PROBE_BIND_ADDRESS_V6")
+                .unwrap_or_else(|_| "[::]:0".to_string()),
+
+            probe_timeout_ms: env::var("PROBE_TIMEOUT_MS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(1000),
+
+            probe_concurrency: env::var("PROBE_CONCURRENCY")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(64),
+
+            probe_mismatch_threshold: env::var("PROBE_MISMATCH_THRESHOLD")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|v| *v > 0)
+                .unwrap_or(3),
+
+            probe_mismatch_action: env::var("PROBE_MISMATCH_ACTION")
+                .ok()
+                .and_then(|v| ProbeMismatchAction::parse(&v))
+                .unwrap_or(ProbeMismatchAction::Flag),
+        })
     }
     
     pub fn heartbeat_quota(&self) -> Quota {
@@ -99,3 +430,22 @@ impl Config {
             .allow_burst(NonZeroU32::new(self.server_delete_burst_limit).unwrap())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_modes_strictly() {
+        assert_eq!(parse_proxy_mode("Trusted"), Ok(ProxyMode::Trusted));
+        assert_eq!(parse_proxy_mode("direct"), Ok(ProxyMode::Direct));
+        assert!(parse_proxy_mode("cloudfare").is_err());
+        assert!(parse_proxy_mode("").is_err());
+
+        assert_eq!(parse_storage_backend("SQLite"), Ok(StorageBackend::Sqlite));
+        assert!(parse_storage_backend("sqllite").is_err());
+
+        assert_eq!(parse_verification_mode("deferred"), Ok(VerificationMode::Deferred));
+        assert!(parse_verification_mode("defered").is_err());
+    }
+}
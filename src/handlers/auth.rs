@@ -1,21 +1,11 @@
 use actix_web::{ web, HttpResponse, HttpRequest };
-use capnp::message::ReaderOptions;
-use log::{ debug, error };
-use serde_json::json;
-use std::net::{ IpAddr, SocketAddr };
-use crate::storage::memory::ServerStorage;
-use crate::models::server::{ ServerInfo, Player };
-use crate::schema::server_heartbeat;
-use governor::{ RateLimiter, clock::DefaultClock };
-use governor::state::keyed::DefaultKeyedStateStore;
-use crate::utils::{ extract_real_ip, format_address_for_challenge, RequestError, log_all_headers };
-use tokio::net::UdpSocket;
-use rand::Rng;
-use std::fmt::Write;
+use log::error;
+use crate::storage::ServerStore;
+use crate::utils::RequestError;
 
 pub async fn handle_auth(
     req: HttpRequest,
-    storage: web::Data<ServerStorage>
+    _storage: web::Data<dyn ServerStore>
 ) -> Result<HttpResponse, RequestError> {
     // check for code in query string
     let code = match req.query_string().split("code=").last() {
@@ -85,7 +75,7 @@ pub async fn handle_auth(
         }
     };
 
-    let refresh_token = match token_response.get("refresh_token") {
+    let _refresh_token = match token_response.get("refresh_token") {
         Some(t) => t,
         None => {
             error!("Failed to get refresh token from discord response");
@@ -93,7 +83,7 @@ pub async fn handle_auth(
         }
     };
 
-    let token_time = match token_response.get("expires_in") {
+    let _token_time = match token_response.get("expires_in") {
         Some(t) => t,
         None => {
             error!("Failed to get token time from discord response");
@@ -136,7 +126,7 @@ pub async fn handle_auth(
 
     println!("Guild info: {:?}", guild_info);
 
-    if !guild_info.get("roles").is_some() {
+    if guild_info.get("roles").is_none() {
         return Ok(HttpResponse::BadRequest().body("You are not in the correct guild"));
     }
 
@@ -0,0 +1,257 @@
+// src/handlers/events.rs
+use actix_web::{ web, HttpRequest, HttpResponse };
+use actix_web::http::header::{ CacheControl, CacheDirective, ContentEncoding };
+use actix_web::web::Bytes;
+use futures_util::stream;
+use crate::rate_limit::ListLimiter;
+use log::{ debug, error };
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{ broadcast, OwnedSemaphorePermit, Semaphore };
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{ interval_at, Instant, Interval };
+use crate::models::server_view::{ RemovedView, ServerListView, ServerView };
+use crate::storage::ServerStore;
+use crate::storage::events::ServerEvent;
+use crate::utils::{ extract_real_ip, rate_limit_key, RequestError };
+
+// Comment lines keep proxies from closing idle streams and let us notice gone clients
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Caps how many event streams can be open at once.
+pub struct EventStreamLimit(Arc<Semaphore>);
+
+impl EventStreamLimit {
+    pub fn new(max_clients: usize) -> Self {
+        Self(Arc::new(Semaphore::new(max_clients)))
+    }
+}
+
+struct EventStream {
+    storage: web::Data<dyn ServerStore>,
+    events: broadcast::Receiver<ServerEvent>,
+    keepalive: Interval,
+    send_snapshot: bool,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl EventStream {
+    async fn next_chunk(&mut self) -> Option<Bytes> {
+        if std::mem::take(&mut self.send_snapshot) {
+            return Some(snapshot_event(self.storage.get_ref()));
+        }
+
+        tokio::select! {
+            event = self.events.recv() => match event {
+                Ok(event) => Some(server_event(&event)),
+                Err(RecvError::Lagged(skipped)) => {
+                    // Missed events can't be replayed, so start the client over from a fresh snapshot
+                    debug!("Event stream lagged by {} events, resending snapshot", skipped);
+                    Some(snapshot_event(self.storage.get_ref()))
+                }
+                Err(RecvError::Closed) => None,
+            },
+            _ = self.keepalive.tick() => Some(Bytes::from_static(b": keepalive\n\n")),
+        }
+    }
+}
+
+fn sse_event(name: &str, data: &impl Serialize) -> Bytes {
+    let data = serde_json::to_string(data).expect("Failed to serialize event");
+    Bytes::from(format!("event: {}\ndata: {}\n\n", name, data))
+}
+
+fn snapshot_event(storage: &dyn ServerStore) -> Bytes {
+    let version = storage.version();
+    let mut servers = storage.get_servers();
+    servers.sort_by(|a, b| (a.first_seen, &a.id).cmp(&(b.first_seen, &b.id)));
+
+    let view = ServerListView {
+        servers: servers.iter().map(ServerView::from).collect(),
+        total_count: servers.len(),
+        next_cursor: None,
+        version,
+    };
+    sse_event("snapshot", &view)
+}
+
+fn server_event(event: &ServerEvent) -> Bytes {
+    match event {
+        ServerEvent::Added { server } => sse_event("added", &ServerView::from(server)),
+        ServerEvent::Updated { server } => sse_event("updated", &ServerView::from(server)),
+        ServerEvent::Removed { server, reason } => {
+            sse_event("removed", &RemovedView { id: &server.id, reason: *reason })
+        }
+    }
+}
+
+/// Streams the server list as Server-Sent Events: a `snapshot` of every server, then
+/// `added`, `updated` and `removed` events as the registry changes.
+///
+/// Events published while the snapshot is taken may repeat what it already shows,
+/// so clients should apply `added` and `updated` as upserts.
+pub async fn stream_events(
+    req: HttpRequest,
+    storage: web::Data<dyn ServerStore>,
+    rate_limiter: web::Data<ListLimiter>,
+    limit: web::Data<EventStreamLimit>,
+) -> Result<HttpResponse, RequestError> {
+    let peer_ip = extract_real_ip(&req)?;
+
+    // Rate Limiting
+    if rate_limiter.check_key(&rate_limit_key(peer_ip)).is_err() {
+        error!("Rate limit exceeded for event stream for ip: {}", peer_ip);
+        return Err(RequestError::RateLimitExceeded);
+    }
+
+    let Ok(permit) = limit.0.clone().try_acquire_owned() else {
+        error!("Too many event stream clients, rejecting {}", peer_ip);
+        return Ok(HttpResponse::ServiceUnavailable().body("Too many event stream clients, try again later"));
+    };
+
+    // Subscribe before the snapshot is taken so nothing published in between is lost
+    let events = storage.subscribe();
+    let state = EventStream {
+        storage,
+        events,
+        keepalive: interval_at(Instant::now() + KEEPALIVE_INTERVAL, KEEPALIVE_INTERVAL),
+        send_snapshot: true,
+        _permit: permit,
+    };
+
+    let body = stream::unfold(state, |mut state| async move {
+        let chunk = state.next_chunk().await?;
+        Some((Ok::<_, actix_web::Error>(chunk), state))
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        // Keeps the compression middleware from buffering the stream
+        .insert_header(ContentEncoding::Identity)
+        .streaming(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test as actix_test;
+    use actix_web::body::{ BoxBody, MessageBody };
+    use actix_web::http::StatusCode;
+    use futures_util::future::poll_fn;
+    use serde_json::Value;
+    use std::pin::Pin;
+    use crate::config::Config;
+    use crate::handlers::test_support;
+    use crate::models::server::ServerInfo;
+    use crate::storage::events::RemovalReason;
+    use crate::storage::memory::ServerStorage;
+
+    fn storage() -> Arc<dyn ServerStore> {
+        Arc::new(ServerStorage::new(Config { max_servers_per_ip: 8, ..Config::default() }))
+    }
+
+    /// Opens `count` event streams against `storage`.
+    async fn open_streams(storage: &Arc<dyn ServerStore>, limit: &web::Data<EventStreamLimit>, count: usize) -> Vec<(StatusCode, BoxBody)> {
+        let app = actix_test::init_service(
+            test_support::app(storage)
+                .app_data(limit.clone())
+                .route("/server/events", web::get().to(stream_events))
+        ).await;
+        let mut streams = Vec::new();
+        for _ in 0..count {
+            let req = actix_test::TestRequest::get().uri("/server/events").peer_addr("127.0.0.1:50000".parse().unwrap());
+            let response = actix_test::call_service(&app, req.to_request()).await;
+            streams.push((response.status(), response.into_body()));
+        }
+        streams
+    }
+
+    /// Splits an SSE chunk into its event name and JSON data.
+    fn parse_event(chunk: &[u8]) -> (String, Value) {
+        let chunk = std::str::from_utf8(chunk).unwrap();
+        let name = chunk.strip_prefix("event: ").and_then(|rest| rest.split('\n').next()).unwrap();
+        let data = chunk.split("\ndata: ").nth(1).unwrap().trim_end();
+        (name.to_string(), serde_json::from_str(data).unwrap())
+    }
+
+    async fn next_event(body: &mut BoxBody) -> (String, Value) {
+        let chunk = tokio::time::timeout(Duration::from_secs(1), poll_fn(|cx| Pin::new(&mut *body).poll_next(cx)))
+            .await
+            .expect("no event within a second")
+            .expect("stream ended")
+            .unwrap();
+        parse_event(&chunk)
+    }
+
+    #[actix_web::test]
+    async fn streams_snapshot_then_changes() {
+        let storage = storage();
+        storage.add_server(ServerInfo::for_test(0)).unwrap();
+        let (status, mut body) = open_streams(&storage, &web::Data::new(EventStreamLimit::new(4)), 1).await.pop().unwrap();
+        assert_eq!(status, StatusCode::OK);
+
+        let (name, snapshot) = next_event(&mut body).await;
+        assert_eq!(name, "snapshot");
+        assert_eq!(snapshot["totalCount"], 1);
+        assert_eq!(snapshot["servers"][0]["id"], "server-0");
+        assert_eq!(snapshot["version"], storage.version());
+
+        storage.add_server(ServerInfo::for_test(1)).unwrap();
+        let (name, added) = next_event(&mut body).await;
+        assert_eq!(name, "added");
+        assert_eq!(added["id"], "server-1");
+
+        storage.add_server(ServerInfo { map_name: "mp_angel_city".to_string(), ..ServerInfo::for_test(1) }).unwrap();
+        let (name, updated) = next_event(&mut body).await;
+        assert_eq!(name, "updated");
+        assert_eq!(updated["mapName"], "mp_angel_city");
+
+        storage.remove_server("server-0", RemovalReason::Deleted);
+        let (name, removed) = next_event(&mut body).await;
+        assert_eq!(name, "removed");
+        assert_eq!(removed, serde_json::json!({ "id": "server-0", "reason": "deleted" }));
+    }
+
+    #[actix_web::test]
+    async fn resends_snapshot_after_lagging() {
+        let storage = storage();
+        let (sender, events) = broadcast::channel(2);
+        let mut stream = EventStream {
+            storage: web::Data::from(storage.clone()),
+            events,
+            keepalive: interval_at(Instant::now() + KEEPALIVE_INTERVAL, KEEPALIVE_INTERVAL),
+            send_snapshot: false,
+            _permit: Arc::new(Semaphore::new(1)).try_acquire_owned().unwrap(),
+        };
+
+        // More events than the channel holds, so the oldest are lost to this subscriber
+        for i in 0..4 {
+            let server = storage.add_server(ServerInfo::for_test(i)).unwrap();
+            sender.send(ServerEvent::Added { server }).unwrap();
+        }
+        let (name, snapshot) = parse_event(&stream.next_chunk().await.unwrap());
+        assert_eq!(name, "snapshot");
+        assert_eq!(snapshot["totalCount"], 4);
+
+        // and then carries on with what's still buffered
+        let (name, added) = parse_event(&stream.next_chunk().await.unwrap());
+        assert_eq!(name, "added");
+        assert_eq!(added["id"], "server-2");
+    }
+
+    #[actix_web::test]
+    async fn rejects_clients_beyond_the_cap() {
+        let storage = storage();
+        let limit = web::Data::new(EventStreamLimit::new(2));
+        let mut streams = open_streams(&storage, &limit, 3).await;
+        let statuses: Vec<StatusCode> = streams.iter().map(|(status, _)| *status).collect();
+        assert_eq!(statuses, [StatusCode::OK, StatusCode::OK, StatusCode::SERVICE_UNAVAILABLE]);
+
+        // A closed stream frees its slot
+        streams.truncate(1);
+        let statuses: Vec<StatusCode> = open_streams(&storage, &limit, 2).await.iter().map(|(status, _)| *status).collect();
+        assert_eq!(statuses, [StatusCode::OK, StatusCode::SERVICE_UNAVAILABLE]);
+    }
+}
@@ -1,23 +1,41 @@
 // src/handlers/heartbeat.rs
-use actix_web::{ web, HttpResponse, HttpRequest };
+use actix_web::{ web, HttpResponse, HttpResponseBuilder, HttpRequest };
+use actix_web::http::header;
 use capnp::message::ReaderOptions;
 use log::{ debug, error };
 use std::net::{ IpAddr, SocketAddr };
-use crate::storage::memory::ServerStorage;
+use crate::storage::ServerStore;
 use crate::models::server::{ ServerInfo, Player };
-use crate::schema::server_heartbeat;
-use governor::{ RateLimiter, clock::DefaultClock };
-use governor::state::keyed::DefaultKeyedStateStore;
-use crate::utils::{ extract_real_ip, format_address_for_challenge, RequestError, log_all_headers };
-use tokio::net::UdpSocket;
+use crate::schema::{ self, heartbeat_response, server_heartbeat };
+use crate::rate_limit::HeartbeatLimiter;
+use crate::utils::{
+    canonical_ip,
+    extract_real_ip,
+    format_address_for_challenge,
+    hex_encode,
+    log_all_headers,
+    rate_limit_key,
+    RequestError,
+};
+use crate::session::SessionSigner;
+use crate::challenge::{ ChallengeDispatcher, ChallengeError };
+use crate::verifier::{ DeferredVerifier, SubmitError, Verification, VerificationStatus };
 use rand::Rng;
-use std::fmt::Write;
+
+// Header carrying the secret issued alongside the server ID
+const SERVER_TOKEN_HEADER: &str = "X-Server-Token";
+// Header carrying the session token issued after a successful challenge
+const SESSION_TOKEN_HEADER: &str = "X-Session-Token";
+const PACKED_CONTENT_TYPE: &str = "application/x-capnproto-packed";
 
 pub async fn handle_heartbeat(
     req: HttpRequest,
-    storage: web::Data<ServerStorage>,
+    storage: web::Data<dyn ServerStore>,
     bytes: web::Bytes,
-    rate_limiter: web::Data<RateLimiter<IpAddr, DefaultKeyedStateStore<IpAddr>, DefaultClock>>
+    rate_limiter: web::Data<HeartbeatLimiter>,
+    session_signer: web::Data<SessionSigner>,
+    challenge: web::Data<ChallengeDispatcher>,
+    verifier: Option<web::Data<DeferredVerifier>>
 ) -> Result<HttpResponse, RequestError> {
     // Log all headers for debugging
     log_all_headers(&req);
@@ -31,7 +49,7 @@ pub async fn handle_heartbeat(
     debug!("Normalized IP for processing: {}", normalized_ip);
 
     // Rate Limiting
-    if !rate_limiter.check_key(&normalized_ip).is_ok() {
+    if rate_limiter.check_key(&rate_limit_key(normalized_ip)).is_err() {
         error!("Rate limit exceeded for heartbeat for ip: {}", normalized_ip);
         return Err(RequestError::RateLimitExceeded);
     }
@@ -43,10 +61,20 @@ pub async fn handle_heartbeat(
         return Ok(HttpResponse::BadRequest().body("Empty request"));
     }
 
+    // Packed bodies are opt-in, identified by their content type
+    let packed = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with(PACKED_CONTENT_TYPE));
+
     let mut slice = bytes.as_ref();
-    let reader = match
-        capnp::serialize::read_message_from_flat_slice(&mut slice, ReaderOptions::new())
-    {
+    let message = if packed {
+        capnp::serialize_packed::read_message(&mut slice, ReaderOptions::new())
+    } else {
+        capnp::serialize::read_message(&mut slice, ReaderOptions::new())
+    };
+    let reader = match message {
         Ok(reader) => reader,
         Err(e) => {
             error!("Failed to read Cap'n Proto message: {}", e);
@@ -64,6 +92,20 @@ pub async fn handle_heartbeat(
 
     let claimed_port = heartbeat.get_port();
 
+    // A presented token has to be the entry's. Servers that don't send one at all
+    // (older builds) keep refreshing their entry from the same ip:port.
+    let presented_token = req
+        .headers()
+        .get(SERVER_TOKEN_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty());
+    if let (Some(existing), Some(presented)) = (storage.find_server(&normalized_ip.to_string(), claimed_port), presented_token) {
+        if !existing.token.is_empty() && presented != existing.token {
+            error!("Invalid server token for {}:{}", normalized_ip, claimed_port);
+            return Ok(HttpResponse::Forbidden().body("Invalid server token"));
+        }
+    }
+
     // Format address properly for challenge
     let socket_addr = match format_address_for_challenge(normalized_ip, claimed_port) {
         Ok(addr) => addr,
@@ -73,11 +115,141 @@ pub async fn handle_heartbeat(
         }
     };
 
-    if !verify_challenge(&socket_addr).await {
-        error!("Challenge response failed from {}:{}", normalized_ip, claimed_port);
-        return Ok(HttpResponse::BadRequest().body("Challenge response failed"));
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+    let mut server_info = match parse_heartbeat(&heartbeat, normalized_ip, now) {
+        Ok(server_info) => server_info,
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e)),
+    };
+    let minted_id = server_info.id.clone();
+
+    // An alternate address the entry doesn't have yet has to answer a challenge as well
+    let unverified_alt_addr = server_info.alt_ip.parse::<IpAddr>().ok().filter(|_| {
+        !storage
+            .find_server(&server_info.ip, server_info.port)
+            .is_some_and(|existing| existing.ip == server_info.alt_ip || existing.alt_ip == server_info.alt_ip)
+    }).map(|alt_ip| SocketAddr::new(alt_ip, socket_addr.port()));
+
+    // A valid session token means this ip:port passed a challenge recently
+    let presented_session = req
+        .headers()
+        .get(SESSION_TOKEN_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|token| session_signer.verify(token, &socket_addr));
+
+    let session_token = match presented_session {
+        Some(token) => {
+            debug!("Valid session token from {}, skipping challenge", socket_addr);
+            token.to_string()
+        }
+        None => {
+            if let Some(verifier) = verifier {
+                // Answer now and let the background verifier run the challenge. A wrong token
+                // was turned away above, so this is the existing entry's server or an older
+                // build that doesn't send one.
+                if let Some(existing) = storage.find_server(&server_info.ip, server_info.port) {
+                    server_info.id = existing.id;
+                    server_info.token = existing.token;
+                    server_info.first_seen = existing.first_seen;
+                }
+                return match verifier.submit(server_info, socket_addr, presented_token) {
+                    Ok(mut verification) => {
+                        withhold_existing_identity(&mut verification, &minted_id, presented_token);
+                        Ok(build_heartbeat_response(HttpResponse::Accepted(), &verification))
+                    }
+                    Err(SubmitError::TokenMismatch) => {
+                        error!("Invalid server token for pending verification of {}:{}", normalized_ip, claimed_port);
+                        Ok(HttpResponse::Forbidden().body("Invalid server token"))
+                    }
+                    Err(e) => {
+                        error!("Failed to queue verification for {}:{}: {}", normalized_ip, claimed_port, e);
+                        Ok(HttpResponse::ServiceUnavailable().body(e.to_string()))
+                    }
+                };
+            }
+
+            match challenge.verify(&socket_addr).await {
+                Ok(()) => {}
+                Err(ChallengeError::Busy) => {
+                    error!("Too many challenges in flight, rejecting {}:{}", normalized_ip, claimed_port);
+                    return Ok(HttpResponse::ServiceUnavailable().body("Too many pending challenges, try again later"));
+                }
+                Err(ChallengeError::Unreachable) => {
+                    error!("No challenge socket for {}:{}", normalized_ip, claimed_port);
+                    return Ok(HttpResponse::BadRequest().body("Servers of this address family can't be challenged"));
+                }
+                Err(e) => {
+                    error!("Challenge response failed from {}:{}: {}", normalized_ip, claimed_port, e);
+                    storage.record_failed_challenge(&server_info.ip, server_info.port);
+                    return Ok(HttpResponse::BadRequest().body("Challenge response failed"));
+                }
+            }
+            session_signer.issue(&socket_addr)
+        }
+    };
+
+    if let Some(alt_addr) = unverified_alt_addr {
+        match challenge.verify(&alt_addr).await {
+            Ok(()) => {}
+            Err(ChallengeError::Busy) => {
+                error!("Too many challenges in flight, rejecting {}", alt_addr);
+                return Ok(HttpResponse::ServiceUnavailable().body("Too many pending challenges, try again later"));
+            }
+            Err(ChallengeError::Unreachable) => {
+                error!("No challenge socket for alternate address {}", alt_addr);
+                return Ok(HttpResponse::BadRequest().body("Servers of this address family can't be challenged"));
+            }
+            Err(e) => {
+                error!("Challenge response failed from alternate address {}: {}", alt_addr, e);
+                storage.record_failed_challenge(&server_info.ip, server_info.port);
+                return Ok(HttpResponse::BadRequest().body("Challenge response failed on alt_ip"));
+            }
+        }
+    }
+
+    match storage.add_server(server_info) {
+        Ok(server) => {
+            let mut verification = Verification::verified(server, session_token);
+            withhold_existing_identity(&mut verification, &minted_id, presented_token);
+            Ok(build_heartbeat_response(HttpResponse::Ok(), &verification))
+        }
+        Err(e) => {
+            error!("Failed to add server: {}", e);
+            Ok(HttpResponse::BadRequest().body(e))
+        }
+    }
+}
+
+/// Reports how a deferred heartbeat's verification went.
+pub async fn verification_status(
+    req: HttpRequest,
+    path: web::Path<String>,
+    rate_limiter: web::Data<HeartbeatLimiter>,
+    verifier: Option<web::Data<DeferredVerifier>>
+) -> Result<HttpResponse, RequestError> {
+    let real_ip = extract_real_ip(&req)?;
+    if rate_limiter.check_key(&rate_limit_key(real_ip)).is_err() {
+        error!("Rate limit exceeded for verification status for ip: {}", real_ip);
+        return Err(RequestError::RateLimitExceeded);
+    }
+
+    let Some(verifier) = verifier else {
+        return Ok(HttpResponse::NotFound().body("Deferred verification is disabled"));
+    };
+    let Some(verification) = verifier.status(&path.into_inner()) else {
+        return Ok(HttpResponse::NotFound().body("Unknown verification"));
+    };
+
+    let presented_token = req.headers().get(SERVER_TOKEN_HEADER).and_then(|v| v.to_str().ok());
+    if presented_token != Some(verification.server.token.as_str()) {
+        error!("Invalid server token polling verification of {}", verification.server.id);
+        return Ok(HttpResponse::Forbidden().body("Invalid server token"));
     }
 
+    Ok(build_heartbeat_response(HttpResponse::Ok(), &verification))
+}
+
+/// Validates a heartbeat and turns it into the entry it would register.
+fn parse_heartbeat(heartbeat: &server_heartbeat::Reader, ip: IpAddr, now: u64) -> Result<ServerInfo, String> {
     let hostname = heartbeat.get_hostname().unwrap_or("").to_string();
     let map_name = heartbeat.get_map_name().unwrap_or("").to_string();
     let game_mode = heartbeat.get_game_mode().unwrap_or("").to_string();
@@ -87,48 +259,56 @@ pub async fn handle_heartbeat(
     // Perform the data validation checks:
     if hostname.is_empty() {
         error!("Invalid hostname: Empty value");
-        return Ok(HttpResponse::BadRequest().body("Invalid hostname: Must be at least 1 char."));
+        return Err("Invalid hostname: Must be at least 1 char.".to_string());
     }
     if hostname.len() > 64 {
         error!("Invalid hostname: Too long. {}", hostname);
-        return Ok(HttpResponse::BadRequest().body("Invalid hostname: Too long (max 64 chars)."));
+        return Err("Invalid hostname: Too long (max 64 chars).".to_string());
     }
     if map_name.is_empty() {
         error!("Invalid map_name: Empty value");
-        return Ok(HttpResponse::BadRequest().body("Invalid map_name: Must be at least 1 char."));
+        return Err("Invalid map_name: Must be at least 1 char.".to_string());
     }
     if
         map_name.len() > 32 ||
-        !map_name.chars().all(|c| (c.is_ascii_lowercase() || c == '_' || c.is_ascii_digit()))
+        !map_name.chars().all(|c| c.is_ascii_lowercase() || c == '_' || c.is_ascii_digit())
     {
         error!("Invalid map_name: {}, must be <= 32 chars, only a-z and underscore", map_name);
-        return Ok(
-            HttpResponse::BadRequest().body(
-                "Invalid map_name: must be <= 32 chars, only a-z and underscore."
-            )
-        );
+        return Err("Invalid map_name: must be <= 32 chars, only a-z and underscore.".to_string());
     }
     if game_mode.is_empty() {
         error!("Invalid game_mode: Empty value");
-        return Ok(HttpResponse::BadRequest().body("Invalid game_mode: Must be at least 1 char."));
+        return Err("Invalid game_mode: Must be at least 1 char.".to_string());
     }
-    if game_mode.len() > 32 || !game_mode.chars().all(|c| (c.is_ascii_lowercase() || c == '_')) {
+    if game_mode.len() > 32 || !game_mode.chars().all(|c| c.is_ascii_lowercase() || c == '_') {
         error!("Invalid game_mode: {}, must be <= 32 chars, only a-z and underscore", game_mode);
-        return Ok(
-            HttpResponse::BadRequest().body(
-                "Invalid game_mode: must be <= 32 chars, only a-z and underscore."
-            )
-        );
+        return Err("Invalid game_mode: must be <= 32 chars, only a-z and underscore.".to_string());
     }
     if max_players >= 20 {
         error!("Invalid max_players: {}, must be less than 20", max_players);
-        return Ok(HttpResponse::BadRequest().body("Invalid max_players: must be less than 20."));
+        return Err("Invalid max_players: must be less than 20.".to_string());
     }
     if port <= 1024 {
         error!("Invalid port: {}, must be higher than 1024", port);
-        return Ok(HttpResponse::BadRequest().body("Invalid port: must be higher than 1024."));
+        return Err("Invalid port: must be higher than 1024.".to_string());
     }
 
+    // Dual-stack servers also advertise their address of the other family
+    let alt_ip = match heartbeat.get_alt_ip().unwrap_or("") {
+        "" => String::new(),
+        alt_ip => match alt_ip.parse::<IpAddr>().map(canonical_ip) {
+            Ok(alt_ip)
+                if alt_ip.is_ipv6() != ip.is_ipv6()
+                    && !alt_ip.is_unspecified()
+                    && !alt_ip.is_loopback()
+                    && !alt_ip.is_multicast() => alt_ip.to_string(),
+            _ => {
+                error!("Invalid alt_ip: {}, must be a unicast address of the other family than {}", alt_ip, ip);
+                return Err("Invalid alt_ip: must be a unicast address of the other IP family.".to_string());
+            }
+        },
+    };
+
     let players = match heartbeat.get_players() {
         Ok(player_list) => {
             let mut players = Vec::new();
@@ -136,11 +316,7 @@ pub async fn handle_heartbeat(
                 let player_name = player.get_name().unwrap_or("").to_string();
                 if player_name.is_empty() {
                     error!("Invalid player name: Empty value");
-                    return Ok(
-                        HttpResponse::BadRequest().body(
-                            "Invalid player name: Must be at least 1 char."
-                        )
-                    );
+                    return Err("Invalid player name: Must be at least 1 char.".to_string());
                 }
                 players.push(Player {
                     name: player_name,
@@ -157,9 +333,9 @@ pub async fn handle_heartbeat(
         }
     };
 
-    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
-
-    let server_info = ServerInfo {
+    // A new ID and token are only kept if this is the first heartbeat from this ip:port,
+    // otherwise the storage keeps the identity of the existing entry.
+    Ok(ServerInfo {
         id: uuid::Uuid::new_v4().to_string(),
         host_name: hostname,
         map_name,
@@ -167,107 +343,412 @@ pub async fn handle_heartbeat(
         players,
         max_players,
         port,
-        ip: normalized_ip.to_string(), // Store the normalized IP
+        ip: ip.to_string(), // Store the normalized IP
         last_heartbeat: now,
-    };
+        first_seen: now,
+        token: generate_token(),
+        flagged: false,
+        hide_players: heartbeat.get_hide_players(),
+        alt_ip,
+        reliability: 0,
+    })
+}
 
-    match storage.add_server(server_info) {
-        Ok(_) => Ok(HttpResponse::Ok().finish()),
-        Err(e) => {
-            error!("Failed to add server: {}", e);
-            Ok(HttpResponse::BadRequest().body(e))
+fn generate_token() -> String {
+    let token_bytes: [u8; 32] = rand::thread_rng().gen();
+    hex_encode(&token_bytes)
+}
+
+/// Clears the ID and token from a response about a server registered before this
+/// heartbeat. An identity handed out earlier only goes back to whoever presented its
+/// token, a wrong one having been turned away already.
+fn withhold_existing_identity(verification: &mut Verification, minted_id: &str, presented_token: Option<&str>) {
+    if verification.server.id != minted_id && presented_token.is_none() {
+        verification.server.id.clear();
+        verification.server.token.clear();
+    }
+}
+
+fn build_heartbeat_response(mut builder: HttpResponseBuilder, verification: &Verification) -> HttpResponse {
+    let mut message = capnp::message::Builder::new_default();
+    let mut response = message.init_root::<heartbeat_response::Builder>();
+    response.set_server_id(&verification.server.id);
+    response.set_token(&verification.server.token);
+    response.set_session_token(verification.session_token.as_deref().unwrap_or(""));
+    match &verification.status {
+        VerificationStatus::Verified => response.set_status(schema::VerificationStatus::Verified),
+        VerificationStatus::Pending => response.set_status(schema::VerificationStatus::Pending),
+        VerificationStatus::Rejected(reason) => {
+            response.set_status(schema::VerificationStatus::Rejected);
+            response.set_reject_reason(reason);
         }
     }
+
+    let mut response_data = Vec::new();
+    capnp::serialize::write_message(&mut response_data, &message)
+        .expect("Failed to serialize heartbeat response");
+
+    builder
+        .content_type("application/x-capnproto")
+        .body(response_data)
 }
 
-async fn verify_challenge(server_addr: &SocketAddr) -> bool {
-    let mut rng = rand::thread_rng();
-    let nonce_bytes: [u8; 4] = rng.gen();
-    let mut nonce_str = String::from("0x");
-    for byte in nonce_bytes {
-        write!(&mut nonce_str, "{:02X}", byte).unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+    use actix_web::http::StatusCode;
+    use actix_web::web::Bytes;
+    use governor::Quota;
+    use std::num::NonZeroU32;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+    use crate::config::Config;
+    use crate::handlers::test_support;
+    use crate::protocol::Packet;
+    use crate::storage::events::RemovalReason;
+    use crate::storage::memory::ServerStorage;
+
+    const PEER: &str = "127.0.0.1:50000";
+
+    /// The shared state `main` registers, with a short challenge timeout.
+    struct Harness {
+        storage: Arc<dyn ServerStore>,
+        rate_limiter: web::Data<HeartbeatLimiter>,
+        session_signer: web::Data<SessionSigner>,
+        challenge: web::Data<ChallengeDispatcher>,
+        verifier: Option<web::Data<DeferredVerifier>>,
     }
 
-    let mut challenge_packet: Vec<u8> = vec![0xff, 0xff, 0xff, 0xff, 0x48];
-    challenge_packet.extend_from_slice(b"connect");
-    challenge_packet.extend_from_slice(nonce_str.as_bytes());
-    challenge_packet.push(0x00);
+    struct Response {
+        status: StatusCode,
+        body: Bytes,
+    }
 
-    let socket = match UdpSocket::bind("0.0.0.0:0").await {
-        Ok(socket) => socket,
-        Err(e) => {
-            error!("Could not bind udp socket: {}", e);
-            return false;
+    impl Response {
+        /// Server ID, server token and session token of a heartbeat response.
+        fn identity(&self) -> (String, String, String) {
+            let reader = capnp::serialize::read_message(&mut self.body.as_ref(), ReaderOptions::new()).unwrap();
+            let response = reader.get_root::<heartbeat_response::Reader>().unwrap();
+            (
+                response.get_server_id().unwrap().to_string(),
+                response.get_token().unwrap().to_string(),
+                response.get_session_token().unwrap().to_string(),
+            )
         }
-    };
+    }
 
-    match socket.send_to(&challenge_packet, server_addr).await {
-        Ok(_) => debug!("Challenge sent to {} with nonce {}", server_addr, nonce_str),
-        Err(e) => {
-            error!("Error sending challenge to {}: {}", server_addr, e);
-            return false;
+    impl Harness {
+        async fn new(deferred: bool) -> Self {
+            let storage: Arc<dyn ServerStore> = Arc::new(ServerStorage::new(Config::default()));
+            let challenge = Arc::new(
+                ChallengeDispatcher::bind(&["127.0.0.1:0"], 16, Duration::from_millis(300)).await.unwrap()
+            );
+            let session_signer = Arc::new(SessionSigner::new(Some("test secret"), 300));
+            let verifier = deferred.then(|| {
+                web::Data::from(DeferredVerifier::spawn(
+                    storage.clone(),
+                    challenge.clone(),
+                    session_signer.clone(),
+                    16,
+                    Duration::from_secs(60)
+                ))
+            });
+            Self {
+                storage,
+                rate_limiter: web::Data::new(HeartbeatLimiter::new(Quota::per_second(NonZeroU32::new(1000).unwrap()))),
+                session_signer: web::Data::from(session_signer),
+                challenge: web::Data::from(challenge),
+                verifier,
+            }
         }
-    }
 
-    let mut buffer = [0u8; 1024];
-    match
-        tokio::time::timeout(std::time::Duration::from_secs(2), async {
-            socket.recv_from(&mut buffer).await
-        }).await
-    {
-        Ok(Ok((len, _addr))) => {
-            if
-                len < 21 ||
-                buffer[0] != 0xff ||
-                buffer[1] != 0xff ||
-                buffer[2] != 0xff ||
-                buffer[3] != 0xff ||
-                buffer[4] != 0x49
-            {
-                error!("Received invalid challenge response from {} with len {}", server_addr, len);
-                return false;
+        async fn send(&self, req: test::TestRequest, headers: &[(&str, &str)]) -> Response {
+            let mut app = test_support::app(&self.storage)
+                .app_data(self.rate_limiter.clone())
+                .app_data(self.session_signer.clone())
+                .app_data(self.challenge.clone());
+            if let Some(verifier) = &self.verifier {
+                app = app.app_data(verifier.clone());
             }
+            let app = test::init_service(
+                app
+                    .route("/server/heartbeat", web::post().to(handle_heartbeat))
+                    .route("/server/verification/{id}", web::get().to(verification_status))
+            ).await;
 
-            let response_nonce_str = String::from_utf8_lossy(&buffer[16..26]);
-            if !response_nonce_str.starts_with("0x") {
-                error!(
-                    "Received invalid nonce format from {}, received: {}",
-                    server_addr,
-                    response_nonce_str
-                );
-                return false;
+            let mut req = req.peer_addr(PEER.parse().unwrap());
+            for (name, value) in headers {
+                req = req.insert_header((*name, *value));
             }
-            if response_nonce_str != nonce_str {
-                error!(
-                    "Received invalid nonce from {}, sent: {}, received: {}",
-                    server_addr,
-                    nonce_str,
-                    response_nonce_str
-                );
-                return false;
+            let response = test::call_service(&app, req.to_request()).await;
+            Response { status: response.status(), body: test::read_body(response).await }
+        }
+
+        async fn heartbeat(&self, port: u16, map_name: &str, headers: &[(&str, &str)]) -> Response {
+            let req = test::TestRequest::post()
+                .uri("/server/heartbeat")
+                .set_payload(heartbeat_body(port, map_name));
+            self.send(req, headers).await
+        }
+
+        async fn poll(&self, id: &str, token: &str) -> Response {
+            let req = test::TestRequest::get().uri(&format!("/server/verification/{}", id));
+            self.send(req, &[(SERVER_TOKEN_HEADER, token)]).await
+        }
+
+        /// A session token for 127.0.0.1:`port`, as if it had just passed a challenge.
+        fn session(&self, port: u16) -> String {
+            self.session_signer.issue(&SocketAddr::new("127.0.0.1".parse().unwrap(), port))
+        }
+    }
+
+    fn heartbeat_body(port: u16, map_name: &str) -> Vec<u8> {
+        write_heartbeat(port, map_name, false)
+    }
+
+    fn write_heartbeat(port: u16, map_name: &str, packed: bool) -> Vec<u8> {
+        let mut message = capnp::message::Builder::new_default();
+        let mut heartbeat = message.init_root::<server_heartbeat::Builder>();
+        heartbeat.set_hostname("Test Server");
+        heartbeat.set_map_name(map_name);
+        heartbeat.set_game_mode("tdm");
+        heartbeat.set_max_players(12);
+        heartbeat.set_port(port as i32);
+        let mut body = Vec::new();
+        if packed {
+            capnp::serialize_packed::write_message(&mut body, &message).unwrap();
+        } else {
+            capnp::serialize::write_message(&mut body, &message).unwrap();
+        }
+        body
+    }
+
+    /// Answers connect challenges like a game server and returns its port.
+    async fn spawn_game_server() -> u16 {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 1500];
+            while let Ok((len, from)) = socket.recv_from(&mut buffer).await {
+                if let Ok(Packet::ConnectRequest { nonce }) = Packet::decode(&buffer[..len]) {
+                    let reply = Packet::ChallengeReply { challenge: 42, nonce }.encode();
+                    let _ = socket.send_to(&reply, from).await;
+                }
             }
-            if String::from_utf8_lossy(&buffer[9..16]) != "connect" {
-                error!(
-                    "Received invalid connect string from {}, received: {:?}",
-                    server_addr,
-                    &buffer[9..16]
-                );
-                return false;
+        });
+        port
+    }
+
+    /// A port nobody answers challenges on, for as long as the socket is kept.
+    async fn silent_server() -> (UdpSocket, u16) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+        (socket, port)
+    }
+
+    #[actix_web::test]
+    async fn accepts_new_server() {
+        let harness = Harness::new(false).await;
+        let session = harness.session(37015);
+
+        let response = harness.heartbeat(37015, "mp_lobby", &[(SESSION_TOKEN_HEADER, &session)]).await;
+        assert_eq!(response.status, StatusCode::OK);
+        let (id, token, _) = response.identity();
+        let stored = harness.storage.find_server("127.0.0.1", 37015).unwrap();
+        assert_eq!(stored.id, id);
+        assert_eq!(stored.token, token);
+        assert!(!token.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn updates_server_presenting_its_token() {
+        let harness = Harness::new(false).await;
+        let session = harness.session(37015);
+        let (id, token, _) = harness.heartbeat(37015, "mp_lobby", &[(SESSION_TOKEN_HEADER, &session)]).await.identity();
+
+        let response = harness
+            .heartbeat(37015, "mp_angel_city", &[(SESSION_TOKEN_HEADER, &session), (SERVER_TOKEN_HEADER, &token)])
+            .await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.identity().0, id);
+        assert_eq!(harness.storage.get_server(&id).unwrap().map_name, "mp_angel_city");
+    }
+
+    #[actix_web::test]
+    async fn refreshes_server_without_token() {
+        let harness = Harness::new(false).await;
+        let session = harness.session(37015);
+        let (id, token, _) = harness.heartbeat(37015, "mp_lobby", &[(SESSION_TOKEN_HEADER, &session)]).await.identity();
+        let before = harness.storage.get_server(&id).unwrap();
+
+        // Server builds that predate the token never send it, and don't learn it either
+        let response = harness.heartbeat(37015, "mp_angel_city", &[(SESSION_TOKEN_HEADER, &session)]).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.identity().0, "");
+        assert_eq!(response.identity().1, "");
+        assert!(!String::from_utf8_lossy(&response.body).contains(&token));
+        let after = harness.storage.get_server(&id).unwrap();
+        assert_eq!(after.map_name, "mp_angel_city");
+        assert!(after.last_heartbeat >= before.last_heartbeat);
+        assert_eq!(after.token, before.token);
+        assert_eq!(harness.storage.get_servers().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn rejects_update_with_wrong_token() {
+        let harness = Harness::new(false).await;
+        let session = harness.session(37015);
+        let (id, _, _) = harness.heartbeat(37015, "mp_lobby", &[(SESSION_TOKEN_HEADER, &session)]).await.identity();
+
+        let response = harness
+            .heartbeat(37015, "mp_angel_city", &[(SESSION_TOKEN_HEADER, &session), (SERVER_TOKEN_HEADER, "forged")])
+            .await;
+        assert_eq!(response.status, StatusCode::FORBIDDEN);
+        assert_eq!(harness.storage.get_server(&id).unwrap().map_name, "mp_lobby");
+    }
+
+    #[actix_web::test]
+    async fn reregisters_without_token_after_expiry() {
+        let harness = Harness::new(false).await;
+        let session = harness.session(37015);
+        let (id, token, _) = harness.heartbeat(37015, "mp_lobby", &[(SESSION_TOKEN_HEADER, &session)]).await.identity();
+        harness.storage.remove_server(&id, RemovalReason::Expired);
+
+        let response = harness.heartbeat(37015, "mp_lobby", &[(SESSION_TOKEN_HEADER, &session)]).await;
+        assert_eq!(response.status, StatusCode::OK);
+        let (new_id, new_token, _) = response.identity();
+        assert_ne!(new_id, id);
+        assert_ne!(new_token, token);
+    }
+
+    #[actix_web::test]
+    async fn deferred_heartbeat_is_verified_in_background() {
+        let harness = Harness::new(true).await;
+        let port = spawn_game_server().await;
+
+        let response = harness.heartbeat(port, "mp_lobby", &[]).await;
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        let (id, token, session) = response.identity();
+        assert!(session.is_empty());
+        assert_eq!(harness.poll(&id, "forged").await.status, StatusCode::FORBIDDEN);
+
+        let mut verified = None;
+        for _ in 0..50 {
+            let (_, _, session) = harness.poll(&id, &token).await.identity();
+            if !session.is_empty() {
+                verified = Some(session);
+                break;
             }
-            debug!(
-                "Received valid challenge response from {} with nonce: {}",
-                server_addr,
-                nonce_str
-            );
-            true
-        }
-        Ok(Err(e)) => {
-            error!("Failed to receive challenge response from {}: {}", server_addr, e);
-            false
+            tokio::time::sleep(Duration::from_millis(20)).await;
         }
-        Err(e) => {
-            error!("Timed out receiving challenge response from {}: {}", server_addr, e);
-            false
+        assert!(verified.is_some());
+        assert_eq!(harness.storage.find_server("127.0.0.1", port as i32).unwrap().id, id);
+    }
+
+    #[actix_web::test]
+    async fn deferred_heartbeat_keeps_existing_identity_private() {
+        let harness = Harness::new(true).await;
+        let (_socket, port) = silent_server().await;
+        let session = harness.session(port);
+        let (id, token, _) = harness.heartbeat(port, "mp_lobby", &[(SESSION_TOKEN_HEADER, &session)]).await.identity();
+
+        // Without the token nothing about the registered server is handed out
+        let response = harness.heartbeat(port, "mp_angel_city", &[]).await;
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(response.identity().0, "");
+        assert_eq!(response.identity().1, "");
+        assert!(!String::from_utf8_lossy(&response.body).contains(&token));
+        let response = harness.heartbeat(port, "mp_angel_city", &[(SERVER_TOKEN_HEADER, "forged")]).await;
+        assert_eq!(response.status, StatusCode::FORBIDDEN);
+
+        // Its holder gets its identity back while the challenge is pending
+        let response = harness.heartbeat(port, "mp_angel_city", &[(SERVER_TOKEN_HEADER, &token)]).await;
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(response.identity().0, id);
+        assert_eq!(response.identity().1, token);
+    }
+
+    #[actix_web::test]
+    async fn pending_verification_needs_its_token() {
+        let harness = Harness::new(true).await;
+        let (_socket, port) = silent_server().await;
+
+        let response = harness.heartbeat(port, "mp_lobby", &[]).await;
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        let (id, token, _) = response.identity();
+
+        // A resend without the token updates the payload but doesn't learn the identity
+        let response = harness.heartbeat(port, "mp_angel_city", &[]).await;
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(response.identity().0, "");
+        assert_eq!(response.identity().1, "");
+        assert!(!String::from_utf8_lossy(&response.body).contains(&token));
+        let response = harness.heartbeat(port, "mp_angel_city", &[(SERVER_TOKEN_HEADER, "forged")]).await;
+        assert_eq!(response.status, StatusCode::FORBIDDEN);
+
+        let response = harness.heartbeat(port, "mp_angel_city", &[(SERVER_TOKEN_HEADER, &token)]).await;
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert_eq!(response.identity().0, id);
+    }
+
+    #[actix_web::test]
+    async fn valid_session_skips_challenge() {
+        let harness = Harness::new(false).await;
+        let (_socket, port) = silent_server().await;
+        let session = harness.session(port);
+
+        // The silent server would fail a challenge, so this only passes without one
+        let response = harness.heartbeat(port, "mp_lobby", &[(SESSION_TOKEN_HEADER, &session)]).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.identity().2, session);
+    }
+
+    #[actix_web::test]
+    async fn invalid_session_is_challenged() {
+        let harness = Harness::new(false).await;
+        let (_socket, port) = silent_server().await;
+        let other_port = harness.session(port + 1);
+        let expired = SessionSigner::new(Some("test secret"), 0)
+            .issue(&SocketAddr::new("127.0.0.1".parse().unwrap(), port));
+        let forged = format!("{}.{}", u64::MAX, "00".repeat(32));
+
+        for session in [other_port.as_str(), expired.as_str(), forged.as_str(), "garbage"] {
+            let response = harness.heartbeat(port, "mp_lobby", &[(SESSION_TOKEN_HEADER, session)]).await;
+            assert_eq!(response.status, StatusCode::BAD_REQUEST, "{}", session);
+            assert_eq!(response.body, "Challenge response failed", "{}", session);
         }
+        assert!(harness.storage.find_server("127.0.0.1", port as i32).is_none());
+
+        // A server that answers the challenge gets a fresh session token instead
+        let port = spawn_game_server().await;
+        let response = harness.heartbeat(port, "mp_lobby", &[(SESSION_TOKEN_HEADER, "garbage")]).await;
+        assert_eq!(response.status, StatusCode::OK);
+        let session = response.identity().2;
+        assert!(harness.session_signer.verify(&session, &SocketAddr::new("127.0.0.1".parse().unwrap(), port)));
+    }
+
+    #[actix_web::test]
+    async fn accepts_packed_heartbeat() {
+        let harness = Harness::new(false).await;
+        let session = harness.session(37015);
+        let packed = |map_name: &str| test::TestRequest::post()
+            .uri("/server/heartbeat")
+            .set_payload(write_heartbeat(37015, map_name, true));
+
+        // Packed bodies are only read as such when their content type says so
+        let response = harness.send(packed("mp_lobby"), &[(SESSION_TOKEN_HEADER, &session)]).await;
+        assert_eq!(response.status, StatusCode::BAD_REQUEST);
+        assert!(harness.storage.find_server("127.0.0.1", 37015).is_none());
+
+        let headers = [(SESSION_TOKEN_HEADER, session.as_str()), ("Content-Type", PACKED_CONTENT_TYPE)];
+        let response = harness.send(packed("mp_lobby"), &headers).await;
+        assert_eq!(response.status, StatusCode::OK);
+        let stored = harness.storage.find_server("127.0.0.1", 37015).unwrap();
+        assert_eq!(stored.id, response.identity().0);
+        assert_eq!(stored.map_name, "mp_lobby");
+        assert_eq!(stored.host_name, "Test Server");
+        assert_eq!(stored.max_players, 12);
     }
 }
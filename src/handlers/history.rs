@@ -0,0 +1,93 @@
+// src/handlers/history.rs
+use actix_web::{ web, HttpRequest, HttpResponse };
+use actix_web::http::header;
+use capnp::message::Builder;
+use crate::rate_limit::ListLimiter;
+use log::error;
+use serde::Deserialize;
+use crate::handlers::list_query::ListFormat;
+use crate::handlers::servers::{ negotiate_format, write_capnp };
+use crate::models::server_view::{ HistoryPointView, HistoryView };
+use crate::schema::player_history;
+use crate::storage::history::{ PlayerHistory, Resolution, SeriesKey };
+use crate::storage::ServerStore;
+use crate::utils::{ extract_real_ip, rate_limit_key, RequestError };
+
+/// Which series to return. Without `server` or `map` it's the global one. A
+/// server's series follows its address, so it includes earlier registrations.
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    server: Option<String>,
+    map: Option<String>,
+    #[serde(default)]
+    resolution: Resolution,
+    /// Unix timestamps bounding the bucket start times, inclusive.
+    from: Option<u64>,
+    to: Option<u64>,
+    format: Option<ListFormat>,
+}
+
+/// Returns how the player count of a server, a map or all servers developed.
+pub async fn get_history(
+    history: web::Data<PlayerHistory>,
+    storage: web::Data<dyn ServerStore>,
+    rate_limiter: web::Data<ListLimiter>,
+    req: HttpRequest,
+    query: web::Query<HistoryQuery>,
+) -> Result<HttpResponse, RequestError> {
+    let peer_ip = extract_real_ip(&req)?;
+
+    // Rate Limiting
+    if rate_limiter.check_key(&rate_limit_key(peer_ip)).is_err() {
+        error!("Rate limit exceeded for history for ip: {}", peer_ip);
+        return Err(RequestError::RateLimitExceeded);
+    }
+
+    let key = match (&query.server, &query.map) {
+        (Some(_), Some(_)) => {
+            return Ok(HttpResponse::BadRequest().body("Only one of server and map can be given."));
+        }
+        (Some(id), None) => match storage.get_server(id) {
+            Some(server) => SeriesKey::server(&server),
+            None => return Ok(HttpResponse::NotFound().body("Server not found")),
+        },
+        (None, Some(map_name)) => SeriesKey::Map(map_name.clone()),
+        (None, None) => SeriesKey::Global,
+    };
+    let points = history.points(
+        &key,
+        query.resolution,
+        query.from.unwrap_or(0),
+        query.to.unwrap_or(u64::MAX)
+    );
+
+    let format = negotiate_format(&req, query.format);
+    let body = match format {
+        ListFormat::Capnp | ListFormat::CapnpPacked => {
+            let mut message = Builder::new_default();
+            let mut response = message.init_root::<player_history::Builder>();
+            response.set_resolution(query.resolution.as_str());
+            let mut point_list = response.init_points(points.len() as u32);
+            for (i, bucket) in points.iter().enumerate() {
+                let mut point = point_list.reborrow().get(i as u32);
+                point.set_start(bucket.start);
+                point.set_min(bucket.min);
+                point.set_max(bucket.max);
+                point.set_avg(bucket.avg());
+            }
+            write_capnp(&message, format == ListFormat::CapnpPacked)
+        }
+        ListFormat::Json => {
+            let view = HistoryView {
+                resolution: query.resolution.as_str(),
+                points: points.iter().map(HistoryPointView::from).collect(),
+            };
+            serde_json::to_vec(&view).expect("Failed to serialize history")
+        }
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((header::VARY, "Accept"))
+        .body(body))
+}
@@ -0,0 +1,57 @@
+// src/handlers/list_cache.rs
+use actix_web::web::Bytes;
+use dashmap::DashMap;
+use std::time::{ Duration, Instant };
+
+/// A serialized server list and the registry version it was built from.
+#[derive(Clone)]
+pub struct CachedList {
+    pub version: u64,
+    pub body: Bytes,
+    built_at: Instant,
+}
+
+/// Serialized server list and stats responses, keyed by format and query string.
+///
+/// An entry is reused while the registry version is unchanged, and for `debounce`
+/// after it was built even if the registry moved on, so a busy registry isn't
+/// re-serialized on every poll.
+pub struct ListCache {
+    entries: DashMap<String, CachedList>,
+    debounce: Duration,
+    max_entries: usize,
+}
+
+impl ListCache {
+    pub fn new(debounce: Duration, max_entries: usize) -> Self {
+        Self {
+            entries: DashMap::new(),
+            debounce,
+            max_entries,
+        }
+    }
+
+    /// Returns the cached response for `key` if it can still be served at `version`.
+    pub fn get(&self, key: &str, version: u64) -> Option<CachedList> {
+        let entry = self.entries.get(key)?;
+        if entry.version == version || entry.built_at.elapsed() < self.debounce {
+            Some(entry.clone())
+        } else {
+            None
+        }
+    }
+
+    pub fn insert(&self, key: &str, version: u64, body: Bytes) -> CachedList {
+        let cached = CachedList { version, body, built_at: Instant::now() };
+
+        if self.entries.len() >= self.max_entries && !self.entries.contains_key(key) {
+            // Make room by dropping responses built from older versions
+            self.entries.retain(|_, entry| entry.version == version);
+            if self.entries.len() >= self.max_entries {
+                return cached;
+            }
+        }
+        self.entries.insert(key.to_string(), cached.clone());
+        cached
+    }
+}
@@ -0,0 +1,349 @@
+// src/handlers/list_query.rs
+use serde::{ Deserialize, Serialize };
+use std::cmp::Ordering;
+use crate::models::server::ServerInfo;
+use crate::utils::{ hex_decode, hex_encode };
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    Players,
+    Hostname,
+    Map,
+    #[default]
+    FirstSeen,
+    Reliability,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(untagged)]
+enum SortValue {
+    Number(u64),
+    Text(String),
+}
+
+/// Representation of the server list response.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ListFormat {
+    Capnp,
+    /// Cap'n Proto with `serialize_packed`, which squeezes out the zero padding.
+    CapnpPacked,
+    Json,
+}
+
+impl ListFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Capnp => "capnp",
+            Self::CapnpPacked => "capnp_packed",
+            Self::Json => "json",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Capnp => "application/x-capnproto",
+            Self::CapnpPacked => "application/x-capnproto-packed",
+            Self::Json => "application/json",
+        }
+    }
+}
+
+/// Position of the last server on a page. Ties on the sort key are broken by ID,
+/// so the next page starts at the same place even if servers come and go.
+#[derive(Deserialize, Serialize)]
+struct Cursor {
+    sort: SortKey,
+    order: SortOrder,
+    value: SortValue,
+    id: String,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        hex_encode(&serde_json::to_vec(self).expect("Failed to serialize cursor"))
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        serde_json::from_slice(&hex_decode(cursor)?).ok()
+    }
+}
+
+/// Filters, sort order and page of the server list. Every filter that is set must match.
+#[derive(Deserialize)]
+pub struct ServerListQuery {
+    map_name: Option<String>,
+    game_mode: Option<String>,
+    /// Only servers with at least one free slot.
+    #[serde(default)]
+    not_full: bool,
+    /// Only servers with at least one player.
+    #[serde(default)]
+    not_empty: bool,
+    /// Bounds on the number of players currently on the server. Not to be
+    /// confused with the server's `max_players` slot count.
+    min_player_count: Option<usize>,
+    max_player_count: Option<usize>,
+    /// Case-insensitive substring of the hostname.
+    hostname: Option<String>,
+
+    #[serde(default)]
+    sort: SortKey,
+    #[serde(default)]
+    order: SortOrder,
+    /// Page size. Without it every matching server is returned.
+    limit: Option<usize>,
+    /// `nextCursor` of the previous page.
+    cursor: Option<String>,
+
+    /// Overrides the `Accept` header.
+    pub format: Option<ListFormat>,
+}
+
+/// One page of the filtered and sorted server list.
+pub struct Page {
+    pub servers: Vec<ServerInfo>,
+    /// Number of servers matching the filters, across all pages.
+    pub total_count: usize,
+    pub next_cursor: Option<String>,
+}
+
+impl ServerListQuery {
+    fn matches(&self, server: &ServerInfo) -> bool {
+        let player_count = server.players.len();
+
+        if let Some(map_name) = &self.map_name {
+            if server.map_name != *map_name {
+                return false;
+            }
+        }
+        if let Some(game_mode) = &self.game_mode {
+            if server.game_mode != *game_mode {
+                return false;
+            }
+        }
+        if self.not_full && server.is_full() {
+            return false;
+        }
+        if self.not_empty && server.is_empty() {
+            return false;
+        }
+        if self.min_player_count.is_some_and(|min| player_count < min) {
+            return false;
+        }
+        if self.max_player_count.is_some_and(|max| player_count > max) {
+            return false;
+        }
+        if let Some(hostname) = &self.hostname {
+            if !server.host_name.to_lowercase().contains(&hostname.to_lowercase()) {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn sort_value(&self, server: &ServerInfo) -> SortValue {
+        match self.sort {
+            SortKey::Players => SortValue::Number(server.players.len() as u64),
+            SortKey::Hostname => SortValue::Text(server.host_name.to_lowercase()),
+            SortKey::Map => SortValue::Text(server.map_name.clone()),
+            SortKey::FirstSeen => SortValue::Number(server.first_seen),
+            SortKey::Reliability => SortValue::Number(server.reliability as u64),
+        }
+    }
+
+    fn compare(&self, a: (&SortValue, &str), b: (&SortValue, &str)) -> Ordering {
+        let ordering = a.cmp(&b);
+        match self.order {
+            SortOrder::Asc => ordering,
+            SortOrder::Desc => ordering.reverse(),
+        }
+    }
+
+    /// Filters, sorts and pages `servers`.
+    pub fn apply(&self, servers: Vec<ServerInfo>) -> Result<Page, String> {
+        let cursor = match &self.cursor {
+            Some(cursor) => {
+                let cursor = Cursor::decode(cursor).ok_or("Invalid cursor")?;
+                if cursor.sort != self.sort || cursor.order != self.order {
+                    return Err("Cursor doesn't match the requested sort order".to_string());
+                }
+                Some(cursor)
+            }
+            None => None,
+        };
+
+        let mut servers: Vec<(SortValue, ServerInfo)> = servers
+            .into_iter()
+            .filter(|server| self.matches(server))
+            .map(|server| (self.sort_value(&server), server))
+            .collect();
+        servers.sort_by(|(a_value, a), (b_value, b)| self.compare((a_value, &a.id), (b_value, &b.id)));
+        let total_count = servers.len();
+
+        let start = match &cursor {
+            Some(cursor) => servers.partition_point(|(value, server)| {
+                self.compare((value, &server.id), (&cursor.value, &cursor.id)) != Ordering::Greater
+            }),
+            None => 0,
+        };
+        let end = match self.limit {
+            Some(limit) => total_count.min(start.saturating_add(limit.max(1))),
+            None => total_count,
+        };
+
+        let next_cursor = if end < total_count && end > start {
+            let (value, last) = &servers[end - 1];
+            Some(Cursor { sort: self.sort, order: self.order, value: value.clone(), id: last.id.clone() }.encode())
+        } else {
+            None
+        };
+
+        Ok(Page {
+            servers: servers.drain(start..end).map(|(_, server)| server).collect(),
+            total_count,
+            next_cursor,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{ test as actix_test, web };
+    use actix_web::http::StatusCode;
+    use std::sync::Arc;
+    use crate::config::Config;
+    use crate::handlers::servers::get_servers;
+    use crate::handlers::test_support;
+    use crate::storage::ServerStore;
+    use crate::storage::memory::ServerStorage;
+
+    fn query(query: &str) -> ServerListQuery {
+        web::Query::<ServerListQuery>::from_query(query).unwrap().into_inner()
+    }
+
+    // Player counts with ties, so the order within a count comes down to the ID
+    fn servers() -> Vec<ServerInfo> {
+        [2, 0, 2, 1, 2, 0].iter().enumerate().map(|(i, count)| ServerInfo::with_players(i, &vec!["Pilot"; *count])).collect()
+    }
+
+    fn ids(servers: &[ServerInfo]) -> Vec<&str> {
+        servers.iter().map(|server| server.id.as_str()).collect()
+    }
+
+    /// Follows `nextCursor` from the first page to the last, returning the IDs in order.
+    fn walk(params: &str) -> Vec<String> {
+        let mut seen = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let params = match &cursor {
+                Some(cursor) => format!("{}&cursor={}", params, cursor),
+                None => params.to_string(),
+            };
+            let page = query(&params).apply(servers()).unwrap();
+            assert_eq!(page.total_count, 6);
+            seen.extend(page.servers.iter().map(|server| server.id.clone()));
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => return seen,
+            }
+        }
+    }
+
+    #[test]
+    fn filters_on_every_field() {
+        let mut servers = servers();
+        servers[0].map_name = "mp_angel_city".to_string();
+        servers[1].game_mode = "ctf".to_string();
+        servers[2].host_name = "EU Titanfall Night".to_string();
+        servers[4].max_players = 2;
+
+        let matching = |params: &str| {
+            let page = query(params).apply(servers.clone()).unwrap();
+            assert_eq!(page.total_count, page.servers.len());
+            page.servers.into_iter().map(|server| server.id).collect::<Vec<_>>()
+        };
+        assert_eq!(matching("map_name=mp_angel_city"), ["server-0"]);
+        assert_eq!(matching("game_mode=ctf"), ["server-1"]);
+        assert_eq!(matching("hostname=titanfall"), ["server-2"]);
+        assert_eq!(matching("not_empty=true"), ["server-0", "server-2", "server-3", "server-4"]);
+        assert_eq!(matching("not_full=true"), ["server-0", "server-1", "server-2", "server-3", "server-5"]);
+        assert_eq!(matching("min_player_count=2"), ["server-0", "server-2", "server-4"]);
+        assert_eq!(matching("max_player_count=1"), ["server-1", "server-3", "server-5"]);
+        assert_eq!(matching("min_player_count=1&max_player_count=1"), ["server-3"]);
+        // Filters combine
+        assert_eq!(matching("not_full=true&min_player_count=2&hostname=server"), ["server-0"]);
+        assert!(matching("map_name=mp_angel_city&game_mode=ctf").is_empty());
+    }
+
+    #[test]
+    fn pages_in_ascending_order_with_ties_broken_by_id() {
+        let expected = ["server-1", "server-5", "server-3", "server-0", "server-2", "server-4"];
+        assert_eq!(walk("sort=players&limit=2"), expected);
+        assert_eq!(walk("sort=players&order=asc&limit=4"), expected);
+        assert_eq!(ids(&query("sort=players").apply(servers()).unwrap().servers), expected);
+    }
+
+    #[test]
+    fn pages_in_descending_order_with_ties_broken_by_id() {
+        let expected = ["server-4", "server-2", "server-0", "server-3", "server-5", "server-1"];
+        assert_eq!(walk("sort=players&order=desc&limit=2"), expected);
+        assert_eq!(walk("sort=players&order=desc&limit=5"), expected);
+    }
+
+    #[test]
+    fn cursor_outlives_its_server() {
+        let first = query("sort=players&limit=2").apply(servers()).unwrap();
+        assert_eq!(ids(&first.servers), ["server-1", "server-5"]);
+
+        // The last server of the first page goes away before the next one is fetched
+        let remaining: Vec<ServerInfo> = servers().into_iter().filter(|server| server.id != "server-5").collect();
+        let params = format!("sort=players&limit=2&cursor={}", first.next_cursor.unwrap());
+        let second = query(&params).apply(remaining).unwrap();
+        assert_eq!(ids(&second.servers), ["server-3", "server-0"]);
+        assert_eq!(second.total_count, 5);
+    }
+
+    #[test]
+    fn rejects_cursor_from_other_sort() {
+        let cursor = query("sort=players&limit=2").apply(servers()).unwrap().next_cursor.unwrap();
+        assert!(query(&format!("sort=hostname&limit=2&cursor={}", cursor)).apply(servers()).is_err());
+        assert!(query(&format!("sort=players&order=desc&limit=2&cursor={}", cursor)).apply(servers()).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_cursor() {
+        for cursor in ["zz", "abc", "7b7d"] {
+            assert!(query(&format!("limit=2&cursor={}", cursor)).apply(servers()).is_err(), "{}", cursor);
+        }
+    }
+
+    #[actix_web::test]
+    async fn invalid_cursor_is_a_bad_request() {
+        let config = Config { max_servers_per_ip: 8, ..Config::default() };
+        let storage: Arc<dyn ServerStore> = Arc::new(ServerStorage::new(config));
+        for server in servers() {
+            storage.add_server(server).unwrap();
+        }
+        let app = actix_test::init_service(
+            test_support::app(&storage)
+                .route("/servers", web::get().to(get_servers))
+        ).await;
+
+        let get = |uri: &str| actix_test::TestRequest::get().uri(uri).peer_addr("127.0.0.1:50000".parse().unwrap()).to_request();
+        let response = actix_test::call_service(&app, get("/servers?format=json&limit=2&cursor=zz")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = actix_test::call_service(&app, get("/servers?format=json&limit=2")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -1,3 +1,11 @@
 pub mod heartbeat;
 pub mod auth;
 pub mod servers;
+pub mod list_query;
+pub mod list_cache;
+pub mod events;
+pub mod players;
+pub mod stats;
+pub mod history;
+#[cfg(test)]
+mod test_support;
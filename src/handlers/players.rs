@@ -0,0 +1,78 @@
+// src/handlers/players.rs
+use actix_web::{ web, HttpRequest, HttpResponse };
+use actix_web::http::header;
+use capnp::message::Builder;
+use crate::rate_limit::ListLimiter;
+use log::error;
+use serde::Deserialize;
+use crate::handlers::list_query::ListFormat;
+use crate::handlers::servers::{ negotiate_format, write_capnp, write_player, write_server };
+use crate::models::server_view::{ PlayerMatchView, PlayerSearchView };
+use crate::schema::player_search_result;
+use crate::storage::ServerStore;
+use crate::utils::{ extract_real_ip, rate_limit_key, RequestError };
+
+// Enough to find a friend, too few to page through everyone who is online
+const MAX_RESULTS: usize = 50;
+const MIN_NAME_LEN: usize = 2;
+const MAX_NAME_LEN: usize = 32;
+
+#[derive(Deserialize)]
+pub struct PlayerSearchQuery {
+    /// Prefix or substring of the player name, case-insensitive.
+    name: String,
+    limit: Option<usize>,
+    format: Option<ListFormat>,
+}
+
+/// Finds which servers a player is on.
+pub async fn search_players(
+    storage: web::Data<dyn ServerStore>,
+    rate_limiter: web::Data<ListLimiter>,
+    req: HttpRequest,
+    query: web::Query<PlayerSearchQuery>,
+) -> Result<HttpResponse, RequestError> {
+    let peer_ip = extract_real_ip(&req)?;
+
+    // Rate Limiting
+    if rate_limiter.check_key(&rate_limit_key(peer_ip)).is_err() {
+        error!("Rate limit exceeded for player search for ip: {}", peer_ip);
+        return Err(RequestError::RateLimitExceeded);
+    }
+
+    let name = query.name.trim();
+    let name_len = name.chars().count();
+    if !(MIN_NAME_LEN..=MAX_NAME_LEN).contains(&name_len) {
+        return Ok(HttpResponse::BadRequest()
+            .body(format!("Invalid name: must be {} to {} chars.", MIN_NAME_LEN, MAX_NAME_LEN)));
+    }
+    let limit = query.limit.unwrap_or(MAX_RESULTS).clamp(1, MAX_RESULTS);
+
+    let matches = storage.search_players(name, limit);
+
+    let format = negotiate_format(&req, query.format);
+    let body = match format {
+        ListFormat::Capnp | ListFormat::CapnpPacked => {
+            let mut message = Builder::new_default();
+            let response = message.init_root::<player_search_result::Builder>();
+            let mut match_list = response.init_matches(matches.len() as u32);
+            for (i, found) in matches.iter().enumerate() {
+                let mut match_data = match_list.reborrow().get(i as u32);
+                write_player(match_data.reborrow().init_player(), &found.player);
+                write_server(match_data.init_server(), &found.server);
+            }
+            write_capnp(&message, format == ListFormat::CapnpPacked)
+        }
+        ListFormat::Json => {
+            let view = PlayerSearchView {
+                matches: matches.iter().map(PlayerMatchView::from).collect(),
+            };
+            serde_json::to_vec(&view).expect("Failed to serialize player search")
+        }
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((header::VARY, "Accept"))
+        .body(body))
+}
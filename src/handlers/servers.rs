@@ -1,65 +1,341 @@
 // src/handlers/servers.rs
-use actix_web::{web, HttpResponse, HttpRequest, Responder, error::ErrorTooManyRequests};
+use actix_web::{web, HttpResponse, HttpRequest};
+use actix_web::web::Bytes;
+use actix_web::http::header::{ self, Accept, EntityTag, ETag, Header, IfNoneMatch };
 use capnp::message::Builder;
 use log::{debug, error};
-use crate::storage::memory::ServerStorage;
-use crate::schema::server_list;
-use governor::{RateLimiter, clock::DefaultClock};
-use std::net::IpAddr;
-use governor::state::keyed::DefaultKeyedStateStore;
+use crate::storage::ServerStore;
+use crate::storage::events::RemovalReason;
+use crate::schema::{ player, server_changes, server_detail, server_heartbeat, server_list };
+use crate::rate_limit::{ DeleteLimiter, ListLimiter };
 use serde::Deserialize;
-use crate::utils::{extract_real_ip, RequestError};
+use std::sync::OnceLock;
+use rand::Rng;
+use crate::utils::{ extract_real_ip, hex_encode, rate_limit_key, RequestError };
+use sha2::{ Digest, Sha256 };
+use crate::handlers::list_query::{ ListFormat, Page, ServerListQuery };
+use crate::models::server::{ Player, ServerInfo };
+use crate::models::server_view::{ ServerChangesView, ServerDetailView, ServerListView, ServerView };
+use crate::storage::changes::Change;
+use crate::storage::events::ServerEvent;
+use std::collections::HashSet;
+use crate::handlers::list_cache::ListCache;
 
+/// ETag for the current registry version in one representation of one list
+/// query. Versions restart with the process, so the tag also carries an ID picked
+/// at startup.
+fn registry_etag(version: u64, format: ListFormat, query: &str) -> EntityTag {
+    static INSTANCE_ID: OnceLock<u32> = OnceLock::new();
+    let instance_id = INSTANCE_ID.get_or_init(|| rand::thread_rng().gen());
+    let query_hash = Sha256::digest(query.as_bytes());
+    EntityTag::new_strong(format!("{:08x}-{}-{}-{}", instance_id, version, format.as_str(), hex_encode(&query_hash[..8])))
+}
+
+/// The filters, sort and page of a list query in a fixed order, so the same query
+/// written differently shares a tag and a cache entry. `format` is left out, the
+/// negotiated representation is keyed separately.
+fn normalize_list_query(query: &str) -> String {
+    let mut pairs: Vec<&str> = query
+        .split('&')
+        .filter(|pair| !pair.is_empty() && *pair != "format" && !pair.starts_with("format="))
+        .collect();
+    pairs.sort_unstable();
+    pairs.join("&")
+}
+
+/// Picks the list representation from `?format=`, then from the `Accept` header.
+/// Cap'n Proto stays the default for clients that don't ask.
+pub fn negotiate_format(req: &HttpRequest, requested: Option<ListFormat>) -> ListFormat {
+    if let Some(format) = requested {
+        return format;
+    }
+    let Ok(accept) = Accept::parse(req) else {
+        return ListFormat::Capnp;
+    };
+    for mime in accept.ranked() {
+        match mime.essence_str() {
+            "application/json" => return ListFormat::Json,
+            "application/x-capnproto-packed" => return ListFormat::CapnpPacked,
+            "application/x-capnproto" | "*/*" => return ListFormat::Capnp,
+            _ => {}
+        }
+    }
+    ListFormat::Capnp
+}
+
+fn etag_matches(req: &HttpRequest, etag: &EntityTag) -> bool {
+    match IfNoneMatch::parse(req) {
+        Ok(IfNoneMatch::Any) => true,
+        Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        Err(_) => false,
+    }
+}
 
 pub async fn get_servers(
-    storage: web::Data<ServerStorage>,
-    rate_limiter: web::Data<RateLimiter<IpAddr, DefaultKeyedStateStore<IpAddr>, DefaultClock>>,
+    storage: web::Data<dyn ServerStore>,
+    rate_limiter: web::Data<ListLimiter>,
     req: HttpRequest,
+    query: web::Query<ServerListQuery>,
+    list_cache: web::Data<ListCache>,
 ) -> Result<HttpResponse, RequestError> {
     // Use the new extract real IP function
     let peer_ip = extract_real_ip(&req)?;
 
-    // Rate Limiting
-    if !rate_limiter.check_key(&peer_ip).is_ok() {
+    // Rate Limiting, conditional requests included so they can't be looped for free
+    if rate_limiter.check_key(&rate_limit_key(peer_ip)).is_err() {
        error!("Rate limit exceeded for server list for ip: {}", peer_ip);
         return Err(RequestError::RateLimitExceeded);
     }
 
-    storage.cleanup_stale_servers();
-    let servers = storage.get_servers();
+    // Read the version before the servers so a change in between only makes the tag stale
+    let format = negotiate_format(&req, query.format);
+    let list_query = normalize_list_query(req.query_string());
+    let cache_key = format!("{}?{}", format.as_str(), list_query);
+    let version = storage.version();
+    let cached = list_cache.get(&cache_key, version);
+    let etag = registry_etag(cached.as_ref().map_or(version, |cached| cached.version), format, &list_query);
 
-    debug!("Building server list response with {} servers", servers.len());
+    if etag_matches(&req, &etag) {
+        return Ok(HttpResponse::NotModified()
+            .insert_header(ETag(etag))
+            .insert_header((header::VARY, "Accept"))
+            .finish());
+    }
 
-    let mut message = Builder::new_default();
-    let server_list = message.init_root::<server_list::Builder>();
-    let mut server_list_data = server_list.init_servers(servers.len() as u32);
-
-    for (i, server) in servers.iter().enumerate() {
-        let mut server_data = server_list_data.reborrow().get(i as u32);
-        server_data.set_hostname(&server.host_name);
-        server_data.set_map_name(&server.map_name);
-        server_data.set_game_mode(&server.game_mode);
-        server_data.set_max_players(server.max_players);
-        server_data.set_port(server.port);
-        server_data.set_ip(&server.ip);
-
-        let mut player_list = server_data.init_players(server.players.len() as u32);
-        for (j, player) in server.players.iter().enumerate() {
-            let mut player_data = player_list.reborrow().get(j as u32);
-            player_data.set_name(&player.name);
-            player_data.set_gen(player.gen);
-            player_data.set_lvl(player.lvl);
-            player_data.set_team(player.team);
+    let body = match cached {
+        Some(cached) => cached.body,
+        None => {
+            let page = match query.apply(storage.get_servers()) {
+                Ok(page) => page,
+                Err(e) => return Ok(HttpResponse::BadRequest().body(e)),
+            };
+            debug!("Building {} server list response with {} servers", format.as_str(), page.servers.len());
+            let body = match format {
+                ListFormat::Capnp => serialize_server_list(&page, version, false),
+                ListFormat::CapnpPacked => serialize_server_list(&page, version, true),
+                ListFormat::Json => serialize_server_list_json(&page, version),
+            };
+            list_cache.insert(&cache_key, version, Bytes::from(body)).body
         }
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header(ETag(etag))
+        .insert_header((header::VARY, "Accept"))
+        .body(body))
+}
+
+/// Serializes a page of the server list as a Cap'n Proto `ServerList` message.
+pub fn serialize_server_list(page: &Page, version: u64, packed: bool) -> Vec<u8> {
+    let mut message = Builder::new_default();
+    let mut server_list = message.init_root::<server_list::Builder>();
+    server_list.set_total_count(page.total_count as u32);
+    server_list.set_version(version);
+    if let Some(cursor) = &page.next_cursor {
+        server_list.set_next_cursor(cursor);
+    }
+    let mut server_list_data = server_list.init_servers(page.servers.len() as u32);
+
+    for (i, server) in page.servers.iter().enumerate() {
+        write_server(server_list_data.reborrow().get(i as u32), server);
+    }
+
+    write_capnp(&message, packed)
+}
+
+pub fn write_server(mut server_data: server_heartbeat::Builder, server: &ServerInfo) {
+    server_data.set_hostname(&server.host_name);
+    server_data.set_map_name(&server.map_name);
+    server_data.set_game_mode(&server.game_mode);
+    server_data.set_max_players(server.max_players);
+    server_data.set_port(server.port);
+    server_data.set_ip(&server.ip);
+    server_data.set_id(&server.id);
+    server_data.set_flagged(server.flagged);
+    server_data.set_hide_players(server.hide_players);
+    server_data.set_reliability(server.reliability);
+    server_data.set_alt_ip(&server.alt_ip);
+    server_data.set_player_count(server.players.len() as u32);
+
+    let players = server.visible_players();
+    let mut player_list = server_data.init_players(players.len() as u32);
+    for (j, player) in players.iter().enumerate() {
+        write_player(player_list.reborrow().get(j as u32), player);
     }
+}
+
+pub fn write_player(mut player_data: player::Builder, player: &Player) {
+    player_data.set_name(&player.name);
+    player_data.set_gen(player.gen);
+    player_data.set_lvl(player.lvl);
+    player_data.set_team(player.team);
+}
 
+pub fn write_capnp(message: &Builder<capnp::message::HeapAllocator>, packed: bool) -> Vec<u8> {
     let mut response_data = Vec::new();
-    capnp::serialize::write_message(&mut response_data, &message)
-        .expect("Failed to serialize server list");
+    if packed {
+        capnp::serialize_packed::write_message(&mut response_data, message)
+    } else {
+        capnp::serialize::write_message(&mut response_data, message)
+    }
+    .expect("Failed to serialize response");
+    response_data
+}
+
+/// Serializes a page of the server list in its public JSON shape.
+pub fn serialize_server_list_json(page: &Page, version: u64) -> Vec<u8> {
+    let view = ServerListView {
+        servers: page.servers.iter().map(ServerView::from).collect(),
+        total_count: page.total_count,
+        next_cursor: page.next_cursor.as_deref(),
+        version,
+    };
+    serde_json::to_vec(&view).expect("Failed to serialize server list")
+}
+
+#[derive(Deserialize)]
+pub struct ChangesQuery {
+    since: u64,
+    format: Option<ListFormat>,
+}
+
+/// Collapses a change log into the latest state of each server that changed,
+/// in the order of their last change.
+fn collapse_changes(changes: &[Change]) -> (Vec<&ServerInfo>, Vec<&str>) {
+    let mut seen = HashSet::new();
+    let mut updated = Vec::new();
+    let mut removed = Vec::new();
+
+    for change in changes.iter().rev() {
+        match &change.event {
+            ServerEvent::Added { server } | ServerEvent::Updated { server } => {
+                if seen.insert(server.id.as_str()) {
+                    updated.push(server);
+                }
+            }
+            ServerEvent::Removed { server, .. } => {
+                if seen.insert(server.id.as_str()) {
+                    removed.push(server.id.as_str());
+                }
+            }
+        }
+    }
+
+    updated.reverse();
+    removed.reverse();
+    (updated, removed)
+}
+
+/// Returns the servers added, updated or removed since `?since=<version>`.
+pub async fn get_changes(
+    storage: web::Data<dyn ServerStore>,
+    rate_limiter: web::Data<ListLimiter>,
+    req: HttpRequest,
+    query: web::Query<ChangesQuery>,
+) -> Result<HttpResponse, RequestError> {
+    let peer_ip = extract_real_ip(&req)?;
+
+    // Rate Limiting
+    if rate_limiter.check_key(&rate_limit_key(peer_ip)).is_err() {
+        error!("Rate limit exceeded for server changes for ip: {}", peer_ip);
+        return Err(RequestError::RateLimitExceeded);
+    }
+
+    let changes = storage.changes_since(query.since);
+    let (version, resync) = match &changes {
+        Some(changes) => (changes.last().map_or(query.since, |change| change.version), false),
+        None => {
+            debug!("Changes since {} are no longer available, asking {} to resync", query.since, peer_ip);
+            (storage.version(), true)
+        }
+    };
+    let (updated, removed) = changes.as_deref().map(collapse_changes).unwrap_or_default();
+
+    let format = negotiate_format(&req, query.format);
+    let body = match format {
+        ListFormat::Capnp | ListFormat::CapnpPacked => {
+            let mut message = Builder::new_default();
+            let mut response = message.init_root::<server_changes::Builder>();
+            response.set_version(version);
+            response.set_resync(resync);
+            {
+                let mut updated_data = response.reborrow().init_updated(updated.len() as u32);
+                for (i, server) in updated.iter().enumerate() {
+                    write_server(updated_data.reborrow().get(i as u32), server);
+                }
+            }
+            let mut removed_data = response.init_removed(removed.len() as u32);
+            for (i, id) in removed.iter().enumerate() {
+                removed_data.set(i as u32, id);
+            }
+            write_capnp(&message, format == ListFormat::CapnpPacked)
+        }
+        ListFormat::Json => {
+            let view = ServerChangesView {
+                version,
+                resync,
+                updated: updated.into_iter().map(ServerView::from).collect(),
+                removed,
+            };
+            serde_json::to_vec(&view).expect("Failed to serialize server changes")
+        }
+    };
 
     Ok(HttpResponse::Ok()
-        .content_type("application/x-capnproto")
-        .body(response_data))
+        .content_type(format.content_type())
+        .insert_header((header::VARY, "Accept"))
+        .body(body))
+}
+
+#[derive(Deserialize)]
+pub struct ServerDetailQuery {
+    format: Option<ListFormat>,
+}
+
+/// Returns one server's full record, or 404 once it has expired or been delisted.
+pub async fn get_server(
+    storage: web::Data<dyn ServerStore>,
+    rate_limiter: web::Data<ListLimiter>,
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<ServerDetailQuery>,
+) -> Result<HttpResponse, RequestError> {
+    let peer_ip = extract_real_ip(&req)?;
+
+    // Rate Limiting
+    if rate_limiter.check_key(&rate_limit_key(peer_ip)).is_err() {
+        error!("Rate limit exceeded for server detail for ip: {}", peer_ip);
+        return Err(RequestError::RateLimitExceeded);
+    }
+
+    let Some(server) = storage.get_server(&path.into_inner()) else {
+        return Ok(HttpResponse::NotFound().body("Server not found"));
+    };
+    // Reliability history starts over with the process, entries loaded from disk may have none yet
+    let reliability = storage.reliability(&server.ip, server.port).unwrap_or_default();
+
+    let format = negotiate_format(&req, query.format);
+    let body = match format {
+        ListFormat::Capnp | ListFormat::CapnpPacked => {
+            let mut message = Builder::new_default();
+            let mut detail = message.init_root::<server_detail::Builder>();
+            detail.set_first_seen(server.first_seen);
+            detail.set_last_heartbeat(server.last_heartbeat);
+            detail.set_uptime(reliability.uptime);
+            detail.set_missed_heartbeats(reliability.missed_heartbeats);
+            detail.set_failed_challenges(reliability.failed_challenges);
+            write_server(detail.init_server(), &server);
+            write_capnp(&message, format == ListFormat::CapnpPacked)
+        }
+        ListFormat::Json => serde_json::to_vec(&ServerDetailView::new(&server, &reliability))
+            .expect("Failed to serialize server detail"),
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((header::VARY, "Accept"))
+        .body(body))
 }
 
 #[derive(Deserialize)]
@@ -68,27 +344,26 @@ pub struct DeleteServerQuery {
 }
 
 pub async fn delete_server(
-    storage: web::Data<ServerStorage>,
+    storage: web::Data<dyn ServerStore>,
     req: HttpRequest,
     query: web::Query<DeleteServerQuery>,
-    rate_limiter: web::Data<RateLimiter<IpAddr, DefaultKeyedStateStore<IpAddr>, DefaultClock>>,
+    rate_limiter: web::Data<DeleteLimiter>,
 ) -> Result<HttpResponse, RequestError> {
     let peer_ip = extract_real_ip(&req)?;
 
     // Rate Limiting
-    if !rate_limiter.check_key(&peer_ip).is_ok() {
+    if rate_limiter.check_key(&rate_limit_key(peer_ip)).is_err() {
         error!("Rate limit exceeded for server delete for ip: {}", peer_ip);
         return Err(RequestError::RateLimitExceeded);
     }
 
-    let servers = storage.get_servers();
-    let server_id = servers.iter()
-        .find(|server| server.ip == peer_ip.to_string() && server.port == query.port)
-        .map(|server| server.id.clone());
+    let server_id = storage
+        .find_server(&peer_ip.to_string(), query.port)
+        .map(|server| server.id);
 
     match server_id {
         Some(id) => {
-            storage.remove_server(&id);
+            storage.remove_server(&id, RemovalReason::Deleted);
             debug!("Removed server {}:{}", peer_ip, query.port);
             Ok(HttpResponse::Ok().finish())
         }
@@ -98,3 +373,319 @@ pub async fn delete_server(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test as actix_test;
+    use actix_web::http::StatusCode;
+    use governor::Quota;
+    use std::num::NonZeroU32;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::config::Config;
+    use crate::challenge::ChallengeDispatcher;
+    use crate::handlers::heartbeat::{ handle_heartbeat, verification_status };
+    use crate::handlers::test_support;
+    use crate::rate_limit::HeartbeatLimiter;
+    use crate::session::SessionSigner;
+    use crate::storage::memory::ServerStorage;
+
+    struct Response {
+        status: StatusCode,
+        content_type: String,
+        body: Bytes,
+    }
+
+    /// Sends `req` to the read-only server routes over `storage`.
+    async fn read(storage: &Arc<dyn ServerStore>, req: actix_test::TestRequest) -> Response {
+        let app = actix_test::init_service(
+            test_support::app(storage)
+                .route("/server/", web::get().to(get_servers))
+                .route("/server/{id}", web::get().to(get_server))
+        ).await;
+        let response = actix_test::call_service(&app, req.peer_addr("127.0.0.1:50000".parse().unwrap()).to_request()).await;
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .map(|v| v.to_str().unwrap().to_string())
+            .unwrap_or_default();
+        Response { status: response.status(), content_type, body: actix_test::read_body(response).await }
+    }
+
+    fn change(version: u64, event: ServerEvent) -> Change {
+        Change { version, event }
+    }
+
+    #[test]
+    fn collapses_changes_to_latest_state() {
+        let renamed = ServerInfo { host_name: "Renamed".to_string(), ..ServerInfo::for_test(1) };
+        let changes = [
+            change(1, ServerEvent::Added { server: ServerInfo::for_test(0) }),
+            change(2, ServerEvent::Added { server: ServerInfo::for_test(1) }),
+            change(3, ServerEvent::Updated { server: renamed }),
+            change(4, ServerEvent::Updated { server: ServerInfo::for_test(0) }),
+        ];
+        let (updated, removed) = collapse_changes(&changes);
+        assert_eq!(updated.iter().map(|server| server.id.as_str()).collect::<Vec<_>>(), ["server-1", "server-0"]);
+        assert_eq!(updated[0].host_name, "Renamed");
+        assert!(removed.is_empty());
+    }
+
+    #[test]
+    fn server_updated_then_removed_is_only_removed() {
+        let changes = [
+            change(1, ServerEvent::Updated { server: ServerInfo::for_test(0) }),
+            change(2, ServerEvent::Removed { server: ServerInfo::for_test(0), reason: RemovalReason::Expired }),
+        ];
+        let (updated, removed) = collapse_changes(&changes);
+        assert!(updated.is_empty());
+        assert_eq!(removed, ["server-0"]);
+
+        // and one that comes back after its removal is only updated
+        let changes = [
+            change(1, ServerEvent::Removed { server: ServerInfo::for_test(0), reason: RemovalReason::Expired }),
+            change(2, ServerEvent::Added { server: ServerInfo::for_test(0) }),
+        ];
+        let (updated, removed) = collapse_changes(&changes);
+        assert_eq!(updated.len(), 1);
+        assert!(removed.is_empty());
+    }
+
+    // `burst` requests per client, then nothing for an hour
+    fn quota(burst: u32) -> Quota {
+        Quota::with_period(Duration::from_secs(3600)).unwrap().allow_burst(NonZeroU32::new(burst).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn limiters_keep_separate_buckets() {
+        let storage: Arc<dyn ServerStore> = Arc::new(ServerStorage::new(Config::default()));
+        let challenge = ChallengeDispatcher::bind(&["127.0.0.1:0"], 16, Duration::from_millis(300)).await.unwrap();
+        let app = actix_test::init_service(
+            test_support::app(&storage)
+                .app_data(web::Data::new(HeartbeatLimiter::new(quota(2))))
+                .app_data(web::Data::new(ListLimiter::new(quota(2))))
+                .app_data(web::Data::new(DeleteLimiter::new(quota(1))))
+                .app_data(web::Data::new(SessionSigner::new(Some("test secret"), 300)))
+                .app_data(web::Data::new(challenge))
+                .route("/server/", web::get().to(get_servers))
+                .route("/server/heartbeat", web::post().to(handle_heartbeat))
+                .route("/server/delete", web::post().to(delete_server))
+                .route("/server/verification/{id}", web::get().to(verification_status))
+        ).await;
+        let call = |req: actix_test::TestRequest| {
+            let req = req.peer_addr("127.0.0.1:50000".parse().unwrap()).to_request();
+            let app = &app;
+            async move { actix_test::call_service(app, req).await.status() }
+        };
+
+        for _ in 0..2 {
+            assert_eq!(call(actix_test::TestRequest::get().uri("/server/?format=json")).await, StatusCode::OK);
+        }
+        assert_eq!(call(actix_test::TestRequest::get().uri("/server/?format=json")).await, StatusCode::TOO_MANY_REQUESTS);
+
+        // The exhausted list quota leaves the others alone. An empty heartbeat gets
+        // past the limiter and is turned away for its body.
+        assert_eq!(call(actix_test::TestRequest::post().uri("/server/delete?port=37015")).await, StatusCode::NOT_FOUND);
+        assert_eq!(call(actix_test::TestRequest::post().uri("/server/heartbeat")).await, StatusCode::BAD_REQUEST);
+        assert_eq!(call(actix_test::TestRequest::get().uri("/server/verification/missing")).await, StatusCode::NOT_FOUND);
+
+        // and each of those has its own, heartbeats sharing theirs with verification polls
+        assert_eq!(call(actix_test::TestRequest::post().uri("/server/delete?port=37015")).await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(call(actix_test::TestRequest::post().uri("/server/heartbeat")).await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(call(actix_test::TestRequest::get().uri("/server/verification/missing")).await, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn normalizes_list_queries() {
+        assert_eq!(normalize_list_query("sort=players&map_name=mp_lobby"), "map_name=mp_lobby&sort=players");
+        assert_eq!(normalize_list_query("map_name=mp_lobby&sort=players&format=json"), "map_name=mp_lobby&sort=players");
+        assert_eq!(normalize_list_query("&format&limit=10&"), "limit=10");
+        assert_eq!(normalize_list_query(""), "");
+    }
+
+    #[actix_web::test]
+    async fn etag_depends_on_the_query() {
+        let config = Config { max_servers_per_ip: 8, ..Config::default() };
+        let storage: Arc<dyn ServerStore> = Arc::new(ServerStorage::new(config));
+        storage.add_server(ServerInfo::for_test(0)).unwrap();
+        storage.add_server(ServerInfo { map_name: "mp_angel_city".to_string(), ..ServerInfo::for_test(1) }).unwrap();
+        let app = actix_test::init_service(
+            test_support::app(&storage)
+                .route("/server/", web::get().to(get_servers))
+        ).await;
+        let get = |uri: &str, etag: Option<&str>| {
+            let mut req = actix_test::TestRequest::get().uri(uri).peer_addr("127.0.0.1:50000".parse().unwrap());
+            if let Some(etag) = etag {
+                req = req.insert_header((header::IF_NONE_MATCH, etag.to_string()));
+            }
+            let req = req.to_request();
+            let app = &app;
+            async move {
+                let response = actix_test::call_service(app, req).await;
+                let etag = response.headers().get(header::ETAG).unwrap().to_str().unwrap().to_string();
+                (response.status(), etag)
+            }
+        };
+
+        let (_, lobby) = get("/server/?format=json&map_name=mp_lobby", None).await;
+        let (_, angel_city) = get("/server/?format=json&map_name=mp_angel_city", None).await;
+        assert_ne!(lobby, angel_city);
+
+        // Another query's tag doesn't validate this one at the same version
+        let (status, _) = get("/server/?format=json&map_name=mp_angel_city", Some(&lobby)).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // The same query with its parameters reordered does
+        let (status, etag) = get("/server/?map_name=mp_lobby&format=json", Some(&lobby)).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert_eq!(etag, lobby);
+    }
+
+    #[actix_web::test]
+    async fn conditional_requests_count_against_the_quota() {
+        let storage: Arc<dyn ServerStore> = Arc::new(ServerStorage::new(Config::default()));
+        let app = actix_test::init_service(
+            test_support::app(&storage)
+                .app_data(web::Data::new(ListLimiter::new(quota(2))))
+                .route("/server/", web::get().to(get_servers))
+        ).await;
+        let get = |if_none_match: &str| {
+            actix_test::TestRequest::get()
+                .uri("/server/")
+                .insert_header((header::IF_NONE_MATCH, if_none_match.to_string()))
+                .peer_addr("127.0.0.1:50000".parse().unwrap())
+                .to_request()
+        };
+
+        assert_eq!(actix_test::call_service(&app, get("*")).await.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(actix_test::call_service(&app, get("*")).await.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(actix_test::call_service(&app, get("*")).await.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn hidden_players_stay_out_of_public_views() {
+        let player = Player { name: "Pilot".to_string(), gen: 2, lvl: 50, team: 1 };
+        let server = ServerInfo { players: vec![player.clone(), player], hide_players: true, ..ServerInfo::for_test(0) };
+
+        let json = serde_json::to_value(ServerView::from(&server)).unwrap();
+        assert_eq!(json["players"], serde_json::json!([]));
+        assert_eq!(json["playerCount"], 2);
+        assert!(!json.to_string().contains("Pilot"));
+
+        let mut message = Builder::new_default();
+        write_server(message.init_root::<server_heartbeat::Builder>(), &server);
+        let body = write_capnp(&message, false);
+        let reader = capnp::serialize::read_message(&mut body.as_slice(), capnp::message::ReaderOptions::new()).unwrap();
+        let written = reader.get_root::<server_heartbeat::Reader>().unwrap();
+        assert_eq!(written.get_players().unwrap().len(), 0);
+        assert_eq!(written.get_player_count(), 2);
+
+        // Servers that don't opt out list their players as before
+        let shown = ServerInfo { hide_players: false, ..server };
+        assert_eq!(serde_json::to_value(ServerView::from(&shown)).unwrap()["players"][0]["name"], "Pilot");
+    }
+
+    #[actix_web::test]
+    async fn serves_server_detail_as_capnp() {
+        let storage: Arc<dyn ServerStore> = Arc::new(ServerStorage::new(Config::default()));
+        let server = storage.add_server(ServerInfo::with_players(0, &["alice", "bob"])).unwrap();
+
+        let response = read(&storage, actix_test::TestRequest::get().uri("/server/server-0")).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.content_type, "application/x-capnproto");
+        let reader = capnp::serialize::read_message(&mut response.body.as_ref(), capnp::message::ReaderOptions::new()).unwrap();
+        let detail = reader.get_root::<server_detail::Reader>().unwrap();
+        assert_eq!(detail.get_first_seen(), server.first_seen);
+        assert_eq!(detail.get_last_heartbeat(), server.last_heartbeat);
+        let heartbeat = detail.get_server().unwrap();
+        assert_eq!(heartbeat.get_id().unwrap(), "server-0");
+        assert_eq!(heartbeat.get_player_count(), 2);
+        let players = heartbeat.get_players().unwrap();
+        let names: Vec<&str> = players.iter().map(|p| p.get_name().unwrap()).collect();
+        assert_eq!(names, ["alice", "bob"]);
+        assert_eq!(players.get(0).get_lvl(), 50);
+    }
+
+    #[actix_web::test]
+    async fn serves_server_detail_as_json() {
+        let storage: Arc<dyn ServerStore> = Arc::new(ServerStorage::new(Config::default()));
+        let server = storage.add_server(ServerInfo::with_players(0, &["alice", "bob"])).unwrap();
+
+        let req = actix_test::TestRequest::get().uri("/server/server-0").insert_header((header::ACCEPT, "application/json"));
+        let response = read(&storage, req).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.content_type, "application/json");
+        let detail: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(detail["id"], "server-0");
+        assert_eq!(detail["firstSeen"], server.first_seen);
+        assert_eq!(detail["lastHeartbeat"], server.last_heartbeat);
+        assert_eq!(detail["players"], serde_json::json!([
+            { "name": "alice", "gen": 2, "lvl": 50, "team": 1 },
+            { "name": "bob", "gen": 2, "lvl": 50, "team": 1 },
+        ]));
+        assert!(detail.get("token").is_none());
+    }
+
+    #[actix_web::test]
+    async fn server_detail_is_gone_once_the_server_is() {
+        let config = Config { max_servers_per_ip: 8, ..Config::default() };
+        let storage: Arc<dyn ServerStore> = Arc::new(ServerStorage::new(config.clone()));
+        let stale = ServerInfo { last_heartbeat: 0, ..ServerInfo::for_test(0) };
+        storage.add_server(stale).unwrap();
+        storage.add_server(ServerInfo::for_test(1)).unwrap();
+        let detail = |id: &str| actix_test::TestRequest::get().uri(&format!("/server/{}?format=json", id));
+        assert_eq!(read(&storage, detail("server-0")).await.status, StatusCode::OK);
+
+        storage.cleanup_stale_servers();
+        assert_eq!(read(&storage, detail("server-0")).await.status, StatusCode::NOT_FOUND);
+
+        assert_eq!(read(&storage, detail("server-1")).await.status, StatusCode::OK);
+        storage.remove_server("server-1", RemovalReason::Deleted);
+        assert_eq!(read(&storage, detail("server-1")).await.status, StatusCode::NOT_FOUND);
+        assert_eq!(read(&storage, detail("unknown")).await.status, StatusCode::NOT_FOUND);
+    }
+
+    /// Host names of a Cap'n Proto server list body.
+    fn list_hostnames(body: &[u8], packed: bool) -> Vec<String> {
+        let mut body = body;
+        let reader = if packed {
+            capnp::serialize_packed::read_message(&mut body, capnp::message::ReaderOptions::new())
+        } else {
+            capnp::serialize::read_message(&mut body, capnp::message::ReaderOptions::new())
+        }
+        .unwrap();
+        let list = reader.get_root::<server_list::Reader>().unwrap();
+        list.get_servers().unwrap().iter().map(|s| s.get_hostname().unwrap().to_string()).collect()
+    }
+
+    #[actix_web::test]
+    async fn packed_accept_gets_a_packed_list() {
+        let storage: Arc<dyn ServerStore> = Arc::new(ServerStorage::new(Config::default()));
+        storage.add_server(ServerInfo::with_players(0, &["alice"])).unwrap();
+        storage.add_server(ServerInfo::with_players(1, &[])).unwrap();
+
+        let req = actix_test::TestRequest::get().uri("/server/").insert_header((header::ACCEPT, "application/x-capnproto-packed"));
+        let packed = read(&storage, req).await;
+        assert_eq!(packed.status, StatusCode::OK);
+        assert_eq!(packed.content_type, "application/x-capnproto-packed");
+        assert_eq!(list_hostnames(&packed.body, true), ["Server 0", "Server 1"]);
+
+        // The same list as `serialize_server_list` packs it
+        let page = web::Query::<ServerListQuery>::from_query("").unwrap().apply(storage.get_servers()).unwrap();
+        assert_eq!(packed.body, serialize_server_list(&page, storage.version(), true));
+
+        // Clients asking for plain Cap'n Proto, or nothing in particular, get it unpacked
+        for accept in ["application/x-capnproto", "*/*"] {
+            let req = actix_test::TestRequest::get().uri("/server/").insert_header((header::ACCEPT, accept));
+            let plain = read(&storage, req).await;
+            assert_eq!(plain.status, StatusCode::OK);
+            assert_eq!(plain.content_type, "application/x-capnproto", "{}", accept);
+            assert_eq!(plain.body, serialize_server_list(&page, storage.version(), false), "{}", accept);
+            assert_eq!(list_hostnames(&plain.body, false), ["Server 0", "Server 1"]);
+        }
+        let plain = read(&storage, actix_test::TestRequest::get().uri("/server/")).await;
+        assert_eq!(plain.content_type, "application/x-capnproto");
+        assert_ne!(plain.body, packed.body);
+    }
+}
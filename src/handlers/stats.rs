@@ -0,0 +1,177 @@
+// src/handlers/stats.rs
+use actix_web::{ web, HttpRequest, HttpResponse };
+use actix_web::http::header;
+use actix_web::web::Bytes;
+use capnp::message::Builder;
+use crate::rate_limit::ListLimiter;
+use log::{ debug, error };
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use crate::handlers::list_cache::ListCache;
+use crate::handlers::list_query::ListFormat;
+use crate::handlers::servers::{ negotiate_format, write_capnp };
+use crate::models::stats::ServerStats;
+use crate::schema::{ count, server_stats };
+use crate::storage::ServerStore;
+use crate::utils::{ extract_real_ip, rate_limit_key, RequestError };
+
+#[derive(Deserialize)]
+pub struct StatsQuery {
+    format: Option<ListFormat>,
+}
+
+/// Reports aggregate figures across all registered servers.
+///
+/// Responses share the server list cache, so the figures are only recomputed
+/// once the registry has changed and the debounce has passed.
+pub async fn get_stats(
+    storage: web::Data<dyn ServerStore>,
+    rate_limiter: web::Data<ListLimiter>,
+    req: HttpRequest,
+    query: web::Query<StatsQuery>,
+    list_cache: web::Data<ListCache>,
+) -> Result<HttpResponse, RequestError> {
+    let peer_ip = extract_real_ip(&req)?;
+
+    // Rate Limiting
+    if rate_limiter.check_key(&rate_limit_key(peer_ip)).is_err() {
+        error!("Rate limit exceeded for stats for ip: {}", peer_ip);
+        return Err(RequestError::RateLimitExceeded);
+    }
+
+    let format = negotiate_format(&req, query.format);
+    // Server list keys start with the format, so these can't collide with them
+    let cache_key = format!("stats/{}", format.as_str());
+    let version = storage.version();
+
+    let body = match list_cache.get(&cache_key, version) {
+        Some(cached) => cached.body,
+        None => {
+            let stats = ServerStats::compute(&storage.get_servers(), version);
+            debug!("Computed {} stats over {} servers", format.as_str(), stats.total_servers);
+            let body = match format {
+                ListFormat::Capnp => serialize_stats(&stats, false),
+                ListFormat::CapnpPacked => serialize_stats(&stats, true),
+                ListFormat::Json => serde_json::to_vec(&stats).expect("Failed to serialize stats"),
+            };
+            list_cache.insert(&cache_key, version, Bytes::from(body)).body
+        }
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((header::VARY, "Accept"))
+        .body(body))
+}
+
+/// Serializes the stats as a Cap'n Proto `ServerStats` message.
+fn serialize_stats(stats: &ServerStats, packed: bool) -> Vec<u8> {
+    let mut message = Builder::new_default();
+    let mut stats_data = message.init_root::<server_stats::Builder>();
+    stats_data.set_total_servers(stats.total_servers as u32);
+    stats_data.set_total_players(stats.total_players as u32);
+    stats_data.set_empty_servers(stats.occupancy.empty as u32);
+    stats_data.set_partial_servers(stats.occupancy.partial as u32);
+    stats_data.set_full_servers(stats.occupancy.full as u32);
+    stats_data.set_version(stats.version);
+    write_counts(stats_data.reborrow().init_players_by_map(stats.players_by_map.len() as u32), &stats.players_by_map);
+    write_counts(stats_data.init_players_by_game_mode(stats.players_by_game_mode.len() as u32), &stats.players_by_game_mode);
+    write_capnp(&message, packed)
+}
+
+fn write_counts(mut list: capnp::struct_list::Builder<count::Owned>, counts: &BTreeMap<String, usize>) {
+    for (i, (name, value)) in counts.iter().enumerate() {
+        let mut entry = list.reborrow().get(i as u32);
+        entry.set_name(name);
+        entry.set_count(*value as u32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test as actix_test;
+    use actix_web::http::StatusCode;
+    use capnp::message::ReaderOptions;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::config::Config;
+    use crate::handlers::test_support;
+    use crate::models::server::ServerInfo;
+    use crate::storage::memory::ServerStorage;
+
+    /// A registry with an empty, a partial and a full server.
+    fn storage() -> Arc<dyn ServerStore> {
+        let config = Config { max_servers_per_ip: 8, ..Config::default() };
+        let storage: Arc<dyn ServerStore> = Arc::new(ServerStorage::new(config));
+        let full = ServerInfo { max_players: 2, ..ServerInfo::with_players(2, &["Pilot"; 2]) };
+        for server in [ServerInfo::with_players(0, &[]), ServerInfo::with_players(1, &["Pilot"; 3]), full] {
+            storage.add_server(server).unwrap();
+        }
+        storage
+    }
+
+    async fn get_stats_from(storage: &Arc<dyn ServerStore>, list_cache: &web::Data<ListCache>, uri: &str) -> (StatusCode, Bytes) {
+        let app = actix_test::init_service(
+            test_support::app(storage)
+                .app_data(list_cache.clone())
+                .route("/stats", web::get().to(get_stats))
+        ).await;
+        let req = actix_test::TestRequest::get().uri(uri).peer_addr("127.0.0.1:50000".parse().unwrap());
+        let response = actix_test::call_service(&app, req.to_request()).await;
+        (response.status(), actix_test::read_body(response).await)
+    }
+
+    fn no_debounce() -> web::Data<ListCache> {
+        web::Data::new(ListCache::new(Duration::from_millis(0), 16))
+    }
+
+    #[actix_web::test]
+    async fn reports_figures_as_json() {
+        let (status, body) = get_stats_from(&storage(), &no_debounce(), "/stats?format=json").await;
+        assert_eq!(status, StatusCode::OK);
+
+        let stats: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(stats["totalServers"], 3);
+        assert_eq!(stats["totalPlayers"], 5);
+        assert_eq!(stats["playersByMap"]["mp_lobby"], 5);
+        assert_eq!(stats["playersByGameMode"]["tdm"], 5);
+        assert_eq!(stats["occupancy"], serde_json::json!({ "empty": 1, "partial": 1, "full": 1 }));
+    }
+
+    #[actix_web::test]
+    async fn reports_figures_as_capnp() {
+        let (status, body) = get_stats_from(&storage(), &no_debounce(), "/stats").await;
+        assert_eq!(status, StatusCode::OK);
+
+        let reader = capnp::serialize::read_message(&mut body.as_ref(), ReaderOptions::new()).unwrap();
+        let stats = reader.get_root::<server_stats::Reader>().unwrap();
+        assert_eq!(stats.get_total_servers(), 3);
+        assert_eq!(stats.get_total_players(), 5);
+        assert_eq!((stats.get_empty_servers(), stats.get_partial_servers(), stats.get_full_servers()), (1, 1, 1));
+        let by_map = stats.get_players_by_map().unwrap();
+        assert_eq!(by_map.len(), 1);
+        assert_eq!(by_map.get(0).get_name().unwrap(), "mp_lobby");
+        assert_eq!(by_map.get(0).get_count(), 5);
+    }
+
+    #[actix_web::test]
+    async fn reuses_figures_within_debounce() {
+        let storage = storage();
+        let list_cache = web::Data::new(ListCache::new(Duration::from_secs(60), 16));
+        let (_, first) = get_stats_from(&storage, &list_cache, "/stats?format=json").await;
+
+        // A registry change within the debounce is served the cached figures
+        storage.add_server(ServerInfo::with_players(3, &["Pilot"; 4])).unwrap();
+        let (_, second) = get_stats_from(&storage, &list_cache, "/stats?format=json").await;
+        assert_eq!(first, second);
+
+        // Without one the figures follow the registry
+        let list_cache = no_debounce();
+        get_stats_from(&storage, &list_cache, "/stats?format=json").await;
+        storage.add_server(ServerInfo::with_players(4, &["Pilot"])).unwrap();
+        let (_, body) = get_stats_from(&storage, &list_cache, "/stats?format=json").await;
+        let stats: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(stats["totalPlayers"], 10);
+    }
+}
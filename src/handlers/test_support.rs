@@ -0,0 +1,27 @@
+// src/handlers/test_support.rs
+use actix_web::body::BoxBody;
+use actix_web::dev::{ ServiceFactory, ServiceRequest, ServiceResponse };
+use actix_web::{ web, App, Error };
+use governor::Quota;
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::handlers::list_cache::ListCache;
+use crate::proxy::ProxyPolicy;
+use crate::rate_limit::ListLimiter;
+use crate::storage::ServerStore;
+
+/// An app with the state `main` registers for the public routes over `storage`:
+/// direct connections, a list quota no test runs into and no debounce.
+///
+/// Tests add their routes on top, and any state they want different; a later
+/// `app_data` of the same type replaces the one registered here.
+pub fn app(
+    storage: &Arc<dyn ServerStore>
+) -> App<impl ServiceFactory<ServiceRequest, Config = (), Response = ServiceResponse<BoxBody>, Error = Error, InitError = ()>> {
+    App::new()
+        .app_data(web::Data::new(ProxyPolicy::direct()))
+        .app_data(web::Data::from(storage.clone()))
+        .app_data(web::Data::new(ListLimiter::new(Quota::per_second(NonZeroU32::new(1000).unwrap()))))
+        .app_data(web::Data::new(ListCache::new(Duration::from_millis(0), 16)))
+}
@@ -0,0 +1,15 @@
+// src/lib.rs
+pub mod config;
+pub mod schema;
+pub mod models;
+pub mod handlers;
+pub mod storage;
+pub mod cloudflare;
+pub mod proxy;
+pub mod session;
+pub mod challenge;
+pub mod protocol;
+pub mod prober;
+pub mod verifier;
+pub mod rate_limit;
+pub mod utils;
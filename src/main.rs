@@ -1,75 +1,196 @@
 // src/main.rs
-mod config;
-mod schema;
-mod models;
-mod handlers;
-mod storage;
-mod cloudflare;
-mod utils;
-
-use actix_web::{ web, App, HttpServer };
+use actix_web::{ middleware, web, App, HttpServer };
 use env_logger::Env;
-use storage::memory::ServerStorage;
-use governor::{ RateLimiter, clock::DefaultClock };
-use std::net::IpAddr;
-use governor::state::keyed::DefaultKeyedStateStore;
-use crate::config::Config;
+use r1ms::{ cloudflare, handlers, storage };
+use r1ms::session::SessionSigner;
+use r1ms::challenge::ChallengeDispatcher;
+use r1ms::prober::{ self, Prober };
+use r1ms::verifier::DeferredVerifier;
+use r1ms::config::{ ProxyMode, VerificationMode };
+use r1ms::proxy::ProxyPolicy;
+use r1ms::handlers::list_cache::ListCache;
+use r1ms::handlers::events::EventStreamLimit;
+use r1ms::storage::{ reaper, snapshot, ServerStore };
+use r1ms::storage::history::{ self, PlayerHistory };
+use r1ms::rate_limit::{ DeleteLimiter, HeartbeatLimiter, ListLimiter };
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use r1ms::config::Config;
 use log::info;
-use dotenv;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     // Initialize logger only once at the start
     env_logger::init_from_env(Env::default().default_filter_or("debug"));
 
+    // Load configuration
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("Invalid configuration: {}", e);
+            return Err(std::io::Error::other(e));
+        }
+    };
+
+    // Decides which address each request is attributed to
+    let proxy_policy = match ProxyPolicy::from_config(&config) {
+        Ok(policy) => web::Data::new(policy),
+        Err(e) => {
+            log::error!("Invalid proxy configuration: {}", e);
+            return Err(std::io::Error::other(e));
+        }
+    };
+    info!("Proxy mode: {:?}", proxy_policy.mode());
+
     // Initialize Cloudflare ranges
-    if let Err(e) = cloudflare::initialize_cloudflare_ranges().await {
-        log::error!("Failed to initialize Cloudflare IP ranges: {}", e);
-        return Err(
-            std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Failed to initialize Cloudflare ranges: {}", e)
-            )
-        );
+    if proxy_policy.mode() == ProxyMode::Cloudflare {
+        if let Err(e) = cloudflare::initialize_cloudflare_ranges().await {
+            log::error!("Failed to initialize Cloudflare IP ranges: {}", e);
+            return Err(
+                std::io::Error::other(format!("Failed to initialize Cloudflare ranges: {}", e))
+            );
+        }
     }
 
-    // Load configuration
-    let config = Config::from_env();
-
     dotenv::dotenv().ok();
 
     // Get bind address and port from environment or use defaults
     let bind_address = std::env::var("BIND_ADDRESS").unwrap_or_else(|_| "0.0.0.0".to_string());
     let port = std::env::var("PORT").unwrap_or_else(|_| "80".to_string());
-    let bind = format!("{}:{}", bind_address, port);
+    // "::" listens on IPv6 and, through mapped addresses, on IPv4 as well
+    let bind = if bind_address.contains(':') {
+        format!("[{}]:{}", bind_address, port)
+    } else {
+        format!("{}:{}", bind_address, port)
+    };
+
+    let storage: web::Data<dyn ServerStore> = match storage::from_config(&config) {
+        Ok(storage) => web::Data::from(storage),
+        Err(e) => {
+            log::error!("Failed to initialize server storage: {}", e);
+            return Err(std::io::Error::other(e));
+        }
+    };
+    info!("Using {:?} server storage", config.storage_backend);
+
+    // Reload the registry saved by the previous run and keep saving it while we're up
+    let snapshot_path = config.snapshot_path.as_ref().map(PathBuf::from);
+    if let Some(path) = &snapshot_path {
+        if let Err(e) = snapshot::restore(storage.get_ref(), path) {
+            log::error!("Failed to restore registry snapshot from {}: {}", path.display(), e);
+        }
+        snapshot::spawn_snapshot_task(
+            storage.clone().into_inner(),
+            path.clone(),
+            Duration::from_secs(config.snapshot_interval_secs)
+        );
+    }
 
-    let storage = web::Data::new(ServerStorage::new(config.clone()));
+    // Set up rate limiters using config, each handler extracts the one of its own type
+    let heartbeat_rate_limiter = web::Data::new(HeartbeatLimiter::new(config.heartbeat_quota()));
+    let server_list_rate_limiter = web::Data::new(ListLimiter::new(config.server_list_quota()));
+    let server_delete_rate_limiter = web::Data::new(DeleteLimiter::new(config.server_delete_quota()));
 
-    // Set up rate limiters using config
-    let heartbeat_rate_limiter: web::Data<
-        RateLimiter<IpAddr, DefaultKeyedStateStore<IpAddr>, DefaultClock>
-    > = web::Data::new(RateLimiter::keyed(config.heartbeat_quota()));
+    // Expire stale servers in the background so listing the servers never has to
+    reaper::spawn_reaper(
+        storage.clone().into_inner(),
+        Duration::from_secs(config.reaper_interval_secs)
+    );
 
-    let server_list_rate_limiter: web::Data<
-        RateLimiter<IpAddr, DefaultKeyedStateStore<IpAddr>, DefaultClock>
-    > = web::Data::new(RateLimiter::keyed(config.server_list_quota()));
+    let session_signer = web::Data::new(
+        SessionSigner::new(config.session_secret.as_deref(), config.session_token_ttl_secs)
+    );
 
-    let server_delete_rate_limiter: web::Data<
-        RateLimiter<IpAddr, DefaultKeyedStateStore<IpAddr>, DefaultClock>
-    > = web::Data::new(RateLimiter::keyed(config.server_delete_quota()));
+    // All heartbeat challenges go out through one shared UDP socket
+    let challenge = web::Data::new(
+        ChallengeDispatcher::bind(
+            &[&config.challenge_bind_address, &config.challenge_bind_address_v6],
+            config.challenge_max_in_flight,
+            Duration::from_millis(config.challenge_timeout_ms)
+        ).await?
+    );
+    info!("Challenge sockets bound to {:?}", challenge.local_addrs()?);
+
+    // In deferred mode heartbeats are answered right away and challenged in the background
+    let verifier = (config.verification_mode == VerificationMode::Deferred).then(|| {
+        web::Data::from(
+            DeferredVerifier::spawn(
+                storage.clone().into_inner(),
+                challenge.clone().into_inner(),
+                session_signer.clone().into_inner(),
+                config.challenge_max_in_flight,
+                Duration::from_secs(config.verification_result_ttl_secs)
+            )
+        )
+    });
+    info!("Heartbeat verification mode: {:?}", config.verification_mode);
+
+    // Cross-check heartbeats against what the servers answer to direct queries
+    if config.probe_interval_secs > 0 {
+        let server_prober = Prober::bind(
+            &[&config.probe_bind_address, &config.probe_bind_address_v6],
+            Duration::from_millis(config.probe_timeout_ms)
+        ).await?;
+        prober::spawn_prober(Arc::new(server_prober), storage.clone().into_inner(), config.clone());
+        info!("Probing registered servers every {}s", config.probe_interval_secs);
+    }
+
+    // Serialized server list responses, shared by all workers
+    let list_cache = web::Data::new(
+        ListCache::new(Duration::from_millis(config.list_cache_debounce_ms), config.list_cache_max_entries)
+    );
+
+    let event_stream_limit = web::Data::new(EventStreamLimit::new(config.event_stream_max_clients));
+
+    // Player counts over time, fed by the registry's change events
+    let history = web::Data::new(PlayerHistory::new(&config));
+    history::spawn_recorder(history.clone().into_inner(), storage.clone().into_inner());
+
+    let shutdown_storage = storage.clone();
 
     info!("Starting server on {}", bind);
     HttpServer::new(move || {
-        App::new()
+        let mut app = App::new()
+            // gzip, zstd or brotli, whichever the client's Accept-Encoding prefers
+            .wrap(middleware::Compress::default())
+            .app_data(proxy_policy.clone())
             .app_data(storage.clone())
             .app_data(heartbeat_rate_limiter.clone())
             .app_data(server_list_rate_limiter.clone())
             .app_data(server_delete_rate_limiter.clone())
+            .app_data(session_signer.clone())
+            .app_data(challenge.clone())
+            .app_data(list_cache.clone())
+            .app_data(event_stream_limit.clone())
+            .app_data(history.clone());
+        if let Some(verifier) = &verifier {
+            app = app.app_data(verifier.clone());
+        }
+        app
             // .route("/auth", web::get().to(handlers::auth::handle_auth))
             .route("/server/heartbeat", web::post().to(handlers::heartbeat::handle_heartbeat))
+            .route("/server/verification/{id}", web::get().to(handlers::heartbeat::verification_status))
             .route("/server/", web::get().to(handlers::servers::get_servers))
             .route("/server/delete", web::post().to(handlers::servers::delete_server))
+            .route("/server/events", web::get().to(handlers::events::stream_events))
+            .route("/server/changes", web::get().to(handlers::servers::get_changes))
+            // Must stay after the fixed /server/... routes
+            .route("/server/{id}", web::get().to(handlers::servers::get_server))
+            .route("/players/search", web::get().to(handlers::players::search_players))
+            .route("/stats", web::get().to(handlers::stats::get_stats))
+            .route("/history", web::get().to(handlers::history::get_history))
     })
         .bind(&bind)?
-        .run().await
+        .run().await?;
+
+    // The HTTP server has stopped gracefully, save the final state of the registry
+    if let Some(path) = &snapshot_path {
+        match snapshot::save(shutdown_storage.get_ref(), path) {
+            Ok(count) => info!("Saved registry snapshot with {} servers", count),
+            Err(e) => log::error!("Failed to save registry snapshot: {}", e),
+        }
+    }
+
+    Ok(())
 }
@@ -1 +1,3 @@
 pub mod server;
+pub mod server_view;
+pub mod stats;
@@ -1,7 +1,11 @@
 // src/models/server.rs
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+// The reliability score moves a little with every heartbeat. Only a drift this
+// large is taken over, so steady heartbeats don't keep changing the list.
+const RELIABILITY_STEP: u32 = 10;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Player {
     pub name: String,
     pub gen: i32,
@@ -20,4 +24,120 @@ pub struct ServerInfo {
     pub port: i32,
     pub ip: String,
     pub last_heartbeat: u64,
+    // When the master first accepted a heartbeat for this server
+    #[serde(default)]
+    pub first_seen: u64,
+    // Secret issued with the server ID, presented back in `X-Server-Token`
+    #[serde(default)]
+    pub token: String,
+    // Set when the server's answers to info queries don't match its heartbeats
+    #[serde(default)]
+    pub flagged: bool,
+    // Keeps this server's players out of player search and every public view
+    #[serde(default)]
+    pub hide_players: bool,
+    // Address of the other family a dual-stack server can also be reached at, empty if none
+    #[serde(default)]
+    pub alt_ip: String,
+    // 0-100 score from the address's uptime and incident history, set by the storage
+    #[serde(default)]
+    pub reliability: u32,
+}
+
+impl ServerInfo {
+    /// Players to show publicly, none if the server opted out with `hide_players`.
+    pub fn visible_players(&self) -> &[Player] {
+        if self.hide_players {
+            &[]
+        } else {
+            &self.players
+        }
+    }
+
+    /// Whether the server has no free slot. One advertising no slots at all is
+    /// full even while nobody is on it.
+    pub fn is_full(&self) -> bool {
+        self.players.len() >= self.max_players.max(0) as usize
+    }
+
+    /// Whether nobody is on the server.
+    pub fn is_empty(&self) -> bool {
+        self.players.is_empty()
+    }
+
+    /// Applies a newer heartbeat to this entry, keeping its identity
+    /// (ID, token, address and `first_seen`). Entries without a token get the
+    /// heartbeat's.
+    ///
+    /// Returns whether anything shown in the server list changed.
+    /// `reliability` is only updated once it moved by `RELIABILITY_STEP`.
+    pub fn update_from(&mut self, heartbeat: ServerInfo) -> bool {
+        // A dual-stack server may reach us over its other family; the entry keeps
+        // the address it registered with and the other one becomes `alt_ip`.
+        let alt_ip = if heartbeat.ip == self.ip { heartbeat.alt_ip } else { heartbeat.ip };
+        let reliability_changed = self.reliability.abs_diff(heartbeat.reliability) >= RELIABILITY_STEP;
+
+        let changed = self.host_name != heartbeat.host_name
+            || self.map_name != heartbeat.map_name
+            || self.game_mode != heartbeat.game_mode
+            || self.players != heartbeat.players
+            || self.max_players != heartbeat.max_players
+            || self.hide_players != heartbeat.hide_players
+            || reliability_changed
+            || self.alt_ip != alt_ip;
+
+        self.host_name = heartbeat.host_name;
+        self.map_name = heartbeat.map_name;
+        self.game_mode = heartbeat.game_mode;
+        self.players = heartbeat.players;
+        self.max_players = heartbeat.max_players;
+        self.hide_players = heartbeat.hide_players;
+        if reliability_changed {
+            self.reliability = heartbeat.reliability;
+        }
+        self.alt_ip = alt_ip;
+        self.last_heartbeat = heartbeat.last_heartbeat;
+        // Entries from before tokens were issued take the first one offered
+        if self.token.is_empty() {
+            self.token = heartbeat.token;
+        }
+        changed
+    }
+
+    /// Fixture for tests and benches: the `i`-th of a fleet of empty servers,
+    /// eight to an IP, all with a heartbeat from just now.
+    #[cfg(any(test, feature = "bench"))]
+    pub fn for_test(i: usize) -> Self {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        Self {
+            id: format!("server-{}", i),
+            host_name: format!("Server {}", i),
+            map_name: "mp_lobby".to_string(),
+            game_mode: "tdm".to_string(),
+            players: Vec::new(),
+            max_players: 12,
+            port: 37015 + (i % 8) as i32,
+            ip: format!("10.{}.{}.{}", (i / 8) / 65536 % 256, (i / 8) / 256 % 256, (i / 8) % 256),
+            last_heartbeat: now,
+            first_seen: now,
+            token: String::new(),
+            flagged: false,
+            hide_players: false,
+            alt_ip: String::new(),
+            reliability: 0,
+        }
+    }
+
+    /// Fixture for tests and benches: `for_test(i)` with `names` playing on it.
+    #[cfg(any(test, feature = "bench"))]
+    pub fn with_players(i: usize, names: &[&str]) -> Self {
+        let players = names
+            .iter()
+            .map(|name| Player { name: name.to_string(), gen: 2, lvl: 50, team: 1 })
+            .collect();
+        Self { players, ..Self::for_test(i) }
+    }
 }
@@ -0,0 +1,220 @@
+// src/models/server_view.rs
+//
+// Public JSON shape of the server list, served by `GET /server/` for
+// `Accept: application/json` or `?format=json`:
+//
+//   {
+//     "servers": [{
+//       "id": "…", "hostname": "…", "mapName": "mp_lobby", "gameMode": "tdm",
+//       "players": [{ "name": "…", "gen": 1, "lvl": 50, "team": 0 }], "playerCount": 1,
+//       "maxPlayers": 12, "ip": "203.0.113.7", "port": 37015, "flagged": false,
+//       "hidePlayers": false, "reliability": 97, "altIp": "2001:db8::7"
+//     }],
+//     "totalCount": 1,
+//     "nextCursor": null,
+//     "version": 1729150000000123
+//   }
+//
+// `GET /server/changes?since=<version>` returns what changed after that version:
+//
+//   { "version": …, "resync": false, "updated": [<server>…], "removed": ["<id>"…] }
+//
+// `resync: true` means the version is too old to reconstruct and the full list
+// has to be fetched again.
+//
+// `GET /server/events` streams the same server shape as Server-Sent Events:
+// `snapshot` carries the list above, `added` and `updated` carry one server, and
+// `removed` carries `{ "id": "…", "reason": "expired" | "deleted" | "delisted" }`.
+//
+// `GET /server/{id}` returns a single server in the same shape, plus its
+// `firstSeen` and `lastHeartbeat` unix timestamps and what its `reliability`
+// score is based on: `uptime` (0 to 1), `missedHeartbeats` and `failedChallenges`.
+//
+// `GET /players/search?name=<name>` returns the players found and their servers:
+//
+//   { "matches": [{ "player": { "name": "…", "gen": 1, "lvl": 50, "team": 0 }, "server": <server> }] }
+//
+// `GET /history?resolution=1m|1h|1d` returns the global player count over time,
+// or that of one map or server with `map=<name>` or `server=<id>`. A server's
+// history follows its ip:port, so it covers earlier registrations too, and
+// unlisted servers answer 404:
+//
+//   { "resolution": "1h", "points": [{ "start": 1729148400, "min": 3, "max": 12, "avg": 7.5 }] }
+//
+// Servers with `hidePlayers` set are listed with an empty `players` list, their
+// `playerCount` still tells how full they are.
+//
+// Field names here are part of the API. Fields may be added but not renamed or
+// removed, and internal fields such as the server token stay out.
+use serde::Serialize;
+use crate::models::server::{ Player, ServerInfo };
+use crate::storage::events::RemovalReason;
+use crate::storage::history::Bucket;
+use crate::storage::players::PlayerMatch;
+use crate::storage::reliability::ReliabilityReport;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerListView<'a> {
+    pub servers: Vec<ServerView<'a>>,
+    /// Number of servers matching the filters, across all pages.
+    pub total_count: usize,
+    /// Pass as `cursor` to get the next page, `null` on the last page.
+    pub next_cursor: Option<&'a str>,
+    /// Registry version of this list, for `/server/changes?since=`.
+    pub version: u64,
+}
+
+/// Response of `/server/changes`.
+#[derive(Serialize)]
+pub struct ServerChangesView<'a> {
+    /// Pass as `since` on the next poll.
+    pub version: u64,
+    /// The requested version is too old, refetch the full list.
+    pub resync: bool,
+    /// Servers added or changed since the requested version, in their current state.
+    pub updated: Vec<ServerView<'a>>,
+    /// IDs of servers removed since the requested version.
+    pub removed: Vec<&'a str>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerView<'a> {
+    pub id: &'a str,
+    pub hostname: &'a str,
+    pub map_name: &'a str,
+    pub game_mode: &'a str,
+    /// Empty if the server hides its players.
+    pub players: Vec<PlayerView<'a>>,
+    pub player_count: usize,
+    pub max_players: i32,
+    pub ip: &'a str,
+    pub port: i32,
+    pub flagged: bool,
+    pub hide_players: bool,
+    /// 0 to 100, higher for servers that stay up and answer their challenges.
+    pub reliability: u32,
+    /// Address of the other family for dual-stack servers, `null` otherwise.
+    pub alt_ip: Option<&'a str>,
+}
+
+/// A single server with the metadata the list leaves out.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerDetailView<'a> {
+    #[serde(flatten)]
+    pub server: ServerView<'a>,
+    pub first_seen: u64,
+    pub last_heartbeat: u64,
+    pub uptime: f64,
+    pub missed_heartbeats: u32,
+    pub failed_challenges: u32,
+}
+
+/// Payload of a `removed` event on `/server/events`.
+#[derive(Serialize)]
+pub struct RemovedView<'a> {
+    pub id: &'a str,
+    pub reason: RemovalReason,
+}
+
+/// Response of `/players/search`.
+#[derive(Serialize)]
+pub struct PlayerSearchView<'a> {
+    pub matches: Vec<PlayerMatchView<'a>>,
+}
+
+#[derive(Serialize)]
+pub struct PlayerMatchView<'a> {
+    pub player: PlayerView<'a>,
+    pub server: ServerView<'a>,
+}
+
+/// Response of `/history`.
+#[derive(Serialize)]
+pub struct HistoryView {
+    pub resolution: &'static str,
+    pub points: Vec<HistoryPointView>,
+}
+
+/// Player counts seen during the interval starting at `start`.
+#[derive(Serialize)]
+pub struct HistoryPointView {
+    pub start: u64,
+    pub min: u32,
+    pub max: u32,
+    pub avg: f64,
+}
+
+#[derive(Serialize)]
+pub struct PlayerView<'a> {
+    pub name: &'a str,
+    pub gen: i32,
+    pub lvl: i32,
+    pub team: i32,
+}
+
+impl<'a> From<&'a ServerInfo> for ServerView<'a> {
+    fn from(server: &'a ServerInfo) -> Self {
+        Self {
+            id: &server.id,
+            hostname: &server.host_name,
+            map_name: &server.map_name,
+            game_mode: &server.game_mode,
+            players: server.visible_players().iter().map(PlayerView::from).collect(),
+            player_count: server.players.len(),
+            max_players: server.max_players,
+            ip: &server.ip,
+            port: server.port,
+            flagged: server.flagged,
+            hide_players: server.hide_players,
+            reliability: server.reliability,
+            alt_ip: (!server.alt_ip.is_empty()).then_some(server.alt_ip.as_str()),
+        }
+    }
+}
+
+impl<'a> ServerDetailView<'a> {
+    pub fn new(server: &'a ServerInfo, reliability: &ReliabilityReport) -> Self {
+        Self {
+            server: ServerView::from(server),
+            first_seen: server.first_seen,
+            last_heartbeat: server.last_heartbeat,
+            uptime: reliability.uptime,
+            missed_heartbeats: reliability.missed_heartbeats,
+            failed_challenges: reliability.failed_challenges,
+        }
+    }
+}
+
+impl<'a> From<&'a Player> for PlayerView<'a> {
+    fn from(player: &'a Player) -> Self {
+        Self {
+            name: &player.name,
+            gen: player.gen,
+            lvl: player.lvl,
+            team: player.team,
+        }
+    }
+}
+
+impl<'a> From<&'a PlayerMatch> for PlayerMatchView<'a> {
+    fn from(found: &'a PlayerMatch) -> Self {
+        Self {
+            player: PlayerView::from(&found.player),
+            server: ServerView::from(&found.server),
+        }
+    }
+}
+
+impl From<&Bucket> for HistoryPointView {
+    fn from(bucket: &Bucket) -> Self {
+        Self {
+            start: bucket.start,
+            min: bucket.min,
+            max: bucket.max,
+            avg: bucket.avg(),
+        }
+    }
+}
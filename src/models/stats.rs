@@ -0,0 +1,124 @@
+// src/models/stats.rs
+//
+// JSON shape of `GET /stats`:
+//
+//   {
+//     "totalServers": 3, "totalPlayers": 14,
+//     "playersByMap": { "mp_lobby": 10, "mp_box": 4 },
+//     "playersByGameMode": { "tdm": 14 },
+//     "occupancy": { "empty": 1, "partial": 1, "full": 1 },
+//     "version": 1729150000000123
+//   }
+//
+// Like the server list, fields may be added but not renamed or removed.
+use serde::Serialize;
+use std::collections::BTreeMap;
+use crate::models::server::ServerInfo;
+
+/// How many servers have no free slot, no players or neither.
+#[derive(Serialize, Default, Debug, Clone, Copy)]
+pub struct Occupancy {
+    pub empty: usize,
+    pub partial: usize,
+    pub full: usize,
+}
+
+/// Aggregate figures across every registered server.
+#[derive(Serialize, Default, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerStats {
+    pub total_servers: usize,
+    pub total_players: usize,
+    pub players_by_map: BTreeMap<String, usize>,
+    pub players_by_game_mode: BTreeMap<String, usize>,
+    pub occupancy: Occupancy,
+    /// Registry version the figures were computed at.
+    pub version: u64,
+}
+
+impl ServerStats {
+    pub fn compute(servers: &[ServerInfo], version: u64) -> Self {
+        let mut stats = Self { version, ..Self::default() };
+
+        for server in servers {
+            let player_count = server.players.len();
+            stats.total_servers += 1;
+            stats.total_players += player_count;
+            *stats.players_by_map.entry(server.map_name.clone()).or_default() += player_count;
+            *stats.players_by_game_mode.entry(server.game_mode.clone()).or_default() += player_count;
+
+            // Full as the `not_full` list filter sees it, so a server without slots
+            // is full rather than empty
+            if server.is_full() {
+                stats.occupancy.full += 1;
+            } else if server.is_empty() {
+                stats.occupancy.empty += 1;
+            } else {
+                stats.occupancy.partial += 1;
+            }
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(i: usize, map_name: &str, game_mode: &str, player_count: usize, max_players: i32) -> ServerInfo {
+        ServerInfo {
+            map_name: map_name.to_string(),
+            game_mode: game_mode.to_string(),
+            max_players,
+            ..ServerInfo::with_players(i, &vec!["Pilot"; player_count])
+        }
+    }
+
+    #[test]
+    fn counts_players_per_map_and_mode() {
+        let servers = [
+            server(0, "mp_lobby", "tdm", 3, 12),
+            server(1, "mp_lobby", "ctf", 4, 12),
+            server(2, "mp_box", "tdm", 2, 12),
+            server(3, "mp_angel_city", "tdm", 0, 12),
+        ];
+        let stats = ServerStats::compute(&servers, 7);
+
+        assert_eq!(stats.total_servers, 4);
+        assert_eq!(stats.total_players, 9);
+        assert_eq!(stats.version, 7);
+        assert_eq!(stats.players_by_map, BTreeMap::from([
+            ("mp_angel_city".to_string(), 0),
+            ("mp_box".to_string(), 2),
+            ("mp_lobby".to_string(), 7),
+        ]));
+        assert_eq!(stats.players_by_game_mode, BTreeMap::from([
+            ("ctf".to_string(), 4),
+            ("tdm".to_string(), 5),
+        ]));
+    }
+
+    #[test]
+    fn splits_occupancy_like_the_list_filters() {
+        let servers = [
+            server(0, "mp_lobby", "tdm", 0, 12),
+            server(1, "mp_lobby", "tdm", 5, 12),
+            server(2, "mp_lobby", "tdm", 12, 12),
+            // No slots at all: the `not_full` filter leaves it out, so it isn't empty here
+            server(3, "mp_lobby", "tdm", 0, 0),
+            server(4, "mp_lobby", "tdm", 0, -1),
+        ];
+        let occupancy = ServerStats::compute(&servers, 0).occupancy;
+
+        assert_eq!((occupancy.empty, occupancy.partial, occupancy.full), (1, 1, 3));
+        assert_eq!(servers.iter().filter(|s| s.is_full()).count(), occupancy.full);
+    }
+
+    #[test]
+    fn empty_registry_has_no_figures() {
+        let stats = ServerStats::compute(&[], 0);
+        assert_eq!(stats.total_servers, 0);
+        assert!(stats.players_by_map.is_empty());
+        assert_eq!((stats.occupancy.empty, stats.occupancy.partial, stats.occupancy.full), (0, 0, 0));
+    }
+}
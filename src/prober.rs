@@ -0,0 +1,482 @@
+// src/prober.rs
+use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
+use log::{ debug, info, warn };
+use std::collections::{ HashMap, HashSet };
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::{ mpsc, Semaphore };
+use tokio::task::JoinSet;
+use crate::config::{ Config, ProbeMismatchAction };
+use crate::models::server::ServerInfo;
+use crate::protocol::{ InfoReply, PlayerEntry, QueryPacket };
+use crate::storage::ServerStore;
+use crate::storage::events::RemovalReason;
+use crate::utils::{ bind_udp_sockets, format_address_for_challenge, socket_for };
+
+// Probes waiting for replies, keyed by the address of the server being probed
+type WaitingProbes = DashMap<SocketAddr, mpsc::Sender<QueryPacket>>;
+
+// Players join and leave between a heartbeat and the probe, so the player
+// count and list may be off by this many before it counts as a mismatch
+const PLAYER_CHURN_TOLERANCE: usize = 2;
+
+#[derive(Debug)]
+pub enum ProbeError {
+    /// Another probe of the same server is still running.
+    AlreadyProbing,
+    Send(std::io::Error),
+    Timeout,
+}
+
+impl fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AlreadyProbing => write!(f, "Server is already being probed"),
+            Self::Send(e) => write!(f, "Failed to send query: {}", e),
+            Self::Timeout => write!(f, "Timed out waiting for query response"),
+        }
+    }
+}
+
+/// What a server said about itself when queried directly.
+#[derive(Debug, Clone)]
+pub struct ProbeReport {
+    pub info: InfoReply,
+    /// `None` if the server didn't answer the player query.
+    pub players: Option<Vec<PlayerEntry>>,
+}
+
+/// A difference between a server's last heartbeat and its query answers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    Map { heartbeat: String, probed: String },
+    MaxPlayers { heartbeat: i32, probed: i32 },
+    PlayerCount { heartbeat: usize, probed: usize },
+    PlayerNames { heartbeat: Vec<String>, probed: Vec<String> },
+}
+
+impl Mismatch {
+    /// Whether this mismatch is enough to delist a server. Player lists change too
+    /// quickly to be held against a server beyond flagging it.
+    pub fn delists(&self) -> bool {
+        !matches!(self, Self::PlayerNames { .. })
+    }
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Map { heartbeat, probed } => write!(f, "map {} != {}", heartbeat, probed),
+            Self::MaxPlayers { heartbeat, probed } => write!(f, "max players {} != {}", heartbeat, probed),
+            Self::PlayerCount { heartbeat, probed } => write!(f, "player count {} != {}", heartbeat, probed),
+            Self::PlayerNames { heartbeat, probed } => write!(f, "players {:?} != {:?}", heartbeat, probed),
+        }
+    }
+}
+
+/// Sends A2S-style info and player queries from one UDP socket per address family.
+pub struct Prober {
+    sockets: Vec<Arc<UdpSocket>>,
+    waiting: Arc<WaitingProbes>,
+    timeout: Duration,
+}
+
+impl Prober {
+    pub async fn bind(bind_addresses: &[&str], timeout: Duration) -> std::io::Result<Self> {
+        let sockets = bind_udp_sockets(bind_addresses).await?;
+        let waiting = Arc::new(WaitingProbes::new());
+
+        for socket in &sockets {
+            tokio::spawn(receive_loop(socket.clone(), waiting.clone()));
+        }
+
+        Ok(Self { sockets, waiting, timeout })
+    }
+
+    /// Queries the server's info and, if it answers, its player list.
+    pub async fn probe(&self, server_addr: SocketAddr) -> Result<ProbeReport, ProbeError> {
+        let (sender, mut replies) = mpsc::channel(4);
+        match self.waiting.entry(server_addr) {
+            Entry::Occupied(_) => return Err(ProbeError::AlreadyProbing),
+            Entry::Vacant(entry) => {
+                entry.insert(sender);
+            }
+        }
+
+        let result = self.run_probe(server_addr, &mut replies).await;
+        self.waiting.remove(&server_addr);
+        result
+    }
+
+    async fn run_probe(
+        &self,
+        server_addr: SocketAddr,
+        replies: &mut mpsc::Receiver<QueryPacket>
+    ) -> Result<ProbeReport, ProbeError> {
+        self.send(server_addr, &QueryPacket::InfoQuery).await?;
+        let info = self
+            .wait_for(replies, |packet| match packet {
+                QueryPacket::InfoReply(info) => Some(info),
+                _ => None,
+            }).await
+            .ok_or(ProbeError::Timeout)?;
+
+        // Player queries need a challenge first; some servers skip straight to the reply.
+        self.send(server_addr, &QueryPacket::PlayerQuery { challenge: -1 }).await?;
+        let mut players = None;
+        let first = self
+            .wait_for(replies, |packet| match packet {
+                QueryPacket::Challenge { .. } | QueryPacket::PlayerReply { .. } => Some(packet),
+                _ => None,
+            }).await;
+        match first {
+            Some(QueryPacket::PlayerReply { players: list }) => players = Some(list),
+            Some(QueryPacket::Challenge { challenge }) => {
+                self.send(server_addr, &QueryPacket::PlayerQuery { challenge }).await?;
+                players = self
+                    .wait_for(replies, |packet| match packet {
+                        QueryPacket::PlayerReply { players } => Some(players),
+                        _ => None,
+                    }).await;
+            }
+            _ => debug!("No player query response from {}", server_addr),
+        }
+
+        Ok(ProbeReport { info, players })
+    }
+
+    async fn send(&self, server_addr: SocketAddr, packet: &QueryPacket) -> Result<(), ProbeError> {
+        let Some(socket) = socket_for(&self.sockets, &server_addr) else {
+            return Err(ProbeError::Send(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "No probe socket for this address family"
+            )));
+        };
+        socket.send_to(&packet.encode(), server_addr).await.map(|_| ()).map_err(ProbeError::Send)
+    }
+
+    /// Waits for the first reply `accept` maps to a value, ignoring everything else.
+    async fn wait_for<T>(
+        &self,
+        replies: &mut mpsc::Receiver<QueryPacket>,
+        mut accept: impl FnMut(QueryPacket) -> Option<T>
+    ) -> Option<T> {
+        let deadline = tokio::time::Instant::now() + self.timeout;
+        loop {
+            match tokio::time::timeout_at(deadline, replies.recv()).await {
+                Ok(Some(packet)) => {
+                    if let Some(value) = accept(packet) {
+                        return Some(value);
+                    }
+                }
+                _ => return None,
+            }
+        }
+    }
+}
+
+async fn receive_loop(socket: Arc<UdpSocket>, waiting: Arc<WaitingProbes>) {
+    let mut buffer = [0u8; 1400];
+    loop {
+        let (len, addr) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(e) => {
+                debug!("Error receiving on probe socket: {}", e);
+                continue;
+            }
+        };
+
+        let packet = match QueryPacket::decode(&buffer[..len]) {
+            Ok(packet) => packet,
+            Err(e) => {
+                debug!("Received invalid query response from {}: {}", addr, e);
+                continue;
+            }
+        };
+
+        match waiting.get(&addr) {
+            Some(sender) => {
+                let _ = sender.try_send(packet);
+            }
+            None => debug!("Dropping unexpected query response from {}", addr),
+        }
+    }
+}
+
+/// Compares a server's last heartbeat with what it answered when probed.
+pub fn compare(server: &ServerInfo, report: &ProbeReport) -> Vec<Mismatch> {
+    let mut mismatches = Vec::new();
+
+    if server.map_name != report.info.map {
+        mismatches.push(Mismatch::Map { heartbeat: server.map_name.clone(), probed: report.info.map.clone() });
+    }
+    if server.max_players != report.info.max_players as i32 {
+        mismatches.push(Mismatch::MaxPlayers {
+            heartbeat: server.max_players,
+            probed: report.info.max_players as i32,
+        });
+    }
+    if server.players.len().abs_diff(report.info.players as usize) > PLAYER_CHURN_TOLERANCE {
+        mismatches.push(Mismatch::PlayerCount {
+            heartbeat: server.players.len(),
+            probed: report.info.players as usize,
+        });
+    }
+    if let Some(probed) = &report.players {
+        // Players still connecting show up without a name
+        let mut probed: Vec<String> = probed.iter().filter(|p| !p.name.is_empty()).map(|p| p.name.clone()).collect();
+        let mut heartbeat: Vec<String> = server.players.iter().map(|p| p.name.clone()).collect();
+        probed.sort();
+        heartbeat.sort();
+        let only_probed = probed.iter().filter(|name| !heartbeat.contains(name)).count();
+        let only_heartbeat = heartbeat.iter().filter(|name| !probed.contains(name)).count();
+        if only_probed.max(only_heartbeat) > PLAYER_CHURN_TOLERANCE {
+            mismatches.push(Mismatch::PlayerNames { heartbeat, probed });
+        }
+    }
+
+    mismatches
+}
+
+/// Returns the current record of a probed server, or `None` if it has gone away or
+/// sent a heartbeat since the probe started. A probe can wait for a permit and a
+/// timeout, so its answers are only judged against the heartbeat they raced.
+fn unchanged_since_probe(storage: &dyn ServerStore, probed: &ServerInfo) -> Option<ServerInfo> {
+    storage
+        .get_server(&probed.id)
+        .filter(|current| current.last_heartbeat == probed.last_heartbeat)
+}
+
+/// Periodically probes every registered server and flags or delists the ones whose
+/// answers keep disagreeing with their heartbeats.
+pub fn spawn_prober(prober: Arc<Prober>, storage: Arc<dyn ServerStore>, config: Config) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(config.probe_interval_secs));
+        let limit = Arc::new(Semaphore::new(config.probe_concurrency.max(1)));
+        // Consecutive mismatching probes per server ID
+        let mut strikes: HashMap<String, u32> = HashMap::new();
+
+        loop {
+            ticker.tick().await;
+            let servers = storage.get_servers();
+            let live: HashSet<&str> = servers.iter().map(|s| s.id.as_str()).collect();
+            strikes.retain(|id, _| live.contains(id.as_str()));
+
+            let mut probes = JoinSet::new();
+            for server in servers.iter().cloned() {
+                let Ok(ip) = server.ip.parse() else { continue };
+                let Ok(addr) = format_address_for_challenge(ip, server.port) else { continue };
+                let prober = prober.clone();
+                let limit = limit.clone();
+                probes.spawn(async move {
+                    let _permit = limit.acquire_owned().await;
+                    let result = prober.probe(addr).await;
+                    (server, result)
+                });
+            }
+
+            while let Some(joined) = probes.join_next().await {
+                let Ok((probed, result)) = joined else { continue };
+                let report = match result {
+                    Ok(report) => report,
+                    Err(e) => {
+                        // Not every server build answers queries, so silence isn't held against it
+                        debug!("Probe of {} ({}:{}) failed: {}", probed.id, probed.ip, probed.port, e);
+                        continue;
+                    }
+                };
+                let Some(server) = unchanged_since_probe(storage.as_ref(), &probed) else {
+                    debug!("Server {} went away or sent a heartbeat while being probed, skipping", probed.id);
+                    continue;
+                };
+
+                let mismatches = compare(&server, &report);
+                if mismatches.is_empty() {
+                    strikes.remove(&server.id);
+                    if server.flagged {
+                        info!("Server {} answers match its heartbeats again, clearing flag", server.id);
+                        storage.set_flagged(&server.id, false);
+                    }
+                    continue;
+                }
+
+                let count = strikes.entry(server.id.clone()).or_insert(0);
+                *count += 1;
+                let details: Vec<String> = mismatches.iter().map(|m| m.to_string()).collect();
+                debug!("Server {} mismatch {}/{}: {}", server.id, count, config.probe_mismatch_threshold, details.join(", "));
+
+                if *count < config.probe_mismatch_threshold {
+                    continue;
+                }
+                match config.probe_mismatch_action {
+                    ProbeMismatchAction::Delist if mismatches.iter().any(Mismatch::delists) => {
+                        warn!("Delisting server {} ({}:{}): {}", server.id, server.ip, server.port, details.join(", "));
+                        storage.remove_server(&server.id, RemovalReason::Delisted);
+                        strikes.remove(&server.id);
+                    }
+                    ProbeMismatchAction::Flag | ProbeMismatchAction::Delist => {
+                        if !server.flagged {
+                            warn!("Flagging server {} ({}:{}): {}", server.id, server.ip, server.port, details.join(", "));
+                            storage.set_flagged(&server.id, true);
+                        }
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::server::Player;
+    use crate::storage::memory::ServerStorage;
+
+    fn info(map: &str, players: u8, max_players: u8) -> InfoReply {
+        InfoReply {
+            protocol: 17,
+            name: "Test Server".to_string(),
+            map: map.to_string(),
+            folder: "r1".to_string(),
+            game: "Titanfall".to_string(),
+            app_id: 0,
+            players,
+            max_players,
+            bots: 0,
+        }
+    }
+
+    fn player(name: &str) -> PlayerEntry {
+        PlayerEntry { index: 0, name: name.to_string(), score: 0, duration: 1.0 }
+    }
+
+    fn server(map: &str, player_names: &[&str], max_players: i32) -> ServerInfo {
+        ServerInfo {
+            map_name: map.to_string(),
+            players: player_names
+                .iter()
+                .map(|name| Player { name: name.to_string(), gen: 0, lvl: 1, team: 0 })
+                .collect(),
+            max_players,
+            ..ServerInfo::for_test(0)
+        }
+    }
+
+    /// Answers info queries and challenge-protected player queries like a game server.
+    async fn spawn_stub_server(info: InfoReply, players: Vec<PlayerEntry>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 1400];
+            loop {
+                let (len, from) = socket.recv_from(&mut buffer).await.unwrap();
+                let reply = match QueryPacket::decode(&buffer[..len]).unwrap() {
+                    QueryPacket::InfoQuery => QueryPacket::InfoReply(info.clone()),
+                    QueryPacket::PlayerQuery { challenge: 1234 } => QueryPacket::PlayerReply { players: players.clone() },
+                    QueryPacket::PlayerQuery { .. } => QueryPacket::Challenge { challenge: 1234 },
+                    other => panic!("unexpected packet {:?}", other),
+                };
+                socket.send_to(&reply.encode(), from).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn probes_info_and_players_through_challenge() {
+        let addr = spawn_stub_server(info("mp_angel_city", 2, 12), vec![player("alice"), player("bob")]).await;
+        let prober = Prober::bind(&["127.0.0.1:0"], Duration::from_millis(500)).await.unwrap();
+
+        let report = prober.probe(addr).await.unwrap();
+        assert_eq!(report.info, info("mp_angel_city", 2, 12));
+        assert_eq!(report.players, Some(vec![player("alice"), player("bob")]));
+        assert!(compare(&server("mp_angel_city", &["bob", "alice"], 12), &report).is_empty());
+    }
+
+    #[tokio::test]
+    async fn reports_mismatching_answers() {
+        let addr = spawn_stub_server(info("mp_lobby", 1, 16), vec![player("alice")]).await;
+        let prober = Prober::bind(&["127.0.0.1:0"], Duration::from_millis(500)).await.unwrap();
+
+        let report = prober.probe(addr).await.unwrap();
+        let mismatches = compare(&server("mp_angel_city", &["alice", "bob", "carol", "dave"], 12), &report);
+        assert_eq!(mismatches, vec![
+            Mismatch::Map { heartbeat: "mp_angel_city".to_string(), probed: "mp_lobby".to_string() },
+            Mismatch::MaxPlayers { heartbeat: 12, probed: 16 },
+            Mismatch::PlayerCount { heartbeat: 4, probed: 1 },
+            Mismatch::PlayerNames {
+                heartbeat: vec!["alice".to_string(), "bob".to_string(), "carol".to_string(), "dave".to_string()],
+                probed: vec!["alice".to_string()],
+            },
+        ]);
+        assert_eq!(mismatches.iter().filter(|m| m.delists()).count(), 3);
+    }
+
+    #[test]
+    fn tolerates_player_churn() {
+        let report = ProbeReport {
+            info: info("mp_lobby", 3, 12),
+            players: Some(vec![player("alice"), player("carol"), player("dave")]),
+        };
+        // bob left and two others joined since the heartbeat
+        assert!(compare(&server("mp_lobby", &["alice", "bob"], 12), &report).is_empty());
+
+        let report = ProbeReport {
+            info: info("mp_lobby", 5, 12),
+            players: Some(vec![player("carol"), player("dave"), player("erin"), player("frank"), player("gina")]),
+        };
+        assert_eq!(compare(&server("mp_lobby", &["alice", "bob"], 12), &report), vec![
+            Mismatch::PlayerCount { heartbeat: 2, probed: 5 },
+            Mismatch::PlayerNames {
+                heartbeat: vec!["alice".to_string(), "bob".to_string()],
+                probed: vec!["carol".to_string(), "dave".to_string(), "erin".to_string(), "frank".to_string(), "gina".to_string()],
+            },
+        ]);
+    }
+
+    #[test]
+    fn judges_probes_against_current_record() {
+        let storage = ServerStorage::new(Config::default());
+        let probed = storage.add_server(ServerInfo::for_test(1)).unwrap();
+
+        // A flag set meanwhile is what the decision sees
+        storage.set_flagged(&probed.id, true);
+        assert!(unchanged_since_probe(&storage, &probed).unwrap().flagged);
+
+        // A heartbeat that raced the probe may have changed what it's compared against
+        let refreshed = ServerInfo { map_name: "mp_angel_city".to_string(), last_heartbeat: probed.last_heartbeat + 1, ..probed.clone() };
+        storage.add_server(refreshed).unwrap();
+        assert!(unchanged_since_probe(&storage, &probed).is_none());
+
+        let probed = storage.get_server(&probed.id).unwrap();
+        storage.remove_server(&probed.id, RemovalReason::Expired);
+        assert!(unchanged_since_probe(&storage, &probed).is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_concurrent_probe_of_same_server() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = silent.local_addr().unwrap();
+        let prober = Prober::bind(&["127.0.0.1:0"], Duration::from_millis(100)).await.unwrap();
+
+        let (first, second) = tokio::join!(prober.probe(addr), prober.probe(addr));
+        assert!(matches!(first, Err(ProbeError::Timeout)));
+        assert!(matches!(second, Err(ProbeError::AlreadyProbing)));
+
+        // The address is free again once the first probe is done
+        assert!(matches!(prober.probe(addr).await, Err(ProbeError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn times_out_on_silent_server() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let prober = Prober::bind(&["127.0.0.1:0"], Duration::from_millis(100)).await.unwrap();
+
+        let result = prober.probe(silent.local_addr().unwrap()).await;
+        assert!(matches!(result, Err(ProbeError::Timeout)));
+    }
+}
@@ -0,0 +1,508 @@
+// src/protocol.rs
+//
+// Codec for the game's connectionless UDP packets. Every packet starts with a
+// 0xFFFFFFFF header followed by a single type byte:
+//
+//   'H' connect request  : header 'H' "connect" <nonce> NUL
+//   'I' challenge reply  : header 'I' <challenge: u32 le> "connect" <nonce> [NUL ...]
+//   '9' connect rejected : header '9' <reason> NUL
+//
+// The nonce is sent as "0x" followed by 8 upper-case hex digits.
+//
+// Servers also answer A2S-style queries, which reuse some of the same type
+// bytes ('I' is also the info reply) and are decoded separately as `QueryPacket`:
+//
+//   'T' info query       : header 'T' "Source Engine Query" NUL
+//   'I' info reply       : header 'I' <protocol: u8> <name> NUL <map> NUL <folder> NUL <game> NUL
+//                          <app id: u16 le> <players: u8> <max players: u8> <bots: u8> [...]
+//   'U' player query     : header 'U' <challenge: i32 le>, -1 asks for a challenge
+//   'A' query challenge  : header 'A' <challenge: i32 le>
+//   'D' player reply     : header 'D' <count: u8> { <index: u8> <name> NUL <score: i32 le> <duration: f32 le> }
+use std::fmt;
+
+pub const CONNECTIONLESS_HEADER: [u8; 4] = [0xff, 0xff, 0xff, 0xff];
+
+pub const C2S_CONNECT: u8 = b'H';
+pub const S2C_CHALLENGE: u8 = b'I';
+pub const S2C_CONNREJECT: u8 = b'9';
+
+pub const A2S_INFO: u8 = b'T';
+pub const S2A_INFO: u8 = b'I';
+pub const A2S_PLAYER: u8 = b'U';
+pub const S2C_QUERY_CHALLENGE: u8 = b'A';
+pub const S2A_PLAYER: u8 = b'D';
+
+const CONNECT_STRING: &[u8] = b"connect";
+const INFO_QUERY_STRING: &[u8] = b"Source Engine Query";
+const NONCE_LEN: usize = 10;
+
+/// The random value the master sends in a connect request and expects echoed back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Nonce(pub u32);
+
+impl fmt::Display for Nonce {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:08X}", self.0)
+    }
+}
+
+impl Nonce {
+    fn parse(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let invalid = || DecodeError::InvalidNonce(String::from_utf8_lossy(bytes).into_owned());
+
+        if bytes.len() != NONCE_LEN || !bytes.starts_with(b"0x") {
+            return Err(invalid());
+        }
+        let digits = std::str::from_utf8(&bytes[2..]).map_err(|_| invalid())?;
+        if !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        u32::from_str_radix(digits, 16).map(Nonce).map_err(|_| invalid())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    /// Sent by the master to start a challenge.
+    ConnectRequest { nonce: Nonce },
+    /// The game server's answer to a connect request.
+    ChallengeReply { challenge: u32, nonce: Nonce },
+    /// The game server refused the connection.
+    ConnectReject { reason: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    TooShort { expected: usize, actual: usize },
+    NotConnectionless([u8; 4]),
+    UnknownType(u8),
+    BadConnectString(Vec<u8>),
+    BadQueryString(Vec<u8>),
+    InvalidNonce(String),
+    UnterminatedString(&'static str),
+    InvalidUtf8(&'static str),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooShort { expected, actual } => {
+                write!(f, "Packet too short: expected at least {} bytes, got {}", expected, actual)
+            }
+            Self::NotConnectionless(header) => write!(f, "Missing connectionless header, got {:02x?}", header),
+            Self::UnknownType(kind) => write!(f, "Unknown packet type 0x{:02x}", kind),
+            Self::BadConnectString(found) => write!(f, "Expected \"connect\", got {:?}", String::from_utf8_lossy(found)),
+            Self::BadQueryString(found) => {
+                write!(f, "Expected \"Source Engine Query\", got {:?}", String::from_utf8_lossy(found))
+            }
+            Self::InvalidNonce(found) => write!(f, "Invalid nonce {:?}", found),
+            Self::UnterminatedString(field) => write!(f, "Missing NUL terminator after {}", field),
+            Self::InvalidUtf8(field) => write!(f, "{} is not valid UTF-8", field),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl Packet {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut packet = CONNECTIONLESS_HEADER.to_vec();
+        match self {
+            Self::ConnectRequest { nonce } => {
+                packet.push(C2S_CONNECT);
+                packet.extend_from_slice(CONNECT_STRING);
+                packet.extend_from_slice(nonce.to_string().as_bytes());
+                packet.push(0x00);
+            }
+            Self::ChallengeReply { challenge, nonce } => {
+                packet.push(S2C_CHALLENGE);
+                packet.extend_from_slice(&challenge.to_le_bytes());
+                packet.extend_from_slice(CONNECT_STRING);
+                packet.extend_from_slice(nonce.to_string().as_bytes());
+                packet.push(0x00);
+            }
+            Self::ConnectReject { reason } => {
+                packet.push(S2C_CONNREJECT);
+                packet.extend_from_slice(reason.as_bytes());
+                packet.push(0x00);
+            }
+        }
+        packet
+    }
+
+    pub fn decode(buffer: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(buffer);
+        reader.expect_header()?;
+
+        match reader.take(1)?[0] {
+            C2S_CONNECT => {
+                reader.expect_connect()?;
+                let nonce = Nonce::parse(reader.cstring("nonce")?)?;
+                Ok(Self::ConnectRequest { nonce })
+            }
+            S2C_CHALLENGE => {
+                let challenge = reader.u32_le()?;
+                reader.expect_connect()?;
+                // Anything after the nonce is ignored; some builds pad the reply.
+                let nonce = Nonce::parse(reader.take(NONCE_LEN)?)?;
+                Ok(Self::ChallengeReply { challenge, nonce })
+            }
+            S2C_CONNREJECT => Ok(Self::ConnectReject { reason: reader.utf8_cstring("reason")? }),
+            other => Err(DecodeError::UnknownType(other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InfoReply {
+    pub protocol: u8,
+    pub name: String,
+    pub map: String,
+    pub folder: String,
+    pub game: String,
+    pub app_id: u16,
+    pub players: u8,
+    pub max_players: u8,
+    pub bots: u8,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayerEntry {
+    pub index: u8,
+    pub name: String,
+    pub score: i32,
+    pub duration: f32,
+}
+
+/// A2S-style query packets, used to check what a server reports about itself.
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryPacket {
+    InfoQuery,
+    InfoReply(InfoReply),
+    /// A challenge of -1 asks the server to hand out a challenge first.
+    PlayerQuery { challenge: i32 },
+    Challenge { challenge: i32 },
+    /// The count is a single byte, so only the first 255 players are encoded.
+    PlayerReply { players: Vec<PlayerEntry> },
+}
+
+impl QueryPacket {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut packet = CONNECTIONLESS_HEADER.to_vec();
+        match self {
+            Self::InfoQuery => {
+                packet.push(A2S_INFO);
+                packet.extend_from_slice(INFO_QUERY_STRING);
+                packet.push(0x00);
+            }
+            Self::InfoReply(info) => {
+                packet.push(S2A_INFO);
+                packet.push(info.protocol);
+                for field in [&info.name, &info.map, &info.folder, &info.game] {
+                    packet.extend_from_slice(field.as_bytes());
+                    packet.push(0x00);
+                }
+                packet.extend_from_slice(&info.app_id.to_le_bytes());
+                packet.extend_from_slice(&[info.players, info.max_players, info.bots]);
+            }
+            Self::PlayerQuery { challenge } => {
+                packet.push(A2S_PLAYER);
+                packet.extend_from_slice(&challenge.to_le_bytes());
+            }
+            Self::Challenge { challenge } => {
+                packet.push(S2C_QUERY_CHALLENGE);
+                packet.extend_from_slice(&challenge.to_le_bytes());
+            }
+            Self::PlayerReply { players } => {
+                packet.push(S2A_PLAYER);
+                let players = &players[..players.len().min(u8::MAX as usize)];
+                packet.push(players.len() as u8);
+                for player in players {
+                    packet.push(player.index);
+                    packet.extend_from_slice(player.name.as_bytes());
+                    packet.push(0x00);
+                    packet.extend_from_slice(&player.score.to_le_bytes());
+                    packet.extend_from_slice(&player.duration.to_le_bytes());
+                }
+            }
+        }
+        packet
+    }
+
+    pub fn decode(buffer: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(buffer);
+        reader.expect_header()?;
+
+        match reader.take(1)?[0] {
+            A2S_INFO => {
+                let query = reader.cstring("query string")?;
+                if query != INFO_QUERY_STRING {
+                    return Err(DecodeError::BadQueryString(query.to_vec()));
+                }
+                Ok(Self::InfoQuery)
+            }
+            S2A_INFO => Ok(Self::InfoReply(InfoReply {
+                protocol: reader.take(1)?[0],
+                name: reader.utf8_cstring("name")?,
+                map: reader.utf8_cstring("map")?,
+                folder: reader.utf8_cstring("folder")?,
+                game: reader.utf8_cstring("game")?,
+                app_id: reader.u16_le()?,
+                players: reader.take(1)?[0],
+                max_players: reader.take(1)?[0],
+                bots: reader.take(1)?[0],
+            })),
+            A2S_PLAYER => Ok(Self::PlayerQuery { challenge: reader.u32_le()? as i32 }),
+            S2C_QUERY_CHALLENGE => Ok(Self::Challenge { challenge: reader.u32_le()? as i32 }),
+            S2A_PLAYER => {
+                let count = reader.take(1)?[0];
+                let mut players = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    players.push(PlayerEntry {
+                        index: reader.take(1)?[0],
+                        name: reader.utf8_cstring("player name")?,
+                        score: reader.u32_le()? as i32,
+                        duration: f32::from_bits(reader.u32_le()?),
+                    });
+                }
+                Ok(Self::PlayerReply { players })
+            }
+            other => Err(DecodeError::UnknownType(other)),
+        }
+    }
+}
+
+/// Bounds-checked cursor over a received datagram.
+struct Reader<'a> {
+    buffer: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buffer: &'a [u8]) -> Self {
+        Self { buffer, position: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let end = self.position + len;
+        if end > self.buffer.len() {
+            return Err(DecodeError::TooShort { expected: end, actual: self.buffer.len() });
+        }
+        let bytes = &self.buffer[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn expect_header(&mut self) -> Result<(), DecodeError> {
+        let header = self.take(CONNECTIONLESS_HEADER.len())?;
+        if header != CONNECTIONLESS_HEADER {
+            let mut found = [0u8; 4];
+            found.copy_from_slice(header);
+            return Err(DecodeError::NotConnectionless(found));
+        }
+        Ok(())
+    }
+
+    fn u16_le(&mut self) -> Result<u16, DecodeError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32_le(&mut self) -> Result<u32, DecodeError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn expect_connect(&mut self) -> Result<(), DecodeError> {
+        let found = self.take(CONNECT_STRING.len())?;
+        if found != CONNECT_STRING {
+            return Err(DecodeError::BadConnectString(found.to_vec()));
+        }
+        Ok(())
+    }
+
+    /// Reads a NUL-terminated string, not including the terminator.
+    fn cstring(&mut self, field: &'static str) -> Result<&'a [u8], DecodeError> {
+        let rest = &self.buffer[self.position..];
+        let len = rest.iter().position(|&b| b == 0).ok_or(DecodeError::UnterminatedString(field))?;
+        self.position += len + 1;
+        Ok(&rest[..len])
+    }
+
+    fn utf8_cstring(&mut self, field: &'static str) -> Result<String, DecodeError> {
+        let bytes = self.cstring(field)?;
+        std::str::from_utf8(bytes)
+            .map(str::to_string)
+            .map_err(|_| DecodeError::InvalidUtf8(field))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn challenge_reply_bytes(nonce: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0xff, 0xff, 0xff, 0xff, b'I', 0x2a, 0x00, 0x00, 0x00];
+        packet.extend_from_slice(b"connect");
+        packet.extend_from_slice(nonce);
+        packet
+    }
+
+    #[test]
+    fn encodes_connect_request() {
+        let packet = Packet::ConnectRequest { nonce: Nonce(0xdeadbeef) }.encode();
+        assert_eq!(packet, b"\xff\xff\xff\xffHconnect0xDEADBEEF\x00".to_vec());
+    }
+
+    #[test]
+    fn decodes_challenge_reply() {
+        let packet = Packet::decode(&challenge_reply_bytes(b"0x0000BEEF\x00")).unwrap();
+        assert_eq!(packet, Packet::ChallengeReply { challenge: 42, nonce: Nonce(0xbeef) });
+    }
+
+    #[test]
+    fn challenge_reply_accepts_lowercase_and_trailing_bytes() {
+        let packet = Packet::decode(&challenge_reply_bytes(b"0xdeadbeef\x00extra")).unwrap();
+        assert_eq!(packet, Packet::ChallengeReply { challenge: 42, nonce: Nonce(0xdeadbeef) });
+    }
+
+    #[test]
+    fn round_trips_every_packet_type() {
+        let packets = [
+            Packet::ConnectRequest { nonce: Nonce(1) },
+            Packet::ChallengeReply { challenge: u32::MAX, nonce: Nonce(0x12345678) },
+            Packet::ConnectReject { reason: "Server is full.".to_string() },
+        ];
+        for packet in packets {
+            assert_eq!(Packet::decode(&packet.encode()).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn rejects_short_challenge_reply() {
+        // The old parser accepted 21 bytes and then read past the end of the reply.
+        let reply = challenge_reply_bytes(b"0x12");
+        assert_eq!(reply.len(), 20);
+        assert_eq!(Packet::decode(&reply), Err(DecodeError::TooShort { expected: 26, actual: 20 }));
+    }
+
+    #[test]
+    fn rejects_missing_header() {
+        assert_eq!(
+            Packet::decode(b"\xfe\xff\xff\xffI"),
+            Err(DecodeError::NotConnectionless([0xfe, 0xff, 0xff, 0xff]))
+        );
+        assert_eq!(Packet::decode(b"\xff\xff"), Err(DecodeError::TooShort { expected: 4, actual: 2 }));
+    }
+
+    #[test]
+    fn rejects_unknown_type() {
+        assert_eq!(Packet::decode(b"\xff\xff\xff\xffZ"), Err(DecodeError::UnknownType(b'Z')));
+    }
+
+    #[test]
+    fn rejects_bad_connect_string() {
+        let mut reply = challenge_reply_bytes(b"0x00000000");
+        reply[9..16].copy_from_slice(b"connecx");
+        assert_eq!(Packet::decode(&reply), Err(DecodeError::BadConnectString(b"connecx".to_vec())));
+    }
+
+    #[test]
+    fn rejects_malformed_nonce() {
+        assert_eq!(
+            Packet::decode(&challenge_reply_bytes(b"1x00000000")),
+            Err(DecodeError::InvalidNonce("1x00000000".to_string()))
+        );
+        assert_eq!(
+            Packet::decode(&challenge_reply_bytes(b"0x0000000g")),
+            Err(DecodeError::InvalidNonce("0x0000000g".to_string()))
+        );
+        assert_eq!(
+            Packet::decode(b"\xff\xff\xff\xffHconnect0x1\x00"),
+            Err(DecodeError::InvalidNonce("0x1".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_unterminated_strings() {
+        assert_eq!(
+            Packet::decode(b"\xff\xff\xff\xff9Server is full."),
+            Err(DecodeError::UnterminatedString("reason"))
+        );
+        assert_eq!(
+            Packet::decode(b"\xff\xff\xff\xffHconnect0xDEADBEEF"),
+            Err(DecodeError::UnterminatedString("nonce"))
+        );
+    }
+
+    #[test]
+    fn rejects_non_utf8_reason() {
+        assert_eq!(Packet::decode(b"\xff\xff\xff\xff9\xc3\x28\x00"), Err(DecodeError::InvalidUtf8("reason")));
+    }
+
+    #[test]
+    fn encodes_info_query() {
+        assert_eq!(QueryPacket::InfoQuery.encode(), b"\xff\xff\xff\xffTSource Engine Query\x00".to_vec());
+    }
+
+    #[test]
+    fn round_trips_every_query_packet_type() {
+        let packets = [
+            QueryPacket::InfoQuery,
+            QueryPacket::InfoReply(InfoReply {
+                protocol: 17,
+                name: "R1Delta Server".to_string(),
+                map: "mp_lobby".to_string(),
+                folder: "r1".to_string(),
+                game: "Titanfall".to_string(),
+                app_id: 0,
+                players: 3,
+                max_players: 12,
+                bots: 0,
+            }),
+            QueryPacket::PlayerQuery { challenge: -1 },
+            QueryPacket::Challenge { challenge: 0x1234_5678 },
+            QueryPacket::PlayerReply {
+                players: vec![
+                    PlayerEntry { index: 0, name: "pilot".to_string(), score: 12, duration: 61.5 },
+                    PlayerEntry { index: 1, name: "grunt".to_string(), score: -1, duration: 0.0 },
+                ],
+            },
+        ];
+        for packet in packets {
+            assert_eq!(QueryPacket::decode(&packet.encode()).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_player_reply() {
+        let mut reply = QueryPacket::PlayerReply {
+            players: vec![PlayerEntry { index: 0, name: "pilot".to_string(), score: 1, duration: 1.0 }],
+        }.encode();
+        reply.truncate(reply.len() - 2);
+        assert_eq!(
+            QueryPacket::decode(&reply),
+            Err(DecodeError::TooShort { expected: reply.len() + 2, actual: reply.len() })
+        );
+    }
+
+    #[test]
+    fn caps_player_reply_at_255_players() {
+        let players: Vec<PlayerEntry> = (0..300)
+            .map(|i| PlayerEntry { index: i as u8, name: format!("pilot{}", i), score: i, duration: 1.0 })
+            .collect();
+        let reply = QueryPacket::PlayerReply { players: players.clone() }.encode();
+        assert_eq!(
+            QueryPacket::decode(&reply).unwrap(),
+            QueryPacket::PlayerReply { players: players[..255].to_vec() }
+        );
+    }
+
+    #[test]
+    fn rejects_bad_info_query_string() {
+        assert_eq!(
+            QueryPacket::decode(b"\xff\xff\xff\xffTSource Engine Quer\x00"),
+            Err(DecodeError::BadQueryString(b"Source Engine Quer".to_vec()))
+        );
+    }
+}
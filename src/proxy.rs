@@ -0,0 +1,272 @@
+// src/proxy.rs
+//
+// Works out which client a request came from when the master sits behind
+// reverse proxies. Each proxy appends the address it was connected from to
+// X-Forwarded-For, so reading the list from the right and skipping our own
+// proxies yields the first address nobody we trust vouched for. Anything to
+// the left of it was sent by the client and can't be relied on.
+use actix_web::http::header::{ HeaderMap, HeaderName };
+use ipnetwork::IpNetwork;
+use std::net::{ IpAddr, SocketAddr };
+use std::str::FromStr;
+use crate::cloudflare::verify_cloudflare_request;
+use crate::config::{ Config, ProxyMode };
+use crate::utils::{ canonical_ip, RequestError };
+
+// X-Real-IP, which the client sets itself, is left out on purpose. See `Config::cloudflare_forwarded_headers`.
+const CLOUDFLARE_HEADERS: [&str; 2] = ["CF-Connecting-IP", "X-Forwarded-For"];
+const TRUSTED_PROXY_HEADERS: [&str; 2] = ["X-Forwarded-For", "X-Real-IP"];
+
+/// How the client address of a request is determined, shared with all handlers
+/// through app data.
+pub struct ProxyPolicy {
+    mode: ProxyMode,
+    trusted_proxies: Vec<IpNetwork>,
+    // Tried in order, the first one present decides
+    headers: Vec<HeaderName>,
+}
+
+impl ProxyPolicy {
+    /// Builds the policy, failing on CIDRs or header names that don't parse.
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        let trusted_proxies = config.trusted_proxies
+            .iter()
+            .map(|cidr| IpNetwork::from_str(cidr).map_err(|e| format!("Invalid trusted proxy {}: {}", cidr, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        if config.proxy_mode == ProxyMode::Trusted && trusted_proxies.is_empty() {
+            return Err("Proxy mode trusted needs at least one TRUSTED_PROXIES entry".to_string());
+        }
+
+        // Each mode trusts its own chain of headers, a list set for another mode doesn't apply
+        let header_names: Vec<&str> = match config.proxy_mode {
+            ProxyMode::Direct => Vec::new(),
+            ProxyMode::Cloudflare => chain_or_default(&config.cloudflare_forwarded_headers, &CLOUDFLARE_HEADERS),
+            ProxyMode::Trusted => chain_or_default(&config.trusted_forwarded_headers, &TRUSTED_PROXY_HEADERS),
+        };
+        let headers = header_names
+            .into_iter()
+            .map(|name| HeaderName::from_str(name).map_err(|e| format!("Invalid forwarded header {}: {}", name, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self { mode: config.proxy_mode, trusted_proxies, headers })
+    }
+
+    /// Takes every connection at face value.
+    pub fn direct() -> Self {
+        Self { mode: ProxyMode::Direct, trusted_proxies: Vec::new(), headers: Vec::new() }
+    }
+
+    pub fn mode(&self) -> ProxyMode {
+        self.mode
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        match self.mode {
+            ProxyMode::Direct => false,
+            ProxyMode::Cloudflare => verify_cloudflare_request(ip),
+            ProxyMode::Trusted => self.trusted_proxies.iter().any(|network| network.contains(ip)),
+        }
+    }
+
+    /// Returns the address of the client behind `peer`, the address the
+    /// connection came from.
+    ///
+    /// Except in direct mode, the peer must be one of the trusted proxies and
+    /// one of the forwarding headers must be present.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> Result<IpAddr, RequestError> {
+        let peer = canonical_ip(peer);
+        if self.mode == ProxyMode::Direct {
+            return Ok(peer);
+        }
+        if !self.is_trusted(peer) {
+            return Err(RequestError::UntrustedProxy(peer.to_string()));
+        }
+
+        for name in &self.headers {
+            let mut values = headers.get_all(name).peekable();
+            if values.peek().is_none() {
+                continue;
+            }
+
+            // Repeated headers form one list, in the order they were received
+            let mut hops = Vec::new();
+            for value in values {
+                let value = value.to_str().map_err(|_| RequestError::InvalidForwardedHeader)?;
+                for hop in value.split(',') {
+                    hops.push(parse_hop(hop.trim()).ok_or(RequestError::InvalidIPFormat)?);
+                }
+            }
+
+            // If every hop is one of ours, the left-most is as close to the client as we get
+            return hops
+                .iter()
+                .rev()
+                .find(|ip| !self.is_trusted(**ip))
+                .or(hops.first())
+                .copied()
+                .ok_or(RequestError::InvalidForwardedHeader);
+        }
+
+        Err(RequestError::MissingForwardedHeader)
+    }
+}
+
+fn chain_or_default<'a>(configured: &'a [String], default: &[&'static str]) -> Vec<&'a str> {
+    if configured.is_empty() {
+        default.to_vec()
+    } else {
+        configured.iter().map(String::as_str).collect()
+    }
+}
+
+// Some proxies include the port, as in "203.0.113.7:50000" or "[2001:db8::1]:50000"
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let ip = hop
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| hop.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| hop.strip_prefix('[')?.strip_suffix(']')?.parse().ok())?;
+    Some(canonical_ip(ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::HeaderValue;
+
+    fn trusted_policy(cidrs: &[&str], headers: &[&str]) -> ProxyPolicy {
+        let config = Config {
+            proxy_mode: ProxyMode::Trusted,
+            trusted_proxies: cidrs.iter().map(|cidr| cidr.to_string()).collect(),
+            trusted_forwarded_headers: headers.iter().map(|name| name.to_string()).collect(),
+            ..Config::default()
+        };
+        ProxyPolicy::from_config(&config).unwrap()
+    }
+
+    fn headers(entries: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in entries {
+            map.append(HeaderName::from_static(name), HeaderValue::from_static(value));
+        }
+        map
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn direct_mode_ignores_forwarding_headers() {
+        let policy = ProxyPolicy::direct();
+        let forwarded = headers(&[("x-forwarded-for", "198.51.100.1")]);
+        assert_eq!(policy.client_ip(ip("203.0.113.7"), &forwarded).unwrap(), ip("203.0.113.7"));
+        assert_eq!(policy.client_ip(ip("::ffff:203.0.113.7"), &forwarded).unwrap(), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn rejects_untrusted_peers() {
+        let policy = trusted_policy(&["10.0.0.0/8"], &[]);
+        let forwarded = headers(&[("x-forwarded-for", "198.51.100.1")]);
+        assert!(matches!(
+            policy.client_ip(ip("203.0.113.7"), &forwarded),
+            Err(RequestError::UntrustedProxy(_))
+        ));
+    }
+
+    #[test]
+    fn picks_right_most_untrusted_hop() {
+        let policy = trusted_policy(&["10.0.0.0/8", "fd00::/8"], &[]);
+        // The client claims to be 192.0.2.1, the first proxy saw 198.51.100.1
+        let forwarded = headers(&[("x-forwarded-for", "192.0.2.1, 198.51.100.1, 10.0.0.2")]);
+        assert_eq!(policy.client_ip(ip("10.0.0.1"), &forwarded).unwrap(), ip("198.51.100.1"));
+
+        let forwarded = headers(&[("x-forwarded-for", "2001:db8::1, fd00::2")]);
+        assert_eq!(policy.client_ip(ip("fd00::1"), &forwarded).unwrap(), ip("2001:db8::1"));
+    }
+
+    #[test]
+    fn joins_repeated_headers_in_order() {
+        let policy = trusted_policy(&["10.0.0.0/8"], &[]);
+        let forwarded = headers(&[
+            ("x-forwarded-for", "192.0.2.1"),
+            ("x-forwarded-for", "198.51.100.1, 10.0.0.2"),
+        ]);
+        assert_eq!(policy.client_ip(ip("10.0.0.1"), &forwarded).unwrap(), ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn falls_back_to_left_most_hop_when_all_are_trusted() {
+        let policy = trusted_policy(&["10.0.0.0/8"], &[]);
+        let forwarded = headers(&[("x-forwarded-for", "10.1.1.1, 10.0.0.2")]);
+        assert_eq!(policy.client_ip(ip("10.0.0.1"), &forwarded).unwrap(), ip("10.1.1.1"));
+    }
+
+    #[test]
+    fn accepts_hops_with_ports() {
+        let policy = trusted_policy(&["10.0.0.0/8"], &[]);
+        let forwarded = headers(&[("x-forwarded-for", "[2001:db8::1]:443, 198.51.100.1:5000")]);
+        assert_eq!(policy.client_ip(ip("10.0.0.1"), &forwarded).unwrap(), ip("198.51.100.1"));
+        let forwarded = headers(&[("x-forwarded-for", "[2001:db8::1]")]);
+        assert_eq!(policy.client_ip(ip("10.0.0.1"), &forwarded).unwrap(), ip("2001:db8::1"));
+    }
+
+    #[test]
+    fn rejects_malformed_hops() {
+        let policy = trusted_policy(&["10.0.0.0/8"], &[]);
+        let forwarded = headers(&[("x-forwarded-for", "198.51.100.1, unknown")]);
+        assert!(matches!(
+            policy.client_ip(ip("10.0.0.1"), &forwarded),
+            Err(RequestError::InvalidIPFormat)
+        ));
+    }
+
+    #[test]
+    fn tries_headers_in_configured_order() {
+        let policy = trusted_policy(&["10.0.0.0/8"], &["X-Real-IP", "X-Forwarded-For"]);
+        let forwarded = headers(&[("x-forwarded-for", "198.51.100.1"), ("x-real-ip", "192.0.2.1")]);
+        assert_eq!(policy.client_ip(ip("10.0.0.1"), &forwarded).unwrap(), ip("192.0.2.1"));
+
+        let forwarded = headers(&[("x-forwarded-for", "198.51.100.1")]);
+        assert_eq!(policy.client_ip(ip("10.0.0.1"), &forwarded).unwrap(), ip("198.51.100.1"));
+
+        assert!(matches!(
+            policy.client_ip(ip("10.0.0.1"), &HeaderMap::new()),
+            Err(RequestError::MissingForwardedHeader)
+        ));
+    }
+
+    #[test]
+    fn each_mode_reads_its_own_header_chain() {
+        let config = Config {
+            proxy_mode: ProxyMode::Cloudflare,
+            cloudflare_forwarded_headers: vec!["CF-Connecting-IP".to_string()],
+            trusted_forwarded_headers: vec!["X-Real-IP".to_string()],
+            ..Config::default()
+        };
+        let names = |policy: &ProxyPolicy| policy.headers.iter().map(|name| name.as_str().to_string()).collect::<Vec<_>>();
+        assert_eq!(names(&ProxyPolicy::from_config(&config).unwrap()), ["cf-connecting-ip"]);
+
+        let config = Config { proxy_mode: ProxyMode::Trusted, trusted_proxies: vec!["10.0.0.0/8".to_string()], ..config };
+        assert_eq!(names(&ProxyPolicy::from_config(&config).unwrap()), ["x-real-ip"]);
+
+        // Unset chains fall back to the mode's defaults, direct mode reads none
+        let config = Config { trusted_forwarded_headers: Vec::new(), ..config };
+        assert_eq!(names(&ProxyPolicy::from_config(&config).unwrap()), ["x-forwarded-for", "x-real-ip"]);
+        let config = Config { proxy_mode: ProxyMode::Direct, ..config };
+        assert!(names(&ProxyPolicy::from_config(&config).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_configuration() {
+        let config = Config { proxy_mode: ProxyMode::Trusted, ..Config::default() };
+        assert!(ProxyPolicy::from_config(&config).is_err());
+
+        let config = Config {
+            proxy_mode: ProxyMode::Trusted,
+            trusted_proxies: vec!["10.0.0.0/33".to_string()],
+            ..Config::default()
+        };
+        assert!(ProxyPolicy::from_config(&config).is_err());
+    }
+}
@@ -0,0 +1,44 @@
+// src/rate_limit.rs
+//
+// One type per rate limit bucket. actix looks app data up by type, so limiters
+// of the same type would all resolve to whichever was registered last.
+use governor::{ Quota, RateLimiter, clock::DefaultClock };
+use governor::state::keyed::DefaultKeyedStateStore;
+use std::net::IpAddr;
+use std::ops::Deref;
+
+pub type KeyedRateLimiter = RateLimiter<IpAddr, DefaultKeyedStateStore<IpAddr>, DefaultClock>;
+
+macro_rules! limiter {
+    ($(#[$doc:meta])* $name:ident) => {
+        $(#[$doc])*
+        pub struct $name(KeyedRateLimiter);
+
+        impl $name {
+            pub fn new(quota: Quota) -> Self {
+                Self(RateLimiter::keyed(quota))
+            }
+        }
+
+        impl Deref for $name {
+            type Target = KeyedRateLimiter;
+
+            fn deref(&self) -> &KeyedRateLimiter {
+                &self.0
+            }
+        }
+    };
+}
+
+limiter!(
+    /// Heartbeats and verification polls from game servers.
+    HeartbeatLimiter
+);
+limiter!(
+    /// The server list and the other read-only endpoints clients poll.
+    ListLimiter
+);
+limiter!(
+    /// Servers delisting themselves.
+    DeleteLimiter
+);
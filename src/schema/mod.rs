@@ -1,3 +1,4 @@
+#[allow(dead_code)]
 pub mod server_capnp {
     include!("server_capnp.rs");
 }
@@ -0,0 +1,144 @@
+// src/session.rs
+use hmac::{ Hmac, Mac };
+use rand::Rng;
+use sha2::Sha256;
+use std::net::SocketAddr;
+use std::time::{ SystemTime, UNIX_EPOCH };
+use crate::utils::{ hex_decode, hex_encode };
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Issues and checks the short-lived session tokens handed out after a server
+/// passes the UDP challenge.
+///
+/// A token is `<expires_at>.<hex hmac>`, where the HMAC covers the server's
+/// ip:port and the expiry, so it can't be reused for another address or
+/// extended by the holder.
+pub struct SessionSigner {
+    key: Vec<u8>,
+    ttl_secs: u64,
+}
+
+impl SessionSigner {
+    pub fn new(secret: Option<&str>, ttl_secs: u64) -> Self {
+        let key = match secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            // Without a configured secret, tokens simply don't survive a restart.
+            None => rand::thread_rng().gen::<[u8; 32]>().to_vec(),
+        };
+        Self { key, ttl_secs }
+    }
+
+    pub fn issue(&self, addr: &SocketAddr) -> String {
+        let expires_at = now() + self.ttl_secs;
+        format!("{}.{}", expires_at, hex_encode(&self.sign(addr, expires_at).finalize().into_bytes()))
+    }
+
+    /// Returns true if `token` was issued for `addr` and hasn't expired yet.
+    pub fn verify(&self, token: &str, addr: &SocketAddr) -> bool {
+        let Some((expires_at, signature)) = token.split_once('.') else {
+            return false;
+        };
+        let Ok(expires_at) = expires_at.parse::<u64>() else {
+            return false;
+        };
+        let Some(signature) = hex_decode(signature) else {
+            return false;
+        };
+        if expires_at <= now() {
+            return false;
+        }
+        self.sign(addr, expires_at).verify_slice(&signature).is_ok()
+    }
+
+    fn sign(&self, addr: &SocketAddr, expires_at: u64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(addr.to_string().as_bytes());
+        mac.update(b"|");
+        mac.update(expires_at.to_string().as_bytes());
+        mac
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::new("203.0.113.7".parse().unwrap(), port)
+    }
+
+    #[test]
+    fn accepts_token_for_same_address() {
+        let signer = SessionSigner::new(Some("secret"), 300);
+        let token = signer.issue(&addr(37015));
+        assert!(signer.verify(&token, &addr(37015)));
+
+        // Tokens survive a restart when the secret is configured
+        assert!(SessionSigner::new(Some("secret"), 300).verify(&token, &addr(37015)));
+    }
+
+    #[test]
+    fn rejects_expired_token() {
+        let signer = SessionSigner::new(Some("secret"), 0);
+        let token = signer.issue(&addr(37015));
+        assert!(!signer.verify(&token, &addr(37015)));
+    }
+
+    #[test]
+    fn rejects_token_for_other_address() {
+        let signer = SessionSigner::new(Some("secret"), 300);
+        let token = signer.issue(&addr(37015));
+        assert!(!signer.verify(&token, &addr(37016)));
+        assert!(!signer.verify(&token, &SocketAddr::new("203.0.113.8".parse().unwrap(), 37015)));
+    }
+
+    #[test]
+    fn rejects_token_from_other_key() {
+        let token = SessionSigner::new(Some("secret"), 300).issue(&addr(37015));
+        assert!(!SessionSigner::new(Some("other secret"), 300).verify(&token, &addr(37015)));
+        assert!(!SessionSigner::new(None, 300).verify(&token, &addr(37015)));
+    }
+
+    #[test]
+    fn rejects_tampered_token() {
+        let signer = SessionSigner::new(Some("secret"), 300);
+        let token = signer.issue(&addr(37015));
+        let (expires_at, signature) = token.split_once('.').unwrap();
+
+        // Extending the expiry invalidates the signature
+        let extended = format!("{}.{}", expires_at.parse::<u64>().unwrap() + 3600, signature);
+        assert!(!signer.verify(&extended, &addr(37015)));
+
+        // As does flipping any part of the signature
+        let mut flipped = signature.as_bytes().to_vec();
+        flipped[0] = if flipped[0] == b'0' { b'1' } else { b'0' };
+        let flipped = format!("{}.{}", expires_at, String::from_utf8(flipped).unwrap());
+        assert!(!signer.verify(&flipped, &addr(37015)));
+
+        let truncated = format!("{}.{}", expires_at, &signature[..signature.len() - 2]);
+        assert!(!signer.verify(&truncated, &addr(37015)));
+    }
+
+    #[test]
+    fn rejects_malformed_token() {
+        let signer = SessionSigner::new(Some("secret"), 300);
+        let far_future = now() + 3600;
+        for token in [
+            String::new(),
+            "no-separator".to_string(),
+            format!("{}.", far_future),
+            format!("{}.zz", far_future),
+            format!("{}.abc", far_future),
+            format!("soon.{}", "00".repeat(32)),
+            format!("-1.{}", "00".repeat(32)),
+            format!("{}.{}", far_future, "00".repeat(32)),
+        ] {
+            assert!(!signer.verify(&token, &addr(37015)), "{:?}", token);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+// src/storage/changes.rs
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::time::{ SystemTime, UNIX_EPOCH };
+use tokio::sync::broadcast;
+use crate::storage::events::{ EventBus, ServerEvent };
+
+/// A registry change and the version it produced.
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub version: u64,
+    pub event: ServerEvent,
+}
+
+struct ChangeLog {
+    entries: VecDeque<Change>,
+    capacity: usize,
+    // Oldest version every later change is still known for
+    floor: u64,
+}
+
+/// Registry version, bounded change log and event bus shared by the storage backends.
+///
+/// Every visible change goes through `record`, so the version, the log and what
+/// subscribers see always agree.
+pub struct ChangeJournal {
+    version: AtomicU64,
+    log: Mutex<ChangeLog>,
+    events: EventBus,
+}
+
+impl ChangeJournal {
+    pub fn new(capacity: usize) -> Self {
+        // Starting from the clock keeps versions from an earlier run below the new
+        // log's floor, so clients holding one are sent back to the full list.
+        let start = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as u64;
+
+        Self {
+            version: AtomicU64::new(start),
+            log: Mutex::new(ChangeLog {
+                entries: VecDeque::with_capacity(capacity),
+                capacity,
+                floor: start,
+            }),
+            events: EventBus::new(),
+        }
+    }
+
+    /// Bumps the version, logs the change and publishes it to subscribers.
+    pub fn record(&self, event: ServerEvent) {
+        let mut log = self.log.lock();
+        let version = self.version.load(Ordering::Acquire) + 1;
+        self.version.store(version, Ordering::Release);
+
+        if log.entries.len() >= log.capacity {
+            if let Some(evicted) = log.entries.pop_front() {
+                log.floor = evicted.version;
+            }
+        }
+        if log.capacity > 0 {
+            log.entries.push_back(Change { version, event: event.clone() });
+        } else {
+            log.floor = version;
+        }
+
+        self.events.publish(event);
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Acquire)
+    }
+
+    /// Returns every change after `version`, oldest first, or `None` if some of
+    /// them have already been dropped from the log.
+    pub fn since(&self, version: u64) -> Option<Vec<Change>> {
+        let log = self.log.lock();
+        if version < log.floor || version > self.version() {
+            return None;
+        }
+        let start = log.entries.partition_point(|change| change.version <= version);
+        Some(log.entries.range(start..).cloned().collect())
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.events.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::server::ServerInfo;
+    use crate::storage::events::RemovalReason;
+
+    fn updated(i: usize) -> ServerEvent {
+        ServerEvent::Updated { server: ServerInfo::for_test(i) }
+    }
+
+    fn versions(changes: &[Change]) -> Vec<u64> {
+        changes.iter().map(|change| change.version).collect()
+    }
+
+    #[test]
+    fn returns_changes_after_version() {
+        let journal = ChangeJournal::new(8);
+        let start = journal.version();
+        for i in 0..3 {
+            journal.record(updated(i));
+        }
+        assert_eq!(journal.version(), start + 3);
+
+        assert_eq!(versions(&journal.since(start).unwrap()), [start + 1, start + 2, start + 3]);
+        assert_eq!(versions(&journal.since(start + 2).unwrap()), [start + 3]);
+        assert!(journal.since(start + 3).unwrap().is_empty());
+    }
+
+    #[test]
+    fn forgets_versions_below_the_floor_after_rollover() {
+        let journal = ChangeJournal::new(2);
+        let start = journal.version();
+        for i in 0..5 {
+            journal.record(updated(i));
+        }
+
+        // Only start+4 and start+5 are left, so the client needs everything after start+3
+        assert!(journal.since(start).is_none());
+        assert!(journal.since(start + 2).is_none());
+        assert_eq!(versions(&journal.since(start + 3).unwrap()), [start + 4, start + 5]);
+        assert_eq!(versions(&journal.since(start + 4).unwrap()), [start + 5]);
+    }
+
+    #[test]
+    fn without_capacity_only_the_current_version_is_known() {
+        let journal = ChangeJournal::new(0);
+        let start = journal.version();
+        journal.record(updated(0));
+        assert!(journal.since(start).is_none());
+        assert!(journal.since(start + 1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_future_versions() {
+        let journal = ChangeJournal::new(8);
+        journal.record(updated(0));
+        assert!(journal.since(journal.version() + 1).is_none());
+        // A version from a later run of the master
+        assert!(journal.since(u64::MAX).is_none());
+    }
+
+    #[test]
+    fn keeps_update_and_removal_in_the_same_window() {
+        let journal = ChangeJournal::new(8);
+        let start = journal.version();
+        journal.record(updated(0));
+        journal.record(ServerEvent::Removed { server: ServerInfo::for_test(0), reason: RemovalReason::Deleted });
+
+        let changes = journal.since(start).unwrap();
+        assert!(matches!(&changes[0].event, ServerEvent::Updated { server } if server.id == "server-0"));
+        assert!(matches!(&changes[1].event, ServerEvent::Removed { server, .. } if server.id == "server-0"));
+    }
+
+    #[test]
+    fn publishes_recorded_changes() {
+        let journal = ChangeJournal::new(8);
+        let mut events = journal.subscribe();
+        journal.record(updated(0));
+        assert!(matches!(events.try_recv().unwrap(), ServerEvent::Updated { server } if server.id == "server-0"));
+    }
+}
@@ -0,0 +1,56 @@
+// src/storage/events.rs
+use serde::Serialize;
+use tokio::sync::broadcast;
+use crate::models::server::ServerInfo;
+
+// Events are dropped for subscribers that fall this far behind.
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RemovalReason {
+    /// The server stopped sending heartbeats and was reaped.
+    Expired,
+    /// The server asked to be delisted.
+    Deleted,
+    /// The prober found the server's heartbeats didn't match its query answers.
+    Delisted,
+}
+
+/// Registry change notifications published by the storage backends.
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    /// A server sent its first heartbeat.
+    Added { server: ServerInfo },
+    /// Something shown in the server list changed. Heartbeats that only refresh
+    /// `last_heartbeat` aren't published.
+    Updated { server: ServerInfo },
+    Removed { server: ServerInfo, reason: RemovalReason },
+}
+
+/// Broadcast channel shared by a storage backend and its subscribers.
+pub struct EventBus {
+    sender: broadcast::Sender<ServerEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        Self { sender }
+    }
+
+    pub fn publish(&self, event: ServerEvent) {
+        // Sending only fails when nobody is listening, which is fine.
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
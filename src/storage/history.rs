@@ -0,0 +1,470 @@
+// src/storage/history.rs
+use log::{ error, warn };
+use parking_lot::Mutex;
+use serde::Deserialize;
+use std::collections::{ HashMap, VecDeque };
+use std::net::{ IpAddr, SocketAddr };
+use std::sync::Arc;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use tokio::sync::broadcast::error::RecvError;
+use crate::config::Config;
+use crate::models::server::ServerInfo;
+use crate::storage::ServerStore;
+use crate::storage::events::ServerEvent;
+
+/// Width of the buckets a series is downsampled into.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Resolution {
+    #[serde(rename = "1m")]
+    Minute,
+    #[default]
+    #[serde(rename = "1h")]
+    Hour,
+    #[serde(rename = "1d")]
+    Day,
+}
+
+impl Resolution {
+    const ALL: [Resolution; 3] = [Self::Minute, Self::Hour, Self::Day];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Minute => "1m",
+            Self::Hour => "1h",
+            Self::Day => "1d",
+        }
+    }
+
+    pub fn secs(&self) -> u64 {
+        match self {
+            Self::Minute => 60,
+            Self::Hour => 60 * 60,
+            Self::Day => 24 * 60 * 60,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// What a series counts the players of.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SeriesKey {
+    Global,
+    Map(String),
+    /// A server by its ip:port, so its history carries on when it expires and
+    /// registers again under a new ID.
+    Server(String),
+}
+
+impl SeriesKey {
+    pub fn server(server: &ServerInfo) -> Self {
+        match server.ip.parse::<IpAddr>() {
+            Ok(ip) => Self::Server(SocketAddr::new(ip, server.port as u16).to_string()),
+            Err(_) => Self::Server(format!("{}:{}", server.ip, server.port)),
+        }
+    }
+}
+
+/// Player counts sampled during one interval.
+#[derive(Debug, Clone, Copy)]
+pub struct Bucket {
+    /// Unix timestamp the interval starts at.
+    pub start: u64,
+    pub min: u32,
+    pub max: u32,
+    sum: u64,
+    samples: u32,
+}
+
+impl Bucket {
+    fn new(start: u64, count: u32) -> Self {
+        Self { start, min: count, max: count, sum: count as u64, samples: 1 }
+    }
+
+    fn add(&mut self, count: u32) {
+        self.min = self.min.min(count);
+        self.max = self.max.max(count);
+        self.sum += count as u64;
+        self.samples += 1;
+    }
+
+    pub fn avg(&self) -> f64 {
+        self.sum as f64 / self.samples as f64
+    }
+}
+
+#[derive(Default)]
+struct Series {
+    // One ring of buckets per resolution, oldest first
+    rings: [VecDeque<Bucket>; 3],
+    last_sampled: u64,
+}
+
+impl Series {
+    fn record(&mut self, count: u32, now: u64, retention: &[usize; 3]) {
+        for resolution in Resolution::ALL {
+            let ring = &mut self.rings[resolution.index()];
+            let start = now - now % resolution.secs();
+            match ring.back_mut() {
+                // A clock step backwards lands in the newest bucket rather than reordering the ring
+                Some(bucket) if bucket.start >= start => bucket.add(count),
+                _ => ring.push_back(Bucket::new(start, count)),
+            }
+        }
+        self.last_sampled = now;
+        self.trim(now, retention);
+    }
+
+    /// Drops buckets that fell out of the retention window.
+    fn trim(&mut self, now: u64, retention: &[usize; 3]) {
+        for resolution in Resolution::ALL {
+            let secs = resolution.secs();
+            let span = secs.saturating_mul(retention[resolution.index()].max(1) as u64 - 1);
+            let oldest = (now - now % secs).saturating_sub(span);
+            let ring = &mut self.rings[resolution.index()];
+            while ring.front().is_some_and(|bucket| bucket.start < oldest) {
+                ring.pop_front();
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.rings.iter().all(|ring| ring.is_empty())
+    }
+}
+
+// Last count reported by a server, so the map and global totals can be kept up to date
+struct LiveServer {
+    key: SeriesKey,
+    map_name: String,
+    players: u32,
+}
+
+#[derive(Default)]
+struct HistoryState {
+    series: HashMap<SeriesKey, Series>,
+    live: HashMap<String, LiveServer>,
+    global_players: u32,
+    map_players: HashMap<String, u32>,
+    max_maps: usize,
+}
+
+impl HistoryState {
+    fn sample(&mut self, key: SeriesKey, count: u32, now: u64, retention: &[usize; 3]) {
+        if matches!(key, SeriesKey::Map(_)) && !self.series.contains_key(&key) {
+            self.make_room_for_map();
+        }
+        self.series.entry(key).or_default().record(count, now, retention);
+    }
+
+    /// Drops the least recently sampled map series once there are `max_maps` of
+    /// them, since servers can report any map name they like.
+    fn make_room_for_map(&mut self) {
+        let maps = self.series.iter().filter(|(key, _)| matches!(key, SeriesKey::Map(_)));
+        if maps.clone().count() < self.max_maps.max(1) {
+            return;
+        }
+        let oldest = maps.min_by_key(|(_, series)| series.last_sampled).map(|(key, _)| key.clone());
+        if let Some(oldest) = oldest {
+            self.series.remove(&oldest);
+        }
+    }
+
+    fn forget(&mut self, server: &LiveServer) {
+        self.global_players = self.global_players.saturating_sub(server.players);
+        if let Some(players) = self.map_players.get_mut(&server.map_name) {
+            *players = players.saturating_sub(server.players);
+            if *players == 0 {
+                self.map_players.remove(&server.map_name);
+            }
+        }
+    }
+
+    /// Samples the map a server left, so its series shows the drop.
+    fn sample_left_map(&mut self, map_name: String, now: u64, retention: &[usize; 3]) {
+        let remaining = self.map_players.get(&map_name).copied().unwrap_or(0);
+        self.sample(SeriesKey::Map(map_name), remaining, now, retention);
+    }
+}
+
+/// Player counts over time, per server, per map and across all servers.
+///
+/// Fed from the registry's change events by `spawn_recorder`: every added,
+/// updated or removed server samples its own series, its map's and the global
+/// one, and all series of listed servers are sampled once a minute in between,
+/// so quiet servers still show up. Each resolution keeps a bounded number of
+/// buckets, and series nobody has reported in that long are dropped, so
+/// servers that are gone for good only linger for the longest retention window.
+/// Map series are also capped in number, as map names come from the servers.
+pub struct PlayerHistory {
+    state: Mutex<HistoryState>,
+    // Buckets kept per resolution, indexed like `Resolution::ALL`
+    retention: [usize; 3],
+}
+
+impl PlayerHistory {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            state: Mutex::new(HistoryState { max_maps: config.history_max_maps, ..HistoryState::default() }),
+            retention: [
+                config.history_minute_buckets,
+                config.history_hour_buckets,
+                config.history_day_buckets,
+            ],
+        }
+    }
+
+    /// Records the player count of a listed server, along with the totals of
+    /// its map and of all servers at that moment.
+    pub fn record(&self, server: &ServerInfo, now: u64) {
+        let mut state = self.state.lock();
+
+        let players = server.players.len() as u32;
+        let key = SeriesKey::server(server);
+        let previous = state.live.insert(
+            server.id.clone(),
+            LiveServer { key: key.clone(), map_name: server.map_name.clone(), players },
+        );
+        let mut left_map = None;
+        if let Some(previous) = previous {
+            state.forget(&previous);
+            if previous.map_name != server.map_name {
+                left_map = Some(previous.map_name);
+            }
+        }
+        state.global_players += players;
+        *state.map_players.entry(server.map_name.clone()).or_default() += players;
+
+        let map_players = state.map_players[&server.map_name];
+        let global_players = state.global_players;
+        state.sample(key, players, now, &self.retention);
+        state.sample(SeriesKey::Map(server.map_name.clone()), map_players, now, &self.retention);
+        if let Some(map_name) = left_map {
+            state.sample_left_map(map_name, now, &self.retention);
+        }
+        state.sample(SeriesKey::Global, global_players, now, &self.retention);
+    }
+
+    /// Takes a server that left the list out of the map and global totals.
+    pub fn remove(&self, server_id: &str, now: u64) {
+        let mut state = self.state.lock();
+        let Some(server) = state.live.remove(server_id) else {
+            return;
+        };
+        state.forget(&server);
+        state.sample_left_map(server.map_name, now, &self.retention);
+        let global_players = state.global_players;
+        state.sample(SeriesKey::Global, global_players, now, &self.retention);
+    }
+
+    /// Replaces what is known about the listed servers, for when events were missed.
+    pub fn resync(&self, servers: &[ServerInfo], now: u64) {
+        {
+            let mut state = self.state.lock();
+            state.live.clear();
+            state.map_players.clear();
+            state.global_players = 0;
+        }
+        for server in servers {
+            self.record(server, now);
+        }
+    }
+
+    /// Samples every series of a listed server and drops buckets and series
+    /// that fell out of the retention window.
+    pub fn sample_all(&self, now: u64) {
+        let mut state = self.state.lock();
+        let counts: Vec<(SeriesKey, u32)> = state.live
+            .values()
+            .map(|server| (server.key.clone(), server.players))
+            .chain(state.map_players.iter().map(|(map_name, players)| (SeriesKey::Map(map_name.clone()), *players)))
+            .chain(std::iter::once((SeriesKey::Global, state.global_players)))
+            .collect();
+        for (key, count) in counts {
+            state.sample(key, count, now, &self.retention);
+        }
+
+        for series in state.series.values_mut() {
+            series.trim(now, &self.retention);
+        }
+        state.series.retain(|key, series| *key == SeriesKey::Global || !series.is_empty());
+    }
+
+    /// Returns the buckets of a series that start within `from..=to`, oldest first.
+    pub fn points(&self, key: &SeriesKey, resolution: Resolution, from: u64, to: u64) -> Vec<Bucket> {
+        let state = self.state.lock();
+        let Some(series) = state.series.get(key) else {
+            return Vec::new();
+        };
+        series.rings[resolution.index()]
+            .iter()
+            .filter(|bucket| bucket.start >= from && bucket.start <= to)
+            .copied()
+            .collect()
+    }
+}
+
+/// Keeps `history` up to date with the registry, whichever path servers are
+/// added, updated or removed through.
+pub fn spawn_recorder(history: Arc<PlayerHistory>, storage: Arc<dyn ServerStore>) {
+    // Subscribe before loading the servers, so nothing falls in between
+    let mut events = storage.subscribe();
+    history.resync(&storage.get_servers(), unix_now());
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(ServerEvent::Added { server }) | Ok(ServerEvent::Updated { server }) => {
+                        history.record(&server, unix_now());
+                    }
+                    Ok(ServerEvent::Removed { server, .. }) => history.remove(&server.id, unix_now()),
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Player history missed {} registry events, resyncing", skipped);
+                        let storage = storage.clone();
+                        match tokio::task::spawn_blocking(move || storage.get_servers()).await {
+                            Ok(servers) => history.resync(&servers, unix_now()),
+                            Err(e) => error!("Failed to load servers for player history: {}", e),
+                        }
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                _ = ticker.tick() => history.sample_all(unix_now()),
+            }
+        }
+    });
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::events::RemovalReason;
+    use crate::storage::memory::ServerStorage;
+
+    fn server(i: usize, map: &str, players: usize) -> ServerInfo {
+        ServerInfo { map_name: map.to_string(), ..ServerInfo::with_players(i, &vec!["Pilot"; players]) }
+    }
+
+    // Max of the newest minute bucket, which is the latest count sampled
+    fn latest(history: &PlayerHistory, key: SeriesKey) -> Option<u32> {
+        history.points(&key, Resolution::Minute, 0, u64::MAX).last().map(|bucket| bucket.max)
+    }
+
+    #[test]
+    fn removal_leaves_totals() {
+        let history = PlayerHistory::new(&Config::default());
+        history.record(&server(0, "mp_lobby", 3), 600);
+        history.record(&server(1, "mp_lobby", 2), 660);
+        assert_eq!(latest(&history, SeriesKey::Global), Some(5));
+
+        history.remove("server-0", 720);
+        assert_eq!(latest(&history, SeriesKey::Global), Some(2));
+        assert_eq!(latest(&history, SeriesKey::Map("mp_lobby".to_string())), Some(2));
+
+        history.remove("server-1", 780);
+        assert_eq!(latest(&history, SeriesKey::Global), Some(0));
+        assert_eq!(latest(&history, SeriesKey::Map("mp_lobby".to_string())), Some(0));
+    }
+
+    #[test]
+    fn map_change_moves_players() {
+        let history = PlayerHistory::new(&Config::default());
+        history.record(&server(0, "mp_lobby", 3), 600);
+        history.record(&server(0, "mp_angel_city", 3), 660);
+        assert_eq!(latest(&history, SeriesKey::Map("mp_lobby".to_string())), Some(0));
+        assert_eq!(latest(&history, SeriesKey::Map("mp_angel_city".to_string())), Some(3));
+        assert_eq!(latest(&history, SeriesKey::Global), Some(3));
+    }
+
+    #[test]
+    fn quiet_servers_are_sampled_every_minute() {
+        let history = PlayerHistory::new(&Config::default());
+        history.record(&server(0, "mp_lobby", 3), 600);
+        history.sample_all(660);
+        history.sample_all(720);
+
+        let points = history.points(&SeriesKey::server(&server(0, "mp_lobby", 3)), Resolution::Minute, 0, u64::MAX);
+        assert_eq!(points.iter().map(|bucket| bucket.start).collect::<Vec<_>>(), [600, 660, 720]);
+        assert!(points.iter().all(|bucket| bucket.min == 3 && bucket.max == 3));
+    }
+
+    #[test]
+    fn map_series_are_capped() {
+        let history = PlayerHistory::new(&Config { history_max_maps: 2, ..Config::default() });
+        history.record(&server(0, "mp_lobby", 3), 600);
+        history.record(&server(1, "mp_box", 2), 660);
+        history.record(&server(2, "mp_angel_city", 1), 720);
+
+        // The map sampled longest ago makes room, server series aren't affected
+        let state = history.state.lock();
+        assert!(!state.series.contains_key(&SeriesKey::Map("mp_lobby".to_string())));
+        assert!(state.series.contains_key(&SeriesKey::Map("mp_box".to_string())));
+        assert!(state.series.contains_key(&SeriesKey::Map("mp_angel_city".to_string())));
+        assert!(state.series.contains_key(&SeriesKey::server(&server(0, "mp_lobby", 3))));
+    }
+
+    #[tokio::test]
+    async fn recorder_follows_registry_events() {
+        let history = Arc::new(PlayerHistory::new(&Config::default()));
+        let storage: Arc<dyn ServerStore> = Arc::new(ServerStorage::new(Config::default()));
+        storage.add_server(server(0, "mp_lobby", 3)).unwrap();
+
+        spawn_recorder(history.clone(), storage.clone());
+        assert_eq!(history.state.lock().global_players, 3);
+
+        storage.add_server(server(1, "mp_lobby", 2)).unwrap();
+        storage.remove_server("server-0", RemovalReason::Delisted);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // Samples within the same minute share a bucket, so check the running totals
+        let state = history.state.lock();
+        assert_eq!(state.global_players, 2);
+        assert_eq!(state.map_players["mp_lobby"], 2);
+        assert!(!state.live.contains_key("server-0"));
+        assert!(state.series.contains_key(&SeriesKey::server(&server(1, "mp_lobby", 2))));
+    }
+
+    #[test]
+    fn reregistered_server_continues_its_series() {
+        let history = PlayerHistory::new(&Config::default());
+        let first = server(0, "mp_lobby", 3);
+        history.record(&first, 600);
+        history.remove(&first.id, 660);
+
+        // Expired and back under a new ID at the same address
+        let again = ServerInfo { id: "server-0-again".to_string(), ..server(0, "mp_lobby", 4) };
+        history.record(&again, 720);
+
+        let key = SeriesKey::server(&again);
+        assert_eq!(key, SeriesKey::Server("10.0.0.0:37015".to_string()));
+        let points = history.points(&key, Resolution::Minute, 0, u64::MAX);
+        assert_eq!(points.iter().map(|bucket| (bucket.start, bucket.max)).collect::<Vec<_>>(), [(600, 3), (720, 4)]);
+        assert_eq!(history.state.lock().series.keys().filter(|key| matches!(key, SeriesKey::Server(_))).count(), 1);
+    }
+
+    #[test]
+    fn gone_servers_are_dropped_after_retention() {
+        let config = Config { history_minute_buckets: 5, history_hour_buckets: 2, history_day_buckets: 2, ..Config::default() };
+        let history = PlayerHistory::new(&config);
+        let gone = server(0, "mp_lobby", 3);
+        history.record(&gone, 600);
+        history.remove(&gone.id, 660);
+
+        let day = Resolution::Day.secs();
+        history.sample_all(day);
+        assert!(history.state.lock().series.contains_key(&SeriesKey::server(&gone)));
+
+        // Two daily buckets reach back to the start of yesterday
+        history.sample_all(2 * day);
+        let state = history.state.lock();
+        assert!(!state.series.contains_key(&SeriesKey::server(&gone)));
+        assert!(!state.series.contains_key(&SeriesKey::Map("mp_lobby".to_string())));
+        assert!(state.series.contains_key(&SeriesKey::Global));
+    }
+}
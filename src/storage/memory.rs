@@ -1,11 +1,28 @@
 // src/storage/memory.rs
 use dashmap::DashMap;
+use parking_lot::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 use crate::models::server::ServerInfo;
 use crate::config::Config;
+use crate::storage::ServerStore;
+use crate::storage::changes::{ Change, ChangeJournal };
+use crate::storage::players::{ PlayerIndex, PlayerMatch };
+use crate::storage::reliability::{ ReliabilityReport, ReliabilityTracker };
+use crate::storage::events::{ RemovalReason, ServerEvent };
+use crate::utils::address_block;
+use tokio::sync::broadcast;
 
 pub struct ServerStorage {
     servers: DashMap<String, ServerInfo>,
+    // ip:port -> server id
+    addr_index: DashMap<(String, i32), String>,
+    // address block (see `address_block`) -> number of servers registered from it
+    ip_counts: DashMap<String, usize>,
+    // Serializes writers so the indexes never drift from `servers`. Readers don't take it.
+    write_lock: Mutex<()>,
+    changes: ChangeJournal,
+    players: PlayerIndex,
+    reliability: ReliabilityTracker,
     config: Config,
 }
 
@@ -13,51 +30,195 @@ impl ServerStorage {
     pub fn new(config: Config) -> Self {
         Self {
             servers: DashMap::new(),
+            addr_index: DashMap::new(),
+            ip_counts: DashMap::new(),
+            write_lock: Mutex::new(()),
+            changes: ChangeJournal::new(config.change_log_capacity),
+            players: PlayerIndex::default(),
+            reliability: ReliabilityTracker::new(&config),
             config,
         }
     }
 
-    pub fn add_server(&self, server_info: ServerInfo) -> Result<(), String> {
-        // Check if a server with the same IP and port already exists.
-        let existing_server_id = self.servers
-            .iter()
-            .find(|r| r.value().ip == server_info.ip && r.value().port == server_info.port)
-            .map(|r| r.key().clone());
+    /// Records a change and applies it to the player index.
+    fn record(&self, event: ServerEvent) {
+        self.players.apply(&event);
+        self.changes.record(event);
+    }
+
+    /// Removes a server and its index entries. Callers must hold `write_lock`.
+    fn remove_locked(&self, id: &str) -> Option<ServerInfo> {
+        let (_, server) = self.servers.remove(id)?;
+
+        self.addr_index.remove_if(&(server.ip.clone(), server.port), |_, indexed_id| indexed_id == id);
+        self.addr_index.remove_if(&(server.alt_ip.clone(), server.port), |_, indexed_id| indexed_id == id);
+        let block = address_block(&server.ip);
+        if let Some(mut count) = self.ip_counts.get_mut(&block) {
+            *count = count.saturating_sub(1);
+        }
+        self.ip_counts.remove_if(&block, |_, count| *count == 0);
+
+        Some(server)
+    }
+
+    /// Drops the heartbeat's claim on its alternate address when a server other
+    /// than `id` is registered there. Callers must hold `write_lock`.
+    fn drop_contested_alt_locked(&self, heartbeat: &mut ServerInfo, id: &str) {
+        if heartbeat.alt_ip.is_empty() {
+            return;
+        }
+        let alt_addr = (heartbeat.alt_ip.clone(), heartbeat.port);
+        if self.addr_index.get(&alt_addr).is_some_and(|holder| *holder != id) {
+            heartbeat.alt_ip.clear();
+        }
+    }
+
+    /// Points the server's alternate address at it. Callers must hold `write_lock`
+    /// and have dropped a contested claim first.
+    fn index_alt_locked(&self, server: &ServerInfo) {
+        if !server.alt_ip.is_empty() {
+            self.addr_index.insert((server.alt_ip.clone(), server.port), server.id.clone());
+        }
+    }
+}
+
+impl ServerStore for ServerStorage {
+    fn add_server(&self, mut server_info: ServerInfo) -> Result<ServerInfo, String> {
+        let _guard = self.write_lock.lock();
+        let addr = (server_info.ip.clone(), server_info.port);
+        let alt_addr = (server_info.alt_ip.clone(), server_info.port);
+
+        // Check if a server with the same IP and port already exists. Dual-stack
+        // servers may come in over either of their addresses.
+        let existing_server_id = self.addr_index
+            .get(&addr)
+            .or_else(|| self.addr_index.get(&alt_addr).filter(|_| !server_info.alt_ip.is_empty()))
+            .map(|r| r.value().clone());
 
         if let Some(id) = existing_server_id {
-           self.servers.remove(&id);
-        } else {
-            // Check number of servers from this IP
-            let server_count = self.servers
-                .iter()
-                .filter(|r| r.value().ip == server_info.ip)
-                .count();
-            
-            if server_count >= self.config.max_servers_per_ip {
-                return Err(format!("Maximum number of servers ({}) reached for this IP", self.config.max_servers_per_ip));
+            // A server already registered at the claimed alternate address keeps it
+            self.drop_contested_alt_locked(&mut server_info, &id);
+            if let Some(mut existing) = self.servers.get_mut(&id) {
+                server_info.reliability =
+                    self.reliability.heartbeat(&existing.ip, existing.port, server_info.last_heartbeat);
+                let previous_alt_ip = existing.alt_ip.clone();
+                let changed = existing.update_from(server_info);
+                let updated = existing.clone();
+                drop(existing);
+
+                if updated.alt_ip != previous_alt_ip {
+                    self.addr_index.remove_if(&(previous_alt_ip, updated.port), |_, indexed_id| *indexed_id == id);
+                    self.index_alt_locked(&updated);
+                }
+                if changed {
+                    self.record(ServerEvent::Updated { server: updated.clone() });
+                }
+                return Ok(updated);
             }
         }
-        
-        self.servers.insert(server_info.id.clone(), server_info);
-        Ok(())
+        server_info.reliability = self.reliability.heartbeat(&server_info.ip, server_info.port, server_info.last_heartbeat);
+
+        // Check number of servers from this IP, or this /64 for IPv6
+        let block = address_block(&server_info.ip);
+        let server_count = self.ip_counts.get(&block).map(|r| *r.value()).unwrap_or(0);
+
+        if server_count >= self.config.max_servers_per_ip {
+            return Err(format!("Maximum number of servers ({}) reached for this IP", self.config.max_servers_per_ip));
+        }
+
+        // An entry with the same ID at a different address would leave a dangling index entry
+        if let Some(moved) = self.remove_locked(&server_info.id) {
+            self.record(ServerEvent::Removed { server: moved, reason: RemovalReason::Deleted });
+        }
+
+        *self.ip_counts.entry(block).or_insert(0) += 1;
+        self.addr_index.insert(addr, server_info.id.clone());
+        self.index_alt_locked(&server_info);
+        self.servers.insert(server_info.id.clone(), server_info.clone());
+        self.record(ServerEvent::Added { server: server_info.clone() });
+        Ok(server_info)
     }
 
-    pub fn cleanup_stale_servers(&self) {
+    fn cleanup_stale_servers(&self) -> Vec<ServerInfo> {
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_secs();
-            
-        self.servers.retain(|_, server| {
-            now - server.last_heartbeat < self.config.server_timeout_secs
-        });
+
+        let _guard = self.write_lock.lock();
+        let stale: Vec<String> = self.servers
+            .iter()
+            .filter(|r| now.saturating_sub(r.value().last_heartbeat) >= self.config.server_timeout_secs)
+            .map(|r| r.key().clone())
+            .collect();
+
+        let removed: Vec<ServerInfo> = stale.iter().filter_map(|id| self.remove_locked(id)).collect();
+        for server in &removed {
+            self.reliability.expired(&server.ip, server.port, now);
+            self.record(ServerEvent::Removed { server: server.clone(), reason: RemovalReason::Expired });
+        }
+        self.reliability.prune(now);
+        removed
     }
 
-    pub fn get_servers(&self) -> Vec<ServerInfo> {
+    fn get_server(&self, id: &str) -> Option<ServerInfo> {
+        self.servers.get(id).map(|r| r.value().clone())
+    }
+
+    fn get_servers(&self) -> Vec<ServerInfo> {
         self.servers.iter().map(|r| r.value().clone()).collect()
     }
 
-    pub fn remove_server(&self, id: &str) {
-        self.servers.remove(id);
+    fn remove_server(&self, id: &str, reason: RemovalReason) {
+        let _guard = self.write_lock.lock();
+        if let Some(server) = self.remove_locked(id) {
+            self.record(ServerEvent::Removed { server, reason });
+        }
+    }
+
+    fn find_server(&self, ip: &str, port: i32) -> Option<ServerInfo> {
+        let id = self.addr_index.get(&(ip.to_string(), port)).map(|r| r.value().clone())?;
+        self.servers.get(&id).map(|r| r.value().clone())
+    }
+
+    fn set_flagged(&self, id: &str, flagged: bool) -> bool {
+        let _guard = self.write_lock.lock();
+        let Some(mut server) = self.servers.get_mut(id) else {
+            return false;
+        };
+        if server.flagged == flagged {
+            return true;
+        }
+        server.flagged = flagged;
+        let updated = server.clone();
+        drop(server);
+
+        self.record(ServerEvent::Updated { server: updated });
+        true
+    }
+
+    fn search_players(&self, name: &str, limit: usize) -> Vec<PlayerMatch> {
+        self.players.search(name, limit, |id| self.get_server(id))
+    }
+
+    fn record_failed_challenge(&self, ip: &str, port: i32) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        self.reliability.challenge_failed(ip, port, now);
+    }
+
+    fn reliability(&self, ip: &str, port: i32) -> Option<ReliabilityReport> {
+        self.reliability.report(ip, port)
+    }
+
+    fn version(&self) -> u64 {
+        self.changes.version()
+    }
+
+    fn changes_since(&self, version: u64) -> Option<Vec<Change>> {
+        self.changes.since(version)
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.changes.subscribe()
     }
 }
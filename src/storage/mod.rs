@@ -1 +1,350 @@
 pub mod memory;
+pub mod sqlite;
+pub mod snapshot;
+pub mod events;
+pub mod changes;
+pub mod players;
+pub mod history;
+pub mod reliability;
+pub mod reaper;
+
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use crate::models::server::ServerInfo;
+use crate::config::{ Config, StorageBackend };
+use crate::storage::changes::Change;
+use crate::storage::players::PlayerMatch;
+use crate::storage::reliability::ReliabilityReport;
+use crate::storage::events::{ RemovalReason, ServerEvent };
+
+/// Backend-agnostic interface to the server registry.
+///
+/// Handlers only ever see a `web::Data<dyn ServerStore>`, so new backends can be
+/// added here without touching the request handling code.
+pub trait ServerStore: Send + Sync {
+    /// Registers a server and returns the stored entry.
+    ///
+    /// If a server is already registered at the same ip:port it is updated in
+    /// place: its ID, token and `first_seen` are kept and everything else is
+    /// taken from `server_info`. An entry without a token takes the one from
+    /// `server_info`. Fails when a new server would exceed
+    /// `max_servers_per_ip`, which IPv6 servers share per /64.
+    ///
+    /// The heartbeat counts towards the address's uptime, and `reliability` is
+    /// set to its current score once that moved far enough to be worth a new
+    /// version.
+    fn add_server(&self, server_info: ServerInfo) -> Result<ServerInfo, String>;
+
+    /// Removes the server with the given ID, if present, publishing `reason`
+    /// to subscribers.
+    fn remove_server(&self, id: &str, reason: RemovalReason);
+
+    /// Returns the server with the given ID, if it's still registered.
+    fn get_server(&self, id: &str) -> Option<ServerInfo>;
+
+    /// Returns a snapshot of every registered server.
+    fn get_servers(&self) -> Vec<ServerInfo>;
+
+    /// Drops every server whose last heartbeat is older than `server_timeout_secs`
+    /// and returns the servers that were removed.
+    fn cleanup_stale_servers(&self) -> Vec<ServerInfo>;
+
+    /// Looks up a server by its advertised ip:port.
+    fn find_server(&self, ip: &str, port: i32) -> Option<ServerInfo>;
+
+    /// Marks or clears a server as misreporting its state. Returns false if the
+    /// server isn't registered.
+    fn set_flagged(&self, id: &str, flagged: bool) -> bool;
+
+    /// Finds up to `limit` players whose name starts with or contains `name`,
+    /// ignoring case, prefix matches first. Servers with `hide_players` set are
+    /// never searched.
+    fn search_players(&self, name: &str, limit: usize) -> Vec<PlayerMatch>;
+
+    /// Counts a failed challenge against the reliability of the server at ip:port,
+    /// if a heartbeat from there was ever accepted.
+    fn record_failed_challenge(&self, ip: &str, port: i32);
+
+    /// Returns the uptime and incident history behind the reliability score of
+    /// the server at ip:port, if it has been seen.
+    fn reliability(&self, ip: &str, port: i32) -> Option<ReliabilityReport>;
+
+    /// Counter that goes up whenever the server list as clients see it changes.
+    /// Heartbeats that only refresh `last_heartbeat` leave it alone.
+    fn version(&self) -> u64;
+
+    /// Returns the changes made after `version`, oldest first, or `None` if the
+    /// bounded change log no longer reaches back that far.
+    fn changes_since(&self, version: u64) -> Option<Vec<Change>>;
+
+    /// Subscribes to registry change events.
+    fn subscribe(&self) -> broadcast::Receiver<ServerEvent>;
+}
+
+/// Builds the storage backend selected by `Config::storage_backend`.
+pub fn from_config(config: &Config) -> Result<Arc<dyn ServerStore>, String> {
+    match config.storage_backend {
+        StorageBackend::Memory => Ok(Arc::new(memory::ServerStorage::new(config.clone()))),
+        StorageBackend::Sqlite => {
+            let storage = sqlite::SqliteStorage::open(&config.sqlite_path, config.clone())?;
+            Ok(Arc::new(storage))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every contract test runs against each backend
+    fn backends(config: Config) -> Vec<(&'static str, Box<dyn ServerStore>)> {
+        vec![
+            ("memory", Box::new(memory::ServerStorage::new(config.clone()))),
+            ("sqlite", Box::new(sqlite::SqliteStorage::open(":memory:", config).unwrap())),
+        ]
+    }
+
+    fn temp_db_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("r1ms-test-{}.db", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn registers_and_looks_up_servers() {
+        for (backend, storage) in backends(Config::default()) {
+            let server = ServerInfo::for_test(0);
+            let stored = storage.add_server(server.clone()).unwrap();
+            assert_eq!(stored.id, server.id, "{}", backend);
+
+            assert_eq!(storage.get_server(&server.id).unwrap().host_name, server.host_name, "{}", backend);
+            assert_eq!(storage.find_server(&server.ip, server.port).unwrap().id, server.id, "{}", backend);
+            assert!(storage.find_server(&server.ip, server.port + 1).is_none(), "{}", backend);
+            assert!(storage.get_server("missing").is_none(), "{}", backend);
+            assert_eq!(storage.get_servers().len(), 1, "{}", backend);
+        }
+    }
+
+    #[test]
+    fn heartbeat_updates_entry_in_place() {
+        for (backend, storage) in backends(Config::default()) {
+            let first = storage.add_server(ServerInfo { token: "secret".to_string(), ..ServerInfo::for_test(0) }).unwrap();
+
+            let heartbeat = ServerInfo {
+                id: "new-id".to_string(),
+                token: "new-token".to_string(),
+                first_seen: first.first_seen + 60,
+                map_name: "mp_angel_city".to_string(),
+                ..ServerInfo::for_test(0)
+            };
+            let updated = storage.add_server(heartbeat).unwrap();
+
+            assert_eq!(updated.id, first.id, "{}", backend);
+            assert_eq!(updated.token, "secret", "{}", backend);
+            assert_eq!(updated.first_seen, first.first_seen, "{}", backend);
+            assert_eq!(updated.map_name, "mp_angel_city", "{}", backend);
+            assert_eq!(storage.get_servers().len(), 1, "{}", backend);
+            assert_eq!(storage.get_server(&first.id).unwrap().map_name, "mp_angel_city", "{}", backend);
+        }
+    }
+
+    #[test]
+    fn enforces_servers_per_ip() {
+        let config = Config { max_servers_per_ip: 2, ..Config::default() };
+        for (backend, storage) in backends(config) {
+            // for_test puts servers 0 to 7 on the same IP
+            storage.add_server(ServerInfo::for_test(0)).unwrap();
+            storage.add_server(ServerInfo::for_test(1)).unwrap();
+            assert!(storage.add_server(ServerInfo::for_test(2)).is_err(), "{}", backend);
+
+            // Refreshing a registered server doesn't count as another one
+            assert!(storage.add_server(ServerInfo::for_test(1)).is_ok(), "{}", backend);
+            assert!(storage.add_server(ServerInfo::for_test(8)).is_ok(), "{}", backend);
+
+            // A removed server frees its slot
+            storage.remove_server("server-0", RemovalReason::Deleted);
+            assert!(storage.add_server(ServerInfo::for_test(2)).is_ok(), "{}", backend);
+        }
+    }
+
+    #[test]
+    fn counts_v6_servers_per_prefix() {
+        let config = Config { max_servers_per_ip: 2, ..Config::default() };
+        for (backend, storage) in backends(config) {
+            let on = |i: usize, ip: &str| ServerInfo { ip: ip.to_string(), ..ServerInfo::for_test(i) };
+            storage.add_server(on(0, "2001:db8:1:2::1")).unwrap();
+            storage.add_server(on(1, "2001:db8:1:2::2")).unwrap();
+            assert!(storage.add_server(on(2, "2001:db8:1:2:ffff::3")).is_err(), "{}", backend);
+            assert!(storage.add_server(on(3, "2001:db8:1:3::1")).is_ok(), "{}", backend);
+
+            storage.remove_server("server-0", RemovalReason::Deleted);
+            assert!(storage.add_server(on(2, "2001:db8:1:2:ffff::3")).is_ok(), "{}", backend);
+        }
+    }
+
+    #[test]
+    fn cleanup_removes_only_stale_servers() {
+        let config = Config { server_timeout_secs: 60, ..Config::default() };
+        for (backend, storage) in backends(config) {
+            let fresh = ServerInfo::for_test(0);
+            let stale = ServerInfo { last_heartbeat: fresh.last_heartbeat - 120, ..ServerInfo::for_test(1) };
+            storage.add_server(fresh.clone()).unwrap();
+            storage.add_server(stale.clone()).unwrap();
+            let mut events = storage.subscribe();
+
+            let removed = storage.cleanup_stale_servers();
+            assert_eq!(removed.iter().map(|s| s.id.as_str()).collect::<Vec<_>>(), [stale.id.as_str()], "{}", backend);
+            assert!(storage.get_server(&stale.id).is_none(), "{}", backend);
+            assert!(storage.find_server(&stale.ip, stale.port).is_none(), "{}", backend);
+            assert!(storage.get_server(&fresh.id).is_some(), "{}", backend);
+            assert!(matches!(
+                events.try_recv(),
+                Ok(ServerEvent::Removed { reason: RemovalReason::Expired, .. })
+            ), "{}", backend);
+            assert!(storage.cleanup_stale_servers().is_empty(), "{}", backend);
+        }
+    }
+
+    #[test]
+    fn remove_server_publishes_reason() {
+        for (backend, storage) in backends(Config::default()) {
+            let server = storage.add_server(ServerInfo::for_test(0)).unwrap();
+            let mut events = storage.subscribe();
+
+            storage.remove_server(&server.id, RemovalReason::Delisted);
+            assert!(storage.get_server(&server.id).is_none(), "{}", backend);
+            assert!(storage.find_server(&server.ip, server.port).is_none(), "{}", backend);
+            assert!(matches!(
+                events.try_recv(),
+                Ok(ServerEvent::Removed { reason: RemovalReason::Delisted, .. })
+            ), "{}", backend);
+
+            // Removing it again is a no-op
+            storage.remove_server(&server.id, RemovalReason::Deleted);
+            assert!(events.try_recv().is_err(), "{}", backend);
+        }
+    }
+
+    #[test]
+    fn set_flagged_reports_whether_server_exists() {
+        for (backend, storage) in backends(Config::default()) {
+            let server = storage.add_server(ServerInfo::for_test(0)).unwrap();
+            let version = storage.version();
+
+            assert!(storage.set_flagged(&server.id, true), "{}", backend);
+            assert!(storage.get_server(&server.id).unwrap().flagged, "{}", backend);
+            assert!(storage.version() > version, "{}", backend);
+
+            // Setting the same flag again changes nothing but still finds the server
+            let version = storage.version();
+            assert!(storage.set_flagged(&server.id, true), "{}", backend);
+            assert_eq!(storage.version(), version, "{}", backend);
+
+            assert!(!storage.set_flagged("missing", true), "{}", backend);
+        }
+    }
+
+    #[test]
+    fn last_change_matches_state_under_concurrent_writers() {
+        for (backend, storage) in backends(Config::default()) {
+            let server = storage.add_server(ServerInfo::for_test(0)).unwrap();
+            let start = storage.version();
+
+            std::thread::scope(|scope| {
+                scope.spawn(|| {
+                    for i in 0..200 {
+                        storage.set_flagged(&server.id, i % 2 == 0);
+                    }
+                });
+                scope.spawn(|| {
+                    for i in 0..200 {
+                        let map_name = if i % 2 == 0 { "mp_angel_city" } else { "mp_lobby" };
+                        storage.add_server(ServerInfo { map_name: map_name.to_string(), ..ServerInfo::for_test(0) }).unwrap();
+                    }
+                });
+            });
+
+            // Subscribers replaying the log must end up where the registry did
+            let changes = storage.changes_since(start).unwrap();
+            assert!(changes.windows(2).all(|pair| pair[0].version < pair[1].version), "{}", backend);
+            let Some(ServerEvent::Updated { server: last }) = changes.last().map(|change| &change.event) else {
+                panic!("{}: expected an update", backend);
+            };
+            let stored = storage.get_server(&server.id).unwrap();
+            assert_eq!(last.flagged, stored.flagged, "{}", backend);
+            assert_eq!(last.map_name, stored.map_name, "{}", backend);
+        }
+    }
+
+    #[test]
+    fn unchanged_heartbeats_keep_version() {
+        for (backend, storage) in backends(Config::default()) {
+            let first = ServerInfo::for_test(0);
+            storage.add_server(first.clone()).unwrap();
+            let version = storage.version();
+
+            storage.add_server(first.clone()).unwrap();
+            assert_eq!(storage.version(), version, "{}", backend);
+
+            // An hour of heartbeats only moves the reliability score in a few steps
+            for i in 1..=120 {
+                let heartbeat = ServerInfo { last_heartbeat: first.last_heartbeat + i * 30, ..first.clone() };
+                storage.add_server(heartbeat).unwrap();
+            }
+            let stored = storage.get_server(&first.id).unwrap();
+            assert_eq!(stored.last_heartbeat, first.last_heartbeat + 3600, "{}", backend);
+            assert!(storage.changes_since(version).unwrap().len() <= 3, "{}", backend);
+
+            // Anything shown in the list still counts
+            let version = storage.version();
+            storage.add_server(ServerInfo { map_name: "mp_angel_city".to_string(), ..stored }).unwrap();
+            assert!(storage.version() > version, "{}", backend);
+        }
+    }
+
+    #[test]
+    fn alt_address_stays_with_the_server_registered_there() {
+        for (backend, storage) in backends(Config::default()) {
+            let v4 = storage.add_server(ServerInfo::for_test(0)).unwrap();
+            let v6 = ServerInfo { ip: "2001:db8::1".to_string(), port: v4.port, ..ServerInfo::for_test(1) };
+            storage.add_server(v6.clone()).unwrap();
+            let mut events = storage.subscribe();
+
+            // The v6 server claims the address the v4 one is registered at
+            let claim = ServerInfo { alt_ip: v4.ip.clone(), ..v6.clone() };
+            let stored = storage.add_server(claim.clone()).unwrap();
+            assert_eq!(stored.alt_ip, "", "{}", backend);
+            assert_eq!(storage.find_server(&v4.ip, v4.port).unwrap().id, v4.id, "{}", backend);
+            assert!(storage.get_server(&v6.id).is_some(), "{}", backend);
+            assert!(!matches!(events.try_recv(), Ok(ServerEvent::Removed { .. })), "{}", backend);
+
+            // Once the address is free the claim goes through
+            storage.remove_server(&v4.id, RemovalReason::Delisted);
+            assert_eq!(storage.add_server(claim).unwrap().alt_ip, v4.ip, "{}", backend);
+            assert_eq!(storage.find_server(&v4.ip, v4.port).unwrap().id, v6.id, "{}", backend);
+        }
+    }
+
+    #[test]
+    fn sqlite_persists_across_reopen() {
+        let path = temp_db_path();
+        let path_str = path.to_str().unwrap();
+        let server = ServerInfo {
+            token: "secret".to_string(),
+            hide_players: true,
+            alt_ip: "2001:db8::1".to_string(),
+            ..ServerInfo::for_test(0)
+        };
+        {
+            let storage = sqlite::SqliteStorage::open(path_str, Config::default()).unwrap();
+            storage.add_server(server.clone()).unwrap();
+        }
+
+        let storage = sqlite::SqliteStorage::open(path_str, Config::default()).unwrap();
+        let reopened = storage.get_server(&server.id).unwrap();
+        assert_eq!(reopened.token, "secret");
+        assert!(reopened.hide_players);
+        assert_eq!(reopened.alt_ip, "2001:db8::1");
+        assert_eq!(storage.find_server("2001:db8::1", server.port).unwrap().id, server.id);
+        drop(storage);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
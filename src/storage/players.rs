@@ -0,0 +1,295 @@
+// src/storage/players.rs
+use parking_lot::RwLock;
+use std::collections::{ BTreeMap, BTreeSet, HashMap, HashSet };
+use crate::models::server::{ Player, ServerInfo };
+use crate::storage::events::ServerEvent;
+
+/// A player found by name and the server they're on.
+#[derive(Debug, Clone)]
+pub struct PlayerMatch {
+    pub player: Player,
+    pub server: ServerInfo,
+}
+
+#[derive(Default)]
+struct Names {
+    // Lowercased player name to the IDs of the servers with a player of that name
+    by_name: BTreeMap<String, BTreeSet<String>>,
+    // Server ID to the lowercased names it put into `by_name`
+    by_server: HashMap<String, Vec<String>>,
+}
+
+impl Names {
+    fn remove_server(&mut self, id: &str) {
+        let Some(names) = self.by_server.remove(id) else {
+            return;
+        };
+        for name in names {
+            if let Some(ids) = self.by_name.get_mut(&name) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    self.by_name.remove(&name);
+                }
+            }
+        }
+    }
+}
+
+/// Player names across all registered servers, so finding a player doesn't
+/// mean scanning every player list.
+///
+/// Storage backends feed it every change they record. Servers that set
+/// `hide_players` are kept out of it.
+#[derive(Default)]
+pub struct PlayerIndex {
+    names: RwLock<Names>,
+}
+
+impl PlayerIndex {
+    /// Updates the index for a change to the registry.
+    pub fn apply(&self, event: &ServerEvent) {
+        let mut names = self.names.write();
+        match event {
+            ServerEvent::Added { server } | ServerEvent::Updated { server } => {
+                names.remove_server(&server.id);
+                if server.hide_players {
+                    return;
+                }
+                let keys: Vec<String> = server.players.iter().map(|player| player.name.to_lowercase()).collect();
+                for key in &keys {
+                    names.by_name.entry(key.clone()).or_default().insert(server.id.clone());
+                }
+                names.by_server.insert(server.id.clone(), keys);
+            }
+            ServerEvent::Removed { server, .. } => names.remove_server(&server.id),
+        }
+    }
+
+    /// Finds players whose name starts with `query`, then those whose name
+    /// only contains it, ignoring case. At most `limit` are returned.
+    ///
+    /// Prefix matches are a range lookup in the sorted names. Substring matches
+    /// aren't indexed: finding them is a linear scan over every indexed name,
+    /// which stops as soon as enough hits are found but walks all of them for a
+    /// query that matches few. The names are only those of players online.
+    ///
+    /// `lookup` fetches the current entry of a server. It's only called once the
+    /// index is unlocked, so it may take the backend's own locks. Hits on servers
+    /// that left or opted out since they were indexed don't count towards `limit`.
+    pub fn search(&self, query: &str, limit: usize, lookup: impl Fn(&str) -> Option<ServerInfo>) -> Vec<PlayerMatch> {
+        let query = query.to_lowercase();
+        let mut servers: HashMap<String, Option<ServerInfo>> = HashMap::new();
+        let mut seen: HashSet<(String, String)> = HashSet::new();
+        let mut matches = Vec::new();
+        let mut skip = 0;
+
+        // Hits are read a batch at a time and resolved with the index unlocked,
+        // until `limit` players are found or the hits run out
+        while matches.len() < limit {
+            let hits = self.hits(&query, skip, limit - matches.len());
+            if hits.is_empty() {
+                break;
+            }
+            skip += hits.len();
+
+            for hit in hits {
+                // The index may have shifted between batches
+                if !seen.insert(hit.clone()) {
+                    continue;
+                }
+                let (name, id) = hit;
+                let server = servers.entry(id).or_insert_with_key(|id| lookup(id));
+                // The server may have left or opted out since the index was read
+                let Some(server) = server.as_ref().filter(|server| !server.hide_players) else {
+                    continue;
+                };
+                for player in server.players.iter().filter(|player| player.name.to_lowercase() == name) {
+                    if matches.len() < limit {
+                        matches.push(PlayerMatch { player: player.clone(), server: server.clone() });
+                    }
+                }
+            }
+        }
+        matches
+    }
+
+    /// Up to `count` (name, server ID) hits for `query` after the first `skip`,
+    /// prefix matches first.
+    fn hits(&self, query: &str, skip: usize, count: usize) -> Vec<(String, String)> {
+        let names = self.names.read();
+        let prefixed = names.by_name
+            .range(query.to_string()..)
+            .take_while(|(name, _)| name.starts_with(query));
+        // O(n) in the indexed names, only reached once the prefix matches ran out
+        let contained = names.by_name
+            .iter()
+            .filter(|(name, _)| !name.starts_with(query) && name.contains(query));
+        // Lazy up to here, so the scan ends with the last hit taken
+        prefixed
+            .chain(contained)
+            .flat_map(|(name, ids)| ids.iter().map(move |id| (name.clone(), id.clone())))
+            .skip(skip)
+            .take(count)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::events::RemovalReason;
+
+    /// The index together with the registry it indexes, as a backend keeps them.
+    #[derive(Default)]
+    struct Registry {
+        index: PlayerIndex,
+        servers: HashMap<String, ServerInfo>,
+    }
+
+    impl Registry {
+        fn put(&mut self, server: ServerInfo) {
+            let event = if self.servers.contains_key(&server.id) {
+                ServerEvent::Updated { server: server.clone() }
+            } else {
+                ServerEvent::Added { server: server.clone() }
+            };
+            self.servers.insert(server.id.clone(), server);
+            self.index.apply(&event);
+        }
+
+        fn remove(&mut self, id: &str) {
+            if let Some(server) = self.servers.remove(id) {
+                self.index.apply(&ServerEvent::Removed { server, reason: RemovalReason::Deleted });
+            }
+        }
+
+        fn search(&self, query: &str, limit: usize) -> Vec<(String, String)> {
+            self.index
+                .search(query, limit, |id| self.servers.get(id).cloned())
+                .into_iter()
+                .map(|found| (found.player.name, found.server.id))
+                .collect()
+        }
+
+        fn names(&self, query: &str) -> Vec<String> {
+            self.search(query, 100).into_iter().map(|(name, _)| name).collect()
+        }
+    }
+
+    #[test]
+    fn lists_prefix_matches_before_substring_matches() {
+        let mut registry = Registry::default();
+        registry.put(ServerInfo::with_players(0, &["xViper", "Viperine", "Ace"]));
+        registry.put(ServerInfo::with_players(1, &["TheViper", "viper"]));
+
+        assert_eq!(registry.names("viper"), ["viper", "Viperine", "TheViper", "xViper"]);
+        assert_eq!(registry.names("viper").len(), registry.search("viper", 100).len());
+        assert_eq!(registry.names("nobody"), Vec::<String>::new());
+        assert_eq!(registry.search("viper", 2).len(), 2);
+    }
+
+    #[test]
+    fn ignores_case() {
+        let mut registry = Registry::default();
+        registry.put(ServerInfo::with_players(0, &["PilotOne"]));
+        assert_eq!(registry.names("pilotone"), ["PilotOne"]);
+        assert_eq!(registry.names("PILOT"), ["PilotOne"]);
+        assert_eq!(registry.names("tOn"), ["PilotOne"]);
+    }
+
+    #[test]
+    fn finds_the_same_name_on_every_server() {
+        let mut registry = Registry::default();
+        registry.put(ServerInfo::with_players(0, &["Pilot"]));
+        registry.put(ServerInfo::with_players(1, &["pilot"]));
+        let found = registry.search("pilot", 100);
+        assert_eq!(found, [
+            ("Pilot".to_string(), "server-0".to_string()),
+            ("pilot".to_string(), "server-1".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn skips_servers_that_hide_players() {
+        let mut registry = Registry::default();
+        registry.put(ServerInfo::with_players(0, &["Pilot"]));
+        registry.put(ServerInfo { hide_players: true, ..ServerInfo::with_players(1, &["Pilot2"]) });
+        assert_eq!(registry.names("pilot"), ["Pilot"]);
+
+        // Opting out later takes the server's players out of the index
+        registry.put(ServerInfo { hide_players: true, ..ServerInfo::with_players(0, &["Pilot"]) });
+        assert!(registry.names("pilot").is_empty());
+        assert!(registry.index.names.read().by_name.is_empty());
+    }
+
+    #[test]
+    fn finds_mixed_case_substrings_except_hidden_players() {
+        let mut registry = Registry::default();
+        registry.put(ServerInfo::with_players(0, &["xXSniPeRXx"]));
+        registry.put(ServerInfo { hide_players: true, ..ServerInfo::with_players(1, &["TheSNIPER"]) });
+        registry.put(ServerInfo::with_players(2, &["sniperWolf"]));
+
+        assert_eq!(registry.names("SnIpEr"), ["sniperWolf", "xXSniPeRXx"]);
+        assert_eq!(registry.names("IpErX"), ["xXSniPeRXx"]);
+
+        // A server that opted out after being indexed is left out as well
+        registry.servers.get_mut("server-0").unwrap().hide_players = true;
+        assert_eq!(registry.names("nIPe"), ["sniperWolf"]);
+    }
+
+    #[test]
+    fn fills_the_limit_past_hidden_and_gone_servers() {
+        let mut registry = Registry::default();
+        registry.put(ServerInfo::with_players(0, &["Pilot1"]));
+        registry.put(ServerInfo::with_players(1, &["Pilot2"]));
+        registry.put(ServerInfo::with_players(2, &["Pilot3"]));
+        registry.put(ServerInfo::with_players(3, &["Pilot4"]));
+        // The first two hits opted out or left after being indexed
+        registry.servers.get_mut("server-0").unwrap().hide_players = true;
+        registry.servers.remove("server-1");
+
+        assert_eq!(registry.names("pilot"), ["Pilot3", "Pilot4"]);
+        assert_eq!(registry.search("pilot", 2), [
+            ("Pilot3".to_string(), "server-2".to_string()),
+            ("Pilot4".to_string(), "server-3".to_string()),
+        ]);
+        assert_eq!(registry.search("pilot", 1), [("Pilot3".to_string(), "server-2".to_string())]);
+    }
+
+    #[test]
+    fn update_replaces_the_servers_names() {
+        let mut registry = Registry::default();
+        registry.put(ServerInfo::with_players(0, &["Leaving", "Staying"]));
+        registry.put(ServerInfo::with_players(0, &["Staying", "Joining"]));
+
+        assert!(registry.names("leaving").is_empty());
+        assert_eq!(registry.names("ing"), ["Joining", "Staying"]);
+        let names = registry.index.names.read();
+        assert!(!names.by_name.contains_key("leaving"));
+        assert_eq!(names.by_server["server-0"], ["staying", "joining"]);
+    }
+
+    #[test]
+    fn remove_cleans_up_the_index() {
+        let mut registry = Registry::default();
+        registry.put(ServerInfo::with_players(0, &["Pilot", "Shared"]));
+        registry.put(ServerInfo::with_players(1, &["Shared"]));
+        registry.remove("server-0");
+
+        assert!(registry.names("pilot").is_empty());
+        assert_eq!(registry.search("shared", 100), [("Shared".to_string(), "server-1".to_string())]);
+        let names = registry.index.names.read();
+        assert!(!names.by_server.contains_key("server-0"));
+        assert!(!names.by_name.contains_key("pilot"));
+        assert_eq!(names.by_name["shared"].len(), 1);
+    }
+
+    #[test]
+    fn drops_matches_from_servers_gone_since_indexing() {
+        let mut registry = Registry::default();
+        registry.put(ServerInfo::with_players(0, &["Pilot"]));
+        // The backend dropped the server but the index hasn't seen the event yet
+        registry.servers.clear();
+        assert!(registry.names("pilot").is_empty());
+    }
+}
@@ -0,0 +1,29 @@
+// src/storage/reaper.rs
+use std::sync::Arc;
+use std::time::Duration;
+use log::{debug, error, info};
+use crate::storage::ServerStore;
+
+/// Expires servers that stopped sending heartbeats, every `interval`.
+///
+/// This keeps stale entries from piling up (and counting against
+/// `max_servers_per_ip`) regardless of how often the list is requested.
+pub fn spawn_reaper(storage: Arc<dyn ServerStore>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let storage = storage.clone();
+            match tokio::task::spawn_blocking(move || storage.cleanup_stale_servers()).await {
+                Ok(removed) if !removed.is_empty() => {
+                    info!("Reaper expired {} stale servers", removed.len());
+                    for server in &removed {
+                        debug!("Expired server {} ({}:{})", server.id, server.ip, server.port);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => error!("Reaper task panicked: {}", e),
+            }
+        }
+    });
+}
@@ -0,0 +1,252 @@
+// src/storage/reliability.rs
+use dashmap::DashMap;
+use crate::config::Config;
+
+// Newly seen addresses start out as if they had been watched for this long at
+// 50% uptime, so a server needs some track record to outrank established ones.
+const PRIOR_SECS: f64 = 60.0 * 60.0;
+// Each recent missed heartbeat or failed challenge costs this share of the score
+const INCIDENT_PENALTY: f64 = 0.9;
+// Records are forgotten once everything in them has decayed below 1%
+const FORGET_AFTER_HALF_LIVES: u64 = 7;
+
+/// How dependable a server has been, as reported by `/server/{id}`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReliabilityReport {
+    /// 0 to 100, what the list exposes and sorts by.
+    pub score: u32,
+    /// Share of the recent past the server was sending heartbeats.
+    pub uptime: f64,
+    /// Times the server was expired for missing heartbeats.
+    pub missed_heartbeats: u32,
+    pub failed_challenges: u32,
+}
+
+// Time and incident figures decay with the configured half-life, so a server
+// that stabilizes recovers its score and one that starts flapping loses it.
+struct Record {
+    updated_at: u64,
+    last_heartbeat: u64,
+    observed_secs: f64,
+    online_secs: f64,
+    incidents: f64,
+    missed_heartbeats: u32,
+    failed_challenges: u32,
+}
+
+impl Record {
+    fn new(now: u64) -> Self {
+        Self {
+            updated_at: now,
+            last_heartbeat: 0,
+            observed_secs: 0.0,
+            online_secs: 0.0,
+            incidents: 0.0,
+            missed_heartbeats: 0,
+            failed_challenges: 0,
+        }
+    }
+
+    /// Accounts for the time since the last update. It counts as uptime while the
+    /// server is still within `server_timeout_secs` of its last heartbeat.
+    fn advance(&mut self, now: u64, half_life_secs: u64, server_timeout_secs: u64) {
+        let elapsed = now.saturating_sub(self.updated_at);
+        if elapsed == 0 {
+            return;
+        }
+        let decay = 0.5_f64.powf(elapsed as f64 / half_life_secs.max(1) as f64);
+        self.observed_secs = self.observed_secs * decay + elapsed as f64;
+        self.online_secs *= decay;
+        if now.saturating_sub(self.last_heartbeat) < server_timeout_secs {
+            self.online_secs += elapsed as f64;
+        }
+        self.incidents *= decay;
+        self.updated_at = now;
+    }
+
+    fn report(&self) -> ReliabilityReport {
+        let uptime = (self.online_secs + PRIOR_SECS / 2.0) / (self.observed_secs + PRIOR_SECS);
+        let score = 100.0 * uptime * INCIDENT_PENALTY.powf(self.incidents);
+        ReliabilityReport {
+            score: score.round().clamp(0.0, 100.0) as u32,
+            uptime: self.online_secs / self.observed_secs.max(1.0),
+            missed_heartbeats: self.missed_heartbeats,
+            failed_challenges: self.failed_challenges,
+        }
+    }
+}
+
+/// Uptime and incident history per ip:port, kept by the storage backends.
+///
+/// Records are keyed by address rather than server ID, so a server that keeps
+/// expiring and registering again carries its history along. They live in
+/// memory only and start over when the master restarts.
+pub struct ReliabilityTracker {
+    records: DashMap<(String, i32), Record>,
+    half_life_secs: u64,
+    server_timeout_secs: u64,
+}
+
+impl ReliabilityTracker {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            records: DashMap::new(),
+            half_life_secs: config.reliability_half_life_secs,
+            server_timeout_secs: config.server_timeout_secs,
+        }
+    }
+
+    /// Records an accepted heartbeat and returns the server's current score.
+    pub fn heartbeat(&self, ip: &str, port: i32, now: u64) -> u32 {
+        let mut record = self.records.entry((ip.to_string(), port)).or_insert_with(|| Record::new(now));
+        record.advance(now, self.half_life_secs, self.server_timeout_secs);
+        record.last_heartbeat = record.last_heartbeat.max(now);
+        record.report().score
+    }
+
+    /// Records that the server at this address was expired by the stale cleanup.
+    pub fn expired(&self, ip: &str, port: i32, now: u64) {
+        if let Some(mut record) = self.records.get_mut(&(ip.to_string(), port)) {
+            record.advance(now, self.half_life_secs, self.server_timeout_secs);
+            record.incidents += 1.0;
+            record.missed_heartbeats += 1;
+        }
+    }
+
+    /// Records a heartbeat whose challenge went unanswered or was answered wrong.
+    ///
+    /// Only addresses that have had a heartbeat accepted are tracked. Anyone can
+    /// claim any ip:port in a heartbeat, so failures alone never create a record.
+    pub fn challenge_failed(&self, ip: &str, port: i32, now: u64) {
+        if let Some(mut record) = self.records.get_mut(&(ip.to_string(), port)) {
+            record.advance(now, self.half_life_secs, self.server_timeout_secs);
+            record.incidents += 1.0;
+            record.failed_challenges += 1;
+        }
+    }
+
+    pub fn report(&self, ip: &str, port: i32) -> Option<ReliabilityReport> {
+        self.records.get(&(ip.to_string(), port)).map(|record| record.report())
+    }
+
+    /// Forgets addresses that haven't been heard from in a long time.
+    pub fn prune(&self, now: u64) {
+        let horizon = self.half_life_secs.saturating_mul(FORGET_AFTER_HALF_LIVES);
+        self.records.retain(|_, record| now.saturating_sub(record.updated_at) < horizon);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HALF_LIFE: u64 = 3600;
+    const HOUR: u64 = 3600;
+
+    fn tracker() -> ReliabilityTracker {
+        ReliabilityTracker::new(&Config {
+            reliability_half_life_secs: HALF_LIFE,
+            server_timeout_secs: 60,
+            ..Config::default()
+        })
+    }
+
+    /// Heartbeats every 30 seconds from `from` until `to`, returning the last score.
+    fn beat(tracker: &ReliabilityTracker, port: i32, from: u64, to: u64) -> u32 {
+        (from..=to).step_by(30).map(|now| tracker.heartbeat("10.0.0.1", port, now)).last().unwrap()
+    }
+
+    fn score(tracker: &ReliabilityTracker, port: i32) -> u32 {
+        tracker.report("10.0.0.1", port).unwrap().score
+    }
+
+    #[test]
+    fn heartbeats_raise_the_score() {
+        let tracker = tracker();
+        let first = tracker.heartbeat("10.0.0.1", 37015, 0);
+        assert_eq!(first, 50);
+        // The prior keeps weighing in at about a half-life's worth of observation
+        let later = beat(&tracker, 37015, 30, 6 * HOUR);
+        assert!(later > first + 25, "{}", later);
+    }
+
+    #[test]
+    fn incidents_lower_the_score() {
+        let tracker = tracker();
+        beat(&tracker, 37015, 0, 6 * HOUR);
+        let before = score(&tracker, 37015);
+
+        tracker.expired("10.0.0.1", 37015, 6 * HOUR);
+        let expired = score(&tracker, 37015);
+        assert!(expired < before, "{} < {}", expired, before);
+
+        tracker.challenge_failed("10.0.0.1", 37015, 6 * HOUR);
+        let failed = score(&tracker, 37015);
+        assert!(failed < expired, "{} < {}", failed, expired);
+
+        let report = tracker.report("10.0.0.1", 37015).unwrap();
+        assert_eq!((report.missed_heartbeats, report.failed_challenges), (1, 1));
+    }
+
+    #[test]
+    fn flapping_server_ranks_below_stable_one() {
+        let tracker = tracker();
+        beat(&tracker, 37015, 0, 6 * HOUR);
+
+        // Up for 20 minutes, expired, gone for 20 minutes, and again
+        let mut now = 0;
+        while now < 6 * HOUR {
+            let last = now + 20 * 60;
+            beat(&tracker, 37016, now, last);
+            tracker.expired("10.0.0.1", 37016, last + 60);
+            now = last + 40 * 60;
+        }
+        tracker.heartbeat("10.0.0.1", 37016, 6 * HOUR);
+
+        assert!(score(&tracker, 37016) + 20 < score(&tracker, 37015));
+    }
+
+    #[test]
+    fn incidents_decay_over_half_lives() {
+        let tracker = tracker();
+        beat(&tracker, 37015, 0, 6 * HOUR);
+        beat(&tracker, 37016, 0, 6 * HOUR);
+        tracker.challenge_failed("10.0.0.1", 37016, 6 * HOUR);
+        let penalty = score(&tracker, 37015) - score(&tracker, 37016);
+        assert!(penalty >= 5, "{}", penalty);
+
+        // After one half-life about half the penalty is left, after five next to nothing
+        beat(&tracker, 37015, 6 * HOUR, 7 * HOUR);
+        beat(&tracker, 37016, 6 * HOUR, 7 * HOUR);
+        let halved = score(&tracker, 37015) - score(&tracker, 37016);
+        assert!(halved < penalty && halved * 3 >= penalty, "{} of {}", halved, penalty);
+
+        beat(&tracker, 37015, 7 * HOUR, 11 * HOUR);
+        beat(&tracker, 37016, 7 * HOUR, 11 * HOUR);
+        assert!(score(&tracker, 37015) - score(&tracker, 37016) <= 1);
+    }
+
+    #[test]
+    fn prune_forgets_silent_addresses() {
+        let tracker = tracker();
+        tracker.heartbeat("10.0.0.1", 37015, 0);
+        tracker.heartbeat("10.0.0.1", 37016, HOUR);
+
+        let horizon = HALF_LIFE * FORGET_AFTER_HALF_LIVES;
+        tracker.prune(horizon - 1);
+        assert!(tracker.report("10.0.0.1", 37015).is_some());
+
+        tracker.prune(horizon);
+        assert!(tracker.report("10.0.0.1", 37015).is_none());
+        assert!(tracker.report("10.0.0.1", 37016).is_some());
+    }
+
+    #[test]
+    fn failed_challenges_alone_are_not_tracked() {
+        let tracker = tracker();
+        for port in 1025..2025 {
+            tracker.challenge_failed("203.0.113.7", port, 0);
+        }
+        assert!(tracker.records.is_empty());
+    }
+}
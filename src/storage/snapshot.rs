@@ -0,0 +1,189 @@
+// src/storage/snapshot.rs
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{ self, Write };
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::{debug, error, info, warn};
+use crate::models::server::ServerInfo;
+use crate::storage::ServerStore;
+
+/// On-disk representation of the registry.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    saved_at: u64,
+    servers: Vec<ServerInfo>,
+}
+
+/// Writes every registered server to `path`.
+///
+/// The snapshot is written and synced to a temporary file first and then renamed
+/// over the old one, so a crash mid-write never leaves a truncated snapshot behind.
+pub fn save(storage: &dyn ServerStore, path: &Path) -> io::Result<usize> {
+    let snapshot = Snapshot {
+        saved_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        servers: storage.get_servers(),
+    };
+    let data = serde_json::to_vec(&snapshot).map_err(io::Error::other)?;
+
+    let mut tmp_path = PathBuf::from(path);
+    tmp_path.set_extension("tmp");
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp_path, path)?;
+
+    // The rename only survives a crash once the directory entry is on disk too
+    #[cfg(unix)]
+    {
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        fs::File::open(dir)?.sync_all()?;
+    }
+
+    Ok(snapshot.servers.len())
+}
+
+/// Loads a snapshot from `path` into `storage`.
+///
+/// Entries keep the `last_heartbeat` they were saved with, so servers that died
+/// while the master was down are dropped by the stale cleanup that follows.
+pub fn restore(storage: &dyn ServerStore, path: &Path) -> io::Result<usize> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            info!("No registry snapshot found at {}, starting empty", path.display());
+            return Ok(0);
+        }
+        Err(e) => return Err(e),
+    };
+    let snapshot: Snapshot = serde_json::from_slice(&data).map_err(io::Error::other)?;
+
+    let mut restored = 0;
+    for server in snapshot.servers {
+        let id = server.id.clone();
+        match storage.add_server(server) {
+            Ok(_) => restored += 1,
+            Err(e) => warn!("Skipping snapshot entry {}: {}", id, e),
+        }
+    }
+    storage.cleanup_stale_servers();
+
+    info!(
+        "Restored {} servers from snapshot saved at {} ({} still live)",
+        restored,
+        snapshot.saved_at,
+        storage.get_servers().len()
+    );
+    Ok(restored)
+}
+
+/// Periodically saves the registry to `path` until the runtime shuts down.
+pub fn spawn_snapshot_task(storage: Arc<dyn ServerStore>, path: PathBuf, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        // The first tick fires immediately; there is nothing new to save yet.
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let storage = storage.clone();
+            let path = path.clone();
+            match tokio::task::spawn_blocking(move || save(storage.as_ref(), &path)).await {
+                Ok(Ok(count)) => debug!("Saved registry snapshot with {} servers", count),
+                Ok(Err(e)) => error!("Failed to save registry snapshot: {}", e),
+                Err(e) => error!("Registry snapshot task panicked: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::storage::memory::ServerStorage;
+
+    /// A directory of its own under the system temp dir, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("r1ms-snapshot-{}", uuid::Uuid::new_v4()));
+            fs::create_dir(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn file(&self, name: &str) -> PathBuf {
+            self.0.join(name)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn storage() -> ServerStorage {
+        ServerStorage::new(Config { max_servers_per_ip: 8, server_timeout_secs: 60, ..Config::default() })
+    }
+
+    #[test]
+    fn restores_saved_servers() {
+        let dir = TempDir::new();
+        let path = dir.file("registry.json");
+        let original = storage();
+        for i in 0..3 {
+            original.add_server(ServerInfo { token: format!("token-{}", i), ..ServerInfo::for_test(i) }).unwrap();
+        }
+        assert_eq!(save(&original, &path).unwrap(), 3);
+        assert!(!dir.file("registry.tmp").exists());
+
+        let restored = storage();
+        assert_eq!(restore(&restored, &path).unwrap(), 3);
+        for server in original.get_servers() {
+            let copy = restored.get_server(&server.id).unwrap();
+            assert_eq!(copy.token, server.token);
+            assert_eq!(copy.last_heartbeat, server.last_heartbeat);
+            assert_eq!(copy.first_seen, server.first_seen);
+            assert_eq!((copy.ip, copy.port), (server.ip, server.port));
+        }
+    }
+
+    #[test]
+    fn drops_servers_that_expired_while_down() {
+        let dir = TempDir::new();
+        let path = dir.file("registry.json");
+        let original = storage();
+        let fresh = ServerInfo::for_test(0);
+        original.add_server(fresh.clone()).unwrap();
+        original.add_server(ServerInfo { last_heartbeat: fresh.last_heartbeat - 120, ..ServerInfo::for_test(1) }).unwrap();
+        save(&original, &path).unwrap();
+
+        let restored = storage();
+        restore(&restored, &path).unwrap();
+        let ids: Vec<String> = restored.get_servers().into_iter().map(|server| server.id).collect();
+        assert_eq!(ids, [fresh.id]);
+    }
+
+    #[test]
+    fn missing_snapshot_starts_empty() {
+        let dir = TempDir::new();
+        let restored = storage();
+        assert_eq!(restore(&restored, &dir.file("registry.json")).unwrap(), 0);
+        assert!(restored.get_servers().is_empty());
+    }
+
+    #[test]
+    fn corrupt_snapshot_starts_empty() {
+        let dir = TempDir::new();
+        let path = dir.file("registry.json");
+        for data in [&b""[..], b"{\"saved_at\": 1, \"servers\": [", b"\x00\xff not json"] {
+            fs::write(&path, data).unwrap();
+            let restored = storage();
+            assert!(restore(&restored, &path).is_err());
+            assert!(restored.get_servers().is_empty());
+        }
+    }
+}
@@ -0,0 +1,330 @@
+// src/storage/sqlite.rs
+use parking_lot::Mutex;
+use rusqlite::{ params, Connection, OptionalExtension, Row };
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::error;
+use crate::models::server::ServerInfo;
+use crate::config::Config;
+use crate::storage::ServerStore;
+use crate::storage::changes::{ Change, ChangeJournal };
+use crate::storage::players::{ PlayerIndex, PlayerMatch };
+use crate::storage::reliability::{ ReliabilityReport, ReliabilityTracker };
+use crate::storage::events::{ RemovalReason, ServerEvent };
+use crate::utils::address_block;
+use tokio::sync::broadcast;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS servers (
+        id             TEXT PRIMARY KEY,
+        host_name      TEXT NOT NULL,
+        map_name       TEXT NOT NULL,
+        game_mode      TEXT NOT NULL,
+        players        TEXT NOT NULL,
+        max_players    INTEGER NOT NULL,
+        port           INTEGER NOT NULL,
+        ip             TEXT NOT NULL,
+        last_heartbeat INTEGER NOT NULL,
+        first_seen     INTEGER NOT NULL DEFAULT 0,
+        token          TEXT NOT NULL DEFAULT '',
+        flagged        INTEGER NOT NULL DEFAULT 0,
+        hide_players   INTEGER NOT NULL DEFAULT 0,
+        reliability    INTEGER NOT NULL DEFAULT 0,
+        alt_ip         TEXT NOT NULL DEFAULT '',
+        ip_block       TEXT NOT NULL DEFAULT '',
+        UNIQUE (ip, port)
+    );
+    CREATE INDEX IF NOT EXISTS servers_ip ON servers (ip);
+    CREATE INDEX IF NOT EXISTS servers_alt_ip ON servers (alt_ip);
+    CREATE INDEX IF NOT EXISTS servers_ip_block ON servers (ip_block);
+";
+
+macro_rules! columns {
+    () => { "id, host_name, map_name, game_mode, players, max_players, port, ip, last_heartbeat, first_seen, token, flagged, hide_players, reliability, alt_ip, ip_block" };
+}
+
+const SELECT_COLUMNS: &str = concat!("SELECT ", columns!(), " FROM servers");
+const RETURNING_COLUMNS: &str = concat!(" RETURNING ", columns!());
+
+/// Server registry backed by an embedded SQLite database file.
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+    changes: ChangeJournal,
+    players: PlayerIndex,
+    reliability: ReliabilityTracker,
+    config: Config,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &str, config: Config) -> Result<Self, String> {
+        let conn = Connection::open(path)
+            .map_err(|e| format!("Failed to open SQLite database {}: {}", path, e))?;
+        conn.execute_batch(SCHEMA)
+            .map_err(|e| format!("Failed to initialize SQLite schema: {}", e))?;
+
+        let storage = Self {
+            conn: Mutex::new(conn),
+            changes: ChangeJournal::new(config.change_log_capacity),
+            players: PlayerIndex::default(),
+            reliability: ReliabilityTracker::new(&config),
+            config,
+        };
+        // Servers persisted by an earlier run are searchable right away
+        for server in storage.get_servers() {
+            storage.players.apply(&ServerEvent::Added { server });
+        }
+        Ok(storage)
+    }
+
+    /// Records a change and applies it to the player index.
+    fn record(&self, event: ServerEvent) {
+        self.players.apply(&event);
+        self.changes.record(event);
+    }
+
+    fn row_to_server(row: &Row<'_>) -> rusqlite::Result<ServerInfo> {
+        let players: String = row.get(4)?;
+        let last_heartbeat: i64 = row.get(8)?;
+        Ok(ServerInfo {
+            id: row.get(0)?,
+            host_name: row.get(1)?,
+            map_name: row.get(2)?,
+            game_mode: row.get(3)?,
+            players: serde_json::from_str(&players).unwrap_or_default(),
+            max_players: row.get(5)?,
+            port: row.get(6)?,
+            ip: row.get(7)?,
+            last_heartbeat: last_heartbeat as u64,
+            first_seen: row.get::<_, i64>(9)? as u64,
+            token: row.get(10)?,
+            flagged: row.get(11)?,
+            hide_players: row.get(12)?,
+            reliability: row.get(13)?,
+            alt_ip: row.get(14)?,
+        })
+    }
+
+    fn query_one(&self, sql: &str, params: impl rusqlite::Params) -> Option<ServerInfo> {
+        let conn = self.conn.lock();
+        match conn.query_row(sql, params, Self::row_to_server).optional() {
+            Ok(server) => server,
+            Err(e) => {
+                error!("Failed to query server: {}", e);
+                None
+            }
+        }
+    }
+}
+
+impl ServerStore for SqliteStorage {
+    fn add_server(&self, mut server_info: ServerInfo) -> Result<ServerInfo, String> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+
+        // Check if a server with the same IP and port already exists. Dual-stack
+        // servers may come in over either of their addresses.
+        let existing = tx
+            .query_row(
+                &format!(
+                    "{} WHERE port = ?2 AND (ip = ?1 OR alt_ip = ?1 OR (?3 != '' AND (ip = ?3 OR alt_ip = ?3)))
+                     ORDER BY (ip = ?1 OR alt_ip = ?1) DESC LIMIT 1",
+                    SELECT_COLUMNS
+                ),
+                params![server_info.ip, server_info.port, server_info.alt_ip],
+                Self::row_to_server,
+            )
+            .optional()
+            .map_err(|e| e.to_string())?;
+
+        let is_new = existing.is_none();
+        let mut changed = true;
+        let stored = match existing {
+            Some(mut existing) => {
+                // A server already registered at the claimed alternate address keeps it
+                let contested: bool = tx
+                    .query_row(
+                        "SELECT ?3 != '' AND EXISTS (SELECT 1 FROM servers WHERE id != ?1 AND port = ?2 AND (ip = ?3 OR alt_ip = ?3))",
+                        params![existing.id, server_info.port, server_info.alt_ip],
+                        |row| row.get(0),
+                    )
+                    .map_err(|e| e.to_string())?;
+                if contested {
+                    server_info.alt_ip.clear();
+                }
+                server_info.reliability =
+                    self.reliability.heartbeat(&existing.ip, existing.port, server_info.last_heartbeat);
+                changed = existing.update_from(server_info);
+                existing
+            }
+            None => {
+                // Check number of servers from this IP, or this /64 for IPv6
+                let server_count: i64 = tx
+                    .query_row(
+                        "SELECT COUNT(*) FROM servers WHERE ip_block = ?1",
+                        params![address_block(&server_info.ip)],
+                        |row| row.get(0),
+                    )
+                    .map_err(|e| e.to_string())?;
+
+                if server_count as usize >= self.config.max_servers_per_ip {
+                    return Err(format!("Maximum number of servers ({}) reached for this IP", self.config.max_servers_per_ip));
+                }
+                server_info.reliability =
+                    self.reliability.heartbeat(&server_info.ip, server_info.port, server_info.last_heartbeat);
+                server_info
+            }
+        };
+
+        let players = serde_json::to_string(&stored.players).map_err(|e| e.to_string())?;
+        tx.execute(
+            &format!("INSERT OR REPLACE INTO servers ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)", columns!()),
+            params![
+                stored.id,
+                stored.host_name,
+                stored.map_name,
+                stored.game_mode,
+                players,
+                stored.max_players,
+                stored.port,
+                stored.ip,
+                stored.last_heartbeat as i64,
+                stored.first_seen as i64,
+                stored.token,
+                stored.flagged,
+                stored.hide_players,
+                stored.reliability,
+                stored.alt_ip,
+                address_block(&stored.ip),
+            ],
+        ).map_err(|e| e.to_string())?;
+
+        tx.commit().map_err(|e| e.to_string())?;
+        if is_new {
+            self.record(ServerEvent::Added { server: stored.clone() });
+        } else if changed {
+            self.record(ServerEvent::Updated { server: stored.clone() });
+        }
+        Ok(stored)
+    }
+
+    fn cleanup_stale_servers(&self) -> Vec<ServerInfo> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let cutoff = now.saturating_sub(self.config.server_timeout_secs) as i64;
+
+        let conn = self.conn.lock();
+        let result = conn
+            .prepare(&format!("DELETE FROM servers WHERE last_heartbeat <= ?1{}", RETURNING_COLUMNS))
+            .and_then(|mut stmt| {
+                stmt.query_map(params![cutoff], Self::row_to_server)?.collect::<rusqlite::Result<Vec<_>>>()
+            });
+
+        match result {
+            Ok(removed) => {
+                for server in &removed {
+                    self.reliability.expired(&server.ip, server.port, now);
+                    self.record(ServerEvent::Removed { server: server.clone(), reason: RemovalReason::Expired });
+                }
+                self.reliability.prune(now);
+                removed
+            }
+            Err(e) => {
+                error!("Failed to clean up stale servers: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn get_server(&self, id: &str) -> Option<ServerInfo> {
+        self.query_one(&format!("{} WHERE id = ?1", SELECT_COLUMNS), params![id])
+    }
+
+    fn get_servers(&self) -> Vec<ServerInfo> {
+        let conn = self.conn.lock();
+        let result = conn
+            .prepare(SELECT_COLUMNS)
+            .and_then(|mut stmt| {
+                stmt.query_map([], Self::row_to_server)?.collect::<rusqlite::Result<Vec<_>>>()
+            });
+
+        match result {
+            Ok(servers) => servers,
+            Err(e) => {
+                error!("Failed to list servers: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn remove_server(&self, id: &str, reason: RemovalReason) {
+        let conn = self.conn.lock();
+        let result = conn
+            .query_row(&format!("DELETE FROM servers WHERE id = ?1{}", RETURNING_COLUMNS), params![id], Self::row_to_server)
+            .optional();
+
+        match result {
+            Ok(Some(server)) => {
+                self.record(ServerEvent::Removed { server, reason });
+            }
+            Ok(None) => {}
+            Err(e) => error!("Failed to remove server {}: {}", id, e),
+        }
+    }
+
+    fn find_server(&self, ip: &str, port: i32) -> Option<ServerInfo> {
+        self.query_one(&format!("{} WHERE (ip = ?1 OR alt_ip = ?1) AND port = ?2", SELECT_COLUMNS), params![ip, port])
+    }
+
+    fn set_flagged(&self, id: &str, flagged: bool) -> bool {
+        let conn = self.conn.lock();
+        let changed = conn
+            .query_row(
+                &format!("UPDATE servers SET flagged = ?1 WHERE id = ?2 AND flagged != ?1{}", RETURNING_COLUMNS),
+                params![flagged, id],
+                Self::row_to_server,
+            )
+            .optional();
+        match changed {
+            Ok(Some(server)) => {
+                self.record(ServerEvent::Updated { server });
+                true
+            }
+            // Nothing changed, but the caller still wants to know whether the server exists
+            Ok(_) => conn
+                .query_row("SELECT 1 FROM servers WHERE id = ?1", params![id], |_| Ok(()))
+                .optional()
+                .map(|found| found.is_some())
+                .unwrap_or(false),
+            Err(e) => {
+                error!("Failed to update flag for server {}: {}", id, e);
+                false
+            }
+        }
+    }
+
+    fn search_players(&self, name: &str, limit: usize) -> Vec<PlayerMatch> {
+        self.players.search(name, limit, |id| self.get_server(id))
+    }
+
+    fn record_failed_challenge(&self, ip: &str, port: i32) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        self.reliability.challenge_failed(ip, port, now);
+    }
+
+    fn reliability(&self, ip: &str, port: i32) -> Option<ReliabilityReport> {
+        self.reliability.report(ip, port)
+    }
+
+    fn version(&self) -> u64 {
+        self.changes.version()
+    }
+
+    fn changes_since(&self, version: u64) -> Option<Vec<Change>> {
+        self.changes.since(version)
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.changes.subscribe()
+    }
+}
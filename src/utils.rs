@@ -1,35 +1,37 @@
 // src/utils.rs
-use actix_web::{ HttpRequest, HttpResponse, ResponseError };
-use std::net::{ IpAddr, Ipv4Addr, SocketAddr };
-use log::error;
-use log::debug;
-use log::warn;
-use crate::cloudflare::verify_cloudflare_request;
+use actix_web::{ web, HttpRequest, HttpResponse, ResponseError };
+use std::net::{ IpAddr, Ipv6Addr, SocketAddr };
+use std::sync::Arc;
+use log::{ debug, error, warn };
+use tokio::net::UdpSocket;
+use crate::proxy::ProxyPolicy;
 use std::fmt;
+use std::fmt::Write;
 
 #[derive(Debug)]
 pub enum RequestError {
     MissingPeerIP,
-    NonCloudflareIP(String),
-    MissingCFHeader,
-    InvalidCFHeader,
+    UntrustedProxy(String),
+    MissingForwardedHeader,
+    InvalidForwardedHeader,
     InvalidIPFormat,
     RateLimitExceeded,
-    IPv6NotSupported,
     AuthFailed,
+    /// No `ProxyPolicy` was registered in the app data.
+    MissingProxyPolicy,
 }
 
 impl fmt::Display for RequestError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::MissingPeerIP => write!(f, "Failed to extract client IP"),
-            Self::NonCloudflareIP(ip) => write!(f, "Request from non-Cloudflare IP: {}", ip),
-            Self::MissingCFHeader => write!(f, "Missing CF-Connecting-IP header"),
-            Self::InvalidCFHeader => write!(f, "Invalid CF-Connecting-IP header"),
-            Self::InvalidIPFormat => write!(f, "Invalid CF-Connecting-IP format"),
+            Self::UntrustedProxy(ip) => write!(f, "Request from untrusted proxy: {}", ip),
+            Self::MissingForwardedHeader => write!(f, "Missing forwarded client IP header"),
+            Self::InvalidForwardedHeader => write!(f, "Invalid forwarded client IP header"),
+            Self::InvalidIPFormat => write!(f, "Invalid forwarded client IP format"),
             Self::RateLimitExceeded => write!(f, "Rate limit exceeded"),
-            Self::IPv6NotSupported => write!(f, "IPv6 addresses are not supported"),
             Self::AuthFailed => write!(f, "Authentication failed"),
+            Self::MissingProxyPolicy => write!(f, "Client address policy is not configured"),
         }
     }
 }
@@ -37,73 +39,32 @@ impl fmt::Display for RequestError {
 impl ResponseError for RequestError {
     fn error_response(&self) -> HttpResponse {
         match self {
-            Self::NonCloudflareIP(_) => { HttpResponse::Forbidden().body(self.to_string()) }
+            Self::UntrustedProxy(_) => { HttpResponse::Forbidden().body(self.to_string()) }
             Self::RateLimitExceeded => { HttpResponse::TooManyRequests().body(self.to_string()) }
-            Self::IPv6NotSupported => { HttpResponse::BadRequest().body(self.to_string()) }
+            Self::MissingProxyPolicy => { HttpResponse::InternalServerError().body(self.to_string()) }
             _ => HttpResponse::BadRequest().body(self.to_string()),
         }
     }
 }
 
+/// Returns the address of the client that made the request, as determined by
+/// the `ProxyPolicy` in the app data. Without one the request fails with a 500
+/// rather than guessing whether the connection's own address can be trusted.
 pub fn extract_real_ip(req: &HttpRequest) -> Result<IpAddr, RequestError> {
-    // Get the peer address FIRST. This is the IP of the connection, and we must validate it against
-    // Cloudflare.
     let peer_addr = match req.peer_addr() {
         Some(addr) => addr.ip(),
         None => {
             return Err(RequestError::MissingPeerIP);
         }
     };
-    if let IpAddr::V4(peer_v4) = peer_addr {
-        if !verify_cloudflare_request(IpAddr::V4(peer_v4)) {
-            return Err(RequestError::NonCloudflareIP(peer_v4.to_string()));
-        }
-    } else {
-        return Err(RequestError::IPv6NotSupported);
-    }
-
-    // We are good, now we try to parse the forwarded headers.
-    // Check X-Forwarded-For first
-    if let Some(forwarded_for) = req.headers().get("X-Forwarded-For") {
-        if let Ok(ip_str) = forwarded_for.to_str() {
-            if let Some(first_ip) = ip_str.split(',').next() {
-                if let Ok(ip) = first_ip.trim().parse::<IpAddr>() {
-                    if let IpAddr::V4(v4) = ip {
-                        return Ok(IpAddr::V4(v4));
-                    } else {
-                        return Err(RequestError::IPv6NotSupported);
-                    }
-                }
-            }
-        }
-    }
 
-    // Check CF-Connecting-IP next
-    if let Some(cf_ip) = req.headers().get("CF-Connecting-IP") {
-        if let Ok(ip_str) = cf_ip.to_str() {
-            if let Ok(ip) = ip_str.parse::<IpAddr>() {
-                // Also check if client provided their real IPv4
-                if let Some(true_ip) = req.headers().get("X-Real-IP") {
-                    if let Ok(true_ip_str) = true_ip.to_str() {
-                        if let Ok(true_ip_addr) = true_ip_str.parse::<IpAddr>() {
-                            if let IpAddr::V4(v4) = true_ip_addr {
-                                debug!("Using provided X-Real-IP: {}", v4);
-                                return Ok(IpAddr::V4(v4));
-                            }
-                        }
-                    }
-                }
-                if let IpAddr::V4(v4) = ip {
-                    return Ok(IpAddr::V4(v4));
-                } else {
-                    return Err(RequestError::IPv6NotSupported);
-                }
-            }
+    match req.app_data::<web::Data<ProxyPolicy>>() {
+        Some(policy) => policy.client_ip(peer_addr, req.headers()),
+        None => {
+            error!("No ProxyPolicy registered, can't tell which client {} is", peer_addr);
+            Err(RequestError::MissingProxyPolicy)
         }
     }
-
-    // If we get here, there's no forwarded IP. This is a problem, because we already validated the Cloudflare ip.
-    Err(RequestError::MissingCFHeader)
 }
 
 // For debugging purposes, add this function
@@ -114,13 +75,137 @@ pub fn log_all_headers(req: &HttpRequest) {
     }
 }
 
-pub fn format_address_for_challenge(ip: IpAddr, port: i32) -> Result<SocketAddr, String> {
+/// Unwraps IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`), so a v4 client reaching
+/// a dual-stack listener is seen with the same address as over plain v4.
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Key a client is rate limited under. IPv6 clients usually get a whole /64, so
+/// they share one bucket per prefix instead of one per address.
+pub fn rate_limit_key(ip: IpAddr) -> IpAddr {
     match ip {
-        IpAddr::V4(_) => {
-            format!("{}:{}", ip, port)
-                .parse()
-                .map_err(|e| format!("Invalid socket address: {}", e))
+        IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !(u64::MAX as u128))),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Block of addresses whose servers count towards one `max_servers_per_ip`,
+/// the same prefix its clients are rate limited under.
+pub fn address_block(ip: &str) -> String {
+    match ip.parse::<IpAddr>() {
+        Ok(ip) => rate_limit_key(canonical_ip(ip)).to_string(),
+        Err(_) => ip.to_string(),
+    }
+}
+
+pub fn format_address_for_challenge(ip: IpAddr, port: i32) -> Result<SocketAddr, String> {
+    let port = u16::try_from(port).map_err(|_| format!("Invalid port: {}", port))?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Binds a UDP socket on each non-empty address.
+///
+/// Addresses that fail to bind are skipped with a warning, so hosts without
+/// IPv6 still start with the default `[::]:0`. Fails only if nothing was bound.
+pub async fn bind_udp_sockets(bind_addresses: &[&str]) -> std::io::Result<Vec<Arc<UdpSocket>>> {
+    let mut sockets = Vec::new();
+    let mut last_error = None;
+    for bind_address in bind_addresses.iter().filter(|address| !address.is_empty()) {
+        match UdpSocket::bind(bind_address).await {
+            Ok(socket) => sockets.push(Arc::new(socket)),
+            Err(e) => {
+                warn!("Failed to bind UDP socket on {}: {}", bind_address, e);
+                last_error = Some(e);
+            }
         }
-        IpAddr::V6(_) => Err("IPv6 addresses are not supported".to_string()),
+    }
+    match (sockets.is_empty(), last_error) {
+        (true, Some(e)) => Err(e),
+        (true, None) => Err(std::io::Error::other("No UDP bind address configured")),
+        _ => Ok(sockets),
+    }
+}
+
+/// Picks the socket of the same address family as `addr`.
+pub fn socket_for<'a>(sockets: &'a [Arc<UdpSocket>], addr: &SocketAddr) -> Option<&'a Arc<UdpSocket>> {
+    sockets
+        .iter()
+        .find(|socket| socket.local_addr().is_ok_and(|local| local.is_ipv6() == addr.is_ipv6()))
+}
+
+pub fn hex_encode(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        write!(&mut hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
+
+pub fn hex_decode(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() & 1 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn canonical_ip_unwraps_v4_mapped_addresses() {
+        assert_eq!(canonical_ip(ip("::ffff:203.0.113.7")), ip("203.0.113.7"));
+        assert_eq!(canonical_ip(ip("203.0.113.7")), ip("203.0.113.7"));
+        assert_eq!(canonical_ip(ip("2001:db8::1")), ip("2001:db8::1"));
+        // v4-compatible addresses are deprecated and stay as they are
+        assert_eq!(canonical_ip(ip("::203.0.113.7")), ip("::203.0.113.7"));
+    }
+
+    #[test]
+    fn rate_limit_key_groups_v6_by_prefix() {
+        assert_eq!(rate_limit_key(ip("203.0.113.7")), ip("203.0.113.7"));
+        assert_eq!(rate_limit_key(ip("2001:db8:1:2:aaaa::1")), ip("2001:db8:1:2::"));
+        assert_eq!(rate_limit_key(ip("2001:db8:1:2:ffff:ffff:ffff:ffff")), ip("2001:db8:1:2::"));
+        assert_ne!(rate_limit_key(ip("2001:db8:1:2::1")), rate_limit_key(ip("2001:db8:1:3::1")));
+    }
+
+    #[test]
+    fn address_block_matches_rate_limit_key() {
+        assert_eq!(address_block("203.0.113.7"), "203.0.113.7");
+        assert_eq!(address_block("::ffff:203.0.113.7"), "203.0.113.7");
+        assert_eq!(address_block("2001:db8:1:2::1"), address_block("2001:db8:1:2:0:0:0:2"));
+        assert_eq!(address_block("not an address"), "not an address");
+    }
+
+    #[test]
+    fn formats_challenge_addresses() {
+        assert_eq!(format_address_for_challenge(ip("203.0.113.7"), 37015).unwrap().to_string(), "203.0.113.7:37015");
+        assert_eq!(format_address_for_challenge(ip("2001:db8::1"), 37015).unwrap().to_string(), "[2001:db8::1]:37015");
+        assert_eq!(format_address_for_challenge(ip("203.0.113.7"), 65535).unwrap().port(), 65535);
+        assert!(format_address_for_challenge(ip("203.0.113.7"), -1).is_err());
+        assert!(format_address_for_challenge(ip("203.0.113.7"), 65536).is_err());
+    }
+
+    #[test]
+    fn missing_proxy_policy_is_a_server_error() {
+        let request = || actix_web::test::TestRequest::default().peer_addr("203.0.113.7:50000".parse().unwrap());
+
+        let err = extract_real_ip(&request().to_http_request()).unwrap_err();
+        assert!(matches!(err, RequestError::MissingProxyPolicy));
+        assert_eq!(err.error_response().status(), actix_web::http::StatusCode::INTERNAL_SERVER_ERROR);
+
+        let req = request().app_data(web::Data::new(ProxyPolicy::direct())).to_http_request();
+        assert_eq!(extract_real_ip(&req).unwrap(), ip("203.0.113.7"));
     }
 }
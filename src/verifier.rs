@@ -0,0 +1,288 @@
+// src/verifier.rs
+use dashmap::DashMap;
+use log::{ debug, error, info };
+use std::fmt;
+use std::net::{ IpAddr, SocketAddr };
+use std::sync::Arc;
+use std::time::{ Duration, Instant };
+use tokio::sync::mpsc;
+use crate::challenge::{ ChallengeDispatcher, ChallengeError };
+use crate::models::server::ServerInfo;
+use crate::session::SessionSigner;
+use crate::storage::ServerStore;
+
+// How long to wait before retrying a challenge the dispatcher had no room for
+const BUSY_RETRY_DELAY: Duration = Duration::from_millis(100);
+const BUSY_MAX_RETRIES: u32 = 50;
+
+#[derive(Debug)]
+pub enum SubmitError {
+    /// A verification for this address is pending and the heartbeat didn't present its token.
+    TokenMismatch,
+    /// Too many verifications are already pending.
+    Busy,
+}
+
+impl fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TokenMismatch => write!(f, "Invalid server token"),
+            Self::Busy => write!(f, "Too many pending verifications, try again later"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerificationStatus {
+    /// The challenge hasn't been answered yet.
+    Pending,
+    /// The server answered the challenge and is in the public list.
+    Verified,
+    Rejected(String),
+}
+
+/// Where a deferred heartbeat is in its verification.
+#[derive(Debug, Clone)]
+pub struct Verification {
+    pub server: ServerInfo,
+    pub status: VerificationStatus,
+    /// Issued once the server is verified.
+    pub session_token: Option<String>,
+    resolved_at: Option<Instant>,
+}
+
+impl Verification {
+    /// A heartbeat that was verified inline, without going through the verifier.
+    pub fn verified(server: ServerInfo, session_token: String) -> Self {
+        Self {
+            server,
+            status: VerificationStatus::Verified,
+            session_token: Some(session_token),
+            resolved_at: Some(Instant::now()),
+        }
+    }
+}
+
+/// Verifies heartbeats in the background so the HTTP request doesn't have to wait
+/// for the UDP challenge.
+///
+/// Accepted heartbeats stay out of the public list until their challenge succeeds.
+/// Results are kept for `result_ttl` so servers can poll for them.
+pub struct DeferredVerifier {
+    verifications: DashMap<String, Verification>,
+    // ip:port -> ID of the verification still pending for it
+    pending_by_addr: DashMap<SocketAddr, String>,
+    queue: mpsc::Sender<(String, SocketAddr)>,
+    max_pending: usize,
+}
+
+impl DeferredVerifier {
+    pub fn spawn(
+        storage: Arc<dyn ServerStore>,
+        challenge: Arc<ChallengeDispatcher>,
+        session_signer: Arc<SessionSigner>,
+        max_pending: usize,
+        result_ttl: Duration
+    ) -> Arc<Self> {
+        let max_pending = max_pending.max(1);
+        let (queue, jobs) = mpsc::channel(max_pending);
+        let verifier = Arc::new(Self {
+            verifications: DashMap::new(),
+            pending_by_addr: DashMap::new(),
+            queue,
+            max_pending,
+        });
+
+        tokio::spawn(run(verifier.clone(), jobs, storage, challenge, session_signer, result_ttl));
+        verifier
+    }
+
+    /// Queues `server` for verification and returns the pending entry.
+    ///
+    /// A heartbeat for an address that is already pending replaces the queued
+    /// payload instead of sending a second challenge, except for the alternate
+    /// address, which is the one being challenged. A presented token must be the
+    /// one handed out with the pending entry.
+    pub fn submit(&self, server: ServerInfo, addr: SocketAddr, presented_token: Option<&str>) -> Result<Verification, SubmitError> {
+        if let Some(id) = self.pending_by_addr.get(&addr).map(|r| r.value().clone()) {
+            if let Some(mut pending) = self.verifications.get_mut(&id) {
+                if pending.status == VerificationStatus::Pending {
+                    if presented_token.is_some_and(|token| token != pending.server.token) {
+                        return Err(SubmitError::TokenMismatch);
+                    }
+                    let (id, token, first_seen, alt_ip) = (
+                        pending.server.id.clone(),
+                        pending.server.token.clone(),
+                        pending.server.first_seen,
+                        pending.server.alt_ip.clone(),
+                    );
+                    pending.server = ServerInfo { id, token, first_seen, alt_ip, ..server };
+                    return Ok(pending.clone());
+                }
+            }
+        }
+
+        if self.pending_by_addr.len() >= self.max_pending {
+            return Err(SubmitError::Busy);
+        }
+
+        let verification = Verification {
+            server,
+            status: VerificationStatus::Pending,
+            session_token: None,
+            resolved_at: None,
+        };
+        let id = verification.server.id.clone();
+
+        self.queue
+            .try_send((id.clone(), addr))
+            .map_err(|_| SubmitError::Busy)?;
+        self.verifications.insert(id.clone(), verification.clone());
+        self.pending_by_addr.insert(addr, id);
+        Ok(verification)
+    }
+
+    pub fn status(&self, id: &str) -> Option<Verification> {
+        self.verifications.get(id).map(|r| r.value().clone())
+    }
+
+    fn resolve(&self, id: &str, addr: &SocketAddr, status: VerificationStatus, server: Option<ServerInfo>, session_token: Option<String>) {
+        self.pending_by_addr.remove_if(addr, |_, pending_id| pending_id == id);
+        if let Some(mut verification) = self.verifications.get_mut(id) {
+            if let Some(server) = server {
+                verification.server = server;
+            }
+            verification.status = status;
+            verification.session_token = session_token;
+            verification.resolved_at = Some(Instant::now());
+        }
+    }
+
+    fn prune(&self, result_ttl: Duration) {
+        self.verifications.retain(|_, v| !matches!(v.resolved_at, Some(at) if at.elapsed() >= result_ttl));
+    }
+}
+
+async fn run(
+    verifier: Arc<DeferredVerifier>,
+    mut jobs: mpsc::Receiver<(String, SocketAddr)>,
+    storage: Arc<dyn ServerStore>,
+    challenge: Arc<ChallengeDispatcher>,
+    session_signer: Arc<SessionSigner>,
+    result_ttl: Duration
+) {
+    let mut prune_ticker = tokio::time::interval(result_ttl.max(Duration::from_secs(1)));
+    loop {
+        tokio::select! {
+            job = jobs.recv() => {
+                let Some((id, addr)) = job else { break };
+                tokio::spawn(verify(
+                    verifier.clone(),
+                    id,
+                    addr,
+                    storage.clone(),
+                    challenge.clone(),
+                    session_signer.clone()
+                ));
+            }
+            _ = prune_ticker.tick() => verifier.prune(result_ttl),
+        }
+    }
+}
+
+async fn verify(
+    verifier: Arc<DeferredVerifier>,
+    id: String,
+    addr: SocketAddr,
+    storage: Arc<dyn ServerStore>,
+    challenge: Arc<ChallengeDispatcher>,
+    session_signer: Arc<SessionSigner>
+) {
+    let mut result = challenge_with_retries(&challenge, &addr).await;
+    if result.is_ok() {
+        // Dual-stack servers also have to answer on the address they advertise for the other family
+        let alt_addr = verifier
+            .status(&id)
+            .and_then(|v| v.server.alt_ip.parse::<IpAddr>().ok())
+            .map(|alt_ip| SocketAddr::new(alt_ip, addr.port()));
+        if let Some(alt_addr) = alt_addr {
+            result = challenge_with_retries(&challenge, &alt_addr).await;
+        }
+    }
+
+    if let Err(e) = result {
+        error!("Deferred challenge failed for {}: {}", addr, e);
+        // Running out of challenge slots or lacking a socket is on us, not the server
+        if !matches!(e, ChallengeError::Busy | ChallengeError::Unreachable) {
+            if let Some(verification) = verifier.status(&id) {
+                storage.record_failed_challenge(&verification.server.ip, verification.server.port);
+            }
+        }
+        verifier.resolve(&id, &addr, VerificationStatus::Rejected(e.to_string()), None, None);
+        return;
+    }
+
+    // Take the latest payload, later heartbeats may have replaced it while we waited
+    let Some(server) = verifier.status(&id).map(|v| v.server) else { return };
+    let storage_result = tokio::task::spawn_blocking(move || storage.add_server(server)).await;
+    match storage_result {
+        Ok(Ok(server)) => {
+            info!("Verified server {} at {}", server.id, addr);
+            let session_token = session_signer.issue(&addr);
+            verifier.resolve(&id, &addr, VerificationStatus::Verified, Some(server), Some(session_token));
+        }
+        Ok(Err(e)) => {
+            debug!("Verified server at {} could not be added: {}", addr, e);
+            verifier.resolve(&id, &addr, VerificationStatus::Rejected(e), None, None);
+        }
+        Err(e) => {
+            error!("Failed to add verified server at {}: {}", addr, e);
+            verifier.resolve(&id, &addr, VerificationStatus::Rejected("Internal error".to_string()), None, None);
+        }
+    }
+}
+
+/// Challenges `addr`, waiting for a free slot a few times if too many are in flight.
+async fn challenge_with_retries(challenge: &ChallengeDispatcher, addr: &SocketAddr) -> Result<(), ChallengeError> {
+    let mut result = challenge.verify(addr).await;
+    let mut retries = 0;
+    while matches!(result, Err(ChallengeError::Busy)) && retries < BUSY_MAX_RETRIES {
+        tokio::time::sleep(BUSY_RETRY_DELAY).await;
+        result = challenge.verify(addr).await;
+        retries += 1;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UdpSocket;
+    use crate::config::Config;
+    use crate::storage::memory::ServerStorage;
+
+    #[tokio::test]
+    async fn resubmits_keep_the_challenged_alt_ip() {
+        let storage: Arc<dyn ServerStore> = Arc::new(ServerStorage::new(Config::default()));
+        let challenge = Arc::new(ChallengeDispatcher::bind(&["127.0.0.1:0"], 16, Duration::from_secs(5)).await.unwrap());
+        let signer = Arc::new(SessionSigner::new(Some("test secret"), 300));
+        let verifier = DeferredVerifier::spawn(storage.clone(), challenge, signer, 16, Duration::from_secs(60));
+        // Nobody answers on this socket, so the verification stays pending
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = silent.local_addr().unwrap();
+        let heartbeat = |map_name: &str, alt_ip: &str| ServerInfo {
+            ip: addr.ip().to_string(),
+            port: addr.port() as i32,
+            map_name: map_name.to_string(),
+            alt_ip: alt_ip.to_string(),
+            ..ServerInfo::for_test(0)
+        };
+
+        let first = verifier.submit(heartbeat("mp_lobby", "2001:db8::1"), addr, None).unwrap();
+        let swapped = verifier.submit(heartbeat("mp_angel_city", "2001:db8::2"), addr, None).unwrap();
+        assert_eq!(swapped.server.id, first.server.id);
+        assert_eq!(swapped.server.map_name, "mp_angel_city");
+        assert_eq!(swapped.server.alt_ip, "2001:db8::1");
+        assert_eq!(verifier.status(&first.server.id).unwrap().server.alt_ip, "2001:db8::1");
+    }
+}